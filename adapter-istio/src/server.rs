@@ -3,13 +3,19 @@ use self::adapter_istio::{
     handle_feature_targeting_service_server::HandleFeatureTargetingService,
     HandleFeatureTargetingRequest, HandleFeatureTargetingResponse, OutputMsg, Params,
 };
-use data_plane::features;
-use features::{explicit, implicit};
+use data_plane::{features, request};
+use features::{
+    dependencies, echo, explicit,
+    expression::{Str, StrList},
+    implicit,
+    visitor::SameSite,
+};
 use istio::mixer::adapter::model::v1beta1::CheckResult;
 use prost::Message;
-use std::collections::HashMap;
 use tonic::{Code, Request, Response, Status};
 
+// generated for the whole template, not all of which the adapter uses
+#[allow(dead_code)]
 pub mod adapter_istio {
     tonic::include_proto!("featuretargeting");
 }
@@ -47,36 +53,64 @@ impl HandleFeatureTargetingService for Service {
         println!("{:?}", request);
 
         let msg = request.into_inner();
-        let config = msg
+        let params = msg
             .adapter_config
             .and_then(|cfg| Params::decode(cfg.value.as_ref()).ok())
-            .and_then(|params| params.explicit_targeting)
-            .map_or(explicit::Config::default(), |tgt| {
-                explicit::Config(vec![
-                    explicit::Extract::List(explicit::List {
-                        attribute: tgt.override_header,
-                    }),
-                    explicit::Extract::Pattern(explicit::Pattern {
-                        attribute: "host".to_owned(),
-                        pattern: tgt.hostname_pattern,
-                    }),
-                ])
-            });
+            .unwrap_or_default();
+        let echo = params
+            .echo
+            .map(|echo| echo_config(echo).compile())
+            .transpose()
+            .map_err(|e| Status::new(Code::InvalidArgument, e.to_string()))?;
+        let config = params
+            .explicit_targeting
+            .map_or(explicit::Config::default(), explicit_config);
         println!("{:?}", config);
+        let config = config
+            .compile()
+            .map_err(|e| Status::new(Code::InvalidArgument, e.to_string()))?;
 
         if let Some(inst) = msg.instance {
-            let mut request: HashMap<&str, &str> = inst
+            let request: request::Request = inst
                 .headers
                 .iter()
-                .map(|(k, v)| (k.as_ref(), v.as_ref()))
+                .map(|(k, v)| (k.as_str(), v.as_str()))
                 .collect();
-            request.insert("method", inst.method.as_ref());
-            request.insert("path", inst.path.as_ref());
+            let request = request
+                .with_header(":method", inst.method.as_str())
+                .with_header(":path", inst.path.as_str());
 
-            let ftrs = features::target(&request, &config, &implicit::Config::default());
+            let targeted = features::target(
+                &request,
+                &config,
+                &implicit::CompiledConfig::default(),
+                &dependencies::CompiledConfig::default(),
+                features::Precedence::default(),
+            );
+            if !targeted.rejected.is_empty() {
+                println!("Dropped overrides: {:?}", targeted.rejected);
+            }
+            for failure in &targeted.errors {
+                println!("Feature failed to evaluate: {:?}", failure);
+            }
+            for failure in &targeted.explicit_errors {
+                println!("Explicit source failed to evaluate: {:?}", failure);
+            }
+
+            let (echo_features, echo_cookie) = match &echo {
+                Some(echo) => (
+                    echo.features(&targeted.features),
+                    echo.set_cookie(&targeted.features).unwrap_or_default(),
+                ),
+                None => (String::new(), String::new()),
+            };
 
             let reply = HandleFeatureTargetingResponse {
-                output: Some(OutputMsg { features: ftrs }),
+                output: Some(OutputMsg {
+                    features: targeted.features,
+                    echo_features,
+                    echo_cookie,
+                }),
                 result: None::<CheckResult>,
             };
             Ok(Response::new(reply))
@@ -88,3 +122,90 @@ impl HandleFeatureTargetingService for Service {
         }
     }
 }
+
+/// Reads overrides from the override header, as a space separated list, and
+/// from the host name
+fn explicit_config(params: adapter_istio::ExplicitTargeting) -> explicit::Config {
+    let mut sources = vec![];
+    if !params.override_header.is_empty() {
+        sources.push(StrList::Split {
+            separator: " ".to_owned(),
+            value: Str::Attribute(params.override_header.into()),
+        });
+    }
+    if !params.hostname_pattern.is_empty() {
+        sources.push(StrList::Extract {
+            regex: hostname_regex(&params.hostname_pattern),
+            value: Box::new(Str::Attribute("host".into())),
+        });
+    }
+
+    explicit::Config::from(sources)
+}
+
+/// The hostname pattern is a glob, like `feature-*.example.com`, where the
+/// `*` stands for the feature name
+fn hostname_regex(pattern: &str) -> String {
+    let parts: Vec<_> = pattern.split('*').map(regex::escape).collect();
+
+    format!("^{}$", parts.join("([a-z0-9-]+)"))
+}
+
+/// Mixer only hands the outputs to the rule, whose response header operations
+/// choose the header names, so the header here is a placeholder
+fn echo_config(params: adapter_istio::Echo) -> echo::Config {
+    let cookie = if params.cookie.is_empty() {
+        None
+    } else {
+        Some(echo::Cookie {
+            name: params.cookie,
+            domain: Some(params.cookie_domain).filter(|d| !d.is_empty()),
+            max_age: Some(params.cookie_max_age).filter(|age| *age > 0),
+            same_site: SameSite::default(),
+            secure: params.cookie_secure,
+        })
+    };
+
+    echo::Config {
+        header: Some(features::DEFAULT_HEADER.to_owned()),
+        cookie,
+        allow: params.allow,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_overrides_from_the_header_and_host() {
+        let config = explicit_config(adapter_istio::ExplicitTargeting {
+            hostname_pattern: "*.echo.localhost".to_owned(),
+            override_header: "x-features".to_owned(),
+        })
+        .compile()
+        .unwrap();
+        let request = request::Request::new()
+            .with_header("x-features", "one two")
+            .with_header("host", "three.echo.localhost");
+
+        let targeted = features::target(
+            &request,
+            &config,
+            &implicit::CompiledConfig::default(),
+            &dependencies::CompiledConfig::default(),
+            features::Precedence::default(),
+        );
+
+        assert_eq!(targeted.features, "one three two");
+    }
+
+    #[test]
+    fn matches_whole_host_names() {
+        let regex = regex::Regex::new(&hostname_regex("feature-*.example.com")).unwrap();
+
+        assert!(regex.is_match("feature-beta.example.com"));
+        assert!(!regex.is_match("feature-beta.example.com.evil.test"));
+        assert!(!regex.is_match("feature-betaxexample.com"));
+    }
+}
@@ -1,49 +1,179 @@
-use data_plane::features;
-use features::{explicit::Config as ExplicitConfig, implicit::Config as ImplicitConfig};
-use log::{info, warn};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use data_plane::{
+    features,
+    request::{self, Request},
+};
+use features::{
+    data::{self, DefaultLoader, Source},
+    dependencies, echo, explicit,
+    expression::{
+        compiled::Environment,
+        time::{self, SharedClock},
+    },
+    implicit, visitor,
+};
+use log::{debug, info, warn};
 use proxy_wasm::{
+    hostcalls,
     traits::*,
     types::{self, LogLevel},
 };
 use serde::Deserialize;
-use std::{cell::RefCell, collections::HashMap};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    sync::Arc,
+};
 use types::Action;
 
 #[derive(Deserialize, Debug)]
 struct FilterConfig {
     header_name: String,
-    explicit: ExplicitConfig,
-    implicit: ImplicitConfig,
+    /// Cookie to mint visitor IDs in, for sticky rollouts to anonymous visitors
+    #[serde(default)]
+    visitor: Option<visitor::Config>,
+    /// Response header or cookie to echo the targeted features to
+    #[serde(default)]
+    echo: Option<echo::Config>,
+    #[serde(flatten)]
+    features: features::Config,
+}
+
+impl FilterConfig {
+    /// What the configuration holds, for logging without revealing its keys
+    fn summary(&self) -> String {
+        let features = &self.features;
+
+        format!(
+            "{} implicit features, {} explicit sources, {} segments, {} lists, {} JWT keys, {} preview keys",
+            features.implicit.0.len(),
+            features.explicit.sources.len(),
+            features.segments.0.len(),
+            features.lists.0.len(),
+            features.jwt.as_ref().map_or(0, |jwt| jwt.jwks.keys.len()),
+            features.explicit.tokens.as_ref().map_or(0, |tokens| tokens.keys.len()),
+        )
+    }
+
+    fn compile(&self, clock: SharedClock) -> Result<Filter> {
+        let geo = match &self.features.geo {
+            Some(geo) => Some(Arc::new(geo.load(&HostLoader)?)),
+            None => None,
+        };
+        let jwt = match &self.features.jwt {
+            Some(jwt) => Some(Arc::new(jwt.load()?)),
+            None => None,
+        };
+        let lists = Arc::new(self.features.lists.load(&HostLoader)?);
+        let mut env = Environment {
+            clock,
+            geo,
+            jwt,
+            lists: Some(lists),
+            ..Environment::default()
+        };
+        env.segments = Some(self.features.segments.compile_with(&env)?);
+
+        Ok(Filter {
+            header_name: self.header_name.clone(),
+            explicit: self.features.explicit.compile_with(&env)?,
+            implicit: self.features.implicit.compile_with(&env)?,
+            dependencies: self.features.dependencies.compile()?,
+            precedence: self.features.precedence,
+            visitor: self.visitor.clone(),
+            echo: match &self.echo {
+                Some(echo) => Some(echo.compile()?),
+                None => None,
+            },
+        })
+    }
+}
+
+/// Loads data from the VM configuration, e.g. a geo database set as the
+/// `vm_config` code's data source, or from the filter configuration
+struct HostLoader;
+
+impl data::Loader for HostLoader {
+    fn load(&self, source: &Source) -> Result<Vec<u8>> {
+        match source {
+            Source::VmConfiguration => VM_CONFIGURATION.with(|vm_configuration| {
+                vm_configuration
+                    .borrow()
+                    .clone()
+                    .ok_or_else(|| anyhow!("No VM configuration was provided"))
+            }),
+            other => DefaultLoader.load(other),
+        }
+    }
+}
+
+/// The proxy's clock, the system clock is not available to WASM modules
+#[derive(Debug)]
+struct HostClock;
+
+impl time::Clock for HostClock {
+    fn now(&self) -> Result<DateTime<Utc>> {
+        hostcalls::get_current_time()
+            .map(DateTime::from)
+            .map_err(|status| anyhow!("Failed to get the current time: {:?}", status))
+    }
 }
 
-impl Default for FilterConfig {
+/// Filter configuration compiled for evaluation, built once per configuration change
+#[derive(Debug)]
+struct Filter {
+    header_name: String,
+    explicit: explicit::CompiledConfig,
+    implicit: implicit::CompiledConfig,
+    dependencies: dependencies::CompiledConfig,
+    precedence: features::Precedence,
+    visitor: Option<visitor::Config>,
+    echo: Option<echo::Echo>,
+}
+
+impl Default for Filter {
     fn default() -> Self {
-        FilterConfig {
-            header_name: "x-feature".to_owned(),
-            explicit: ExplicitConfig::default(),
-            implicit: ImplicitConfig::default(),
+        Filter {
+            header_name: features::DEFAULT_HEADER.to_owned(),
+            explicit: explicit::CompiledConfig::default(),
+            implicit: implicit::CompiledConfig::default(),
+            dependencies: dependencies::CompiledConfig::default(),
+            precedence: features::Precedence::default(),
+            visitor: None,
+            echo: None,
         }
     }
 }
 
 thread_local! {
-    static CONFIGS: RefCell<HashMap<u32, FilterConfig>> = RefCell::new(HashMap::new())
+    static CONFIGS: RefCell<HashMap<u32, Filter>> = RefCell::new(HashMap::new());
+    static VM_CONFIGURATION: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+    /// Explicit overrides dropped by the policy, since the VM started
+    static REJECTED_OVERRIDES: Cell<u64> = const { Cell::new(0) };
+    /// Evaluation errors per feature, or explicit source, since the VM started
+    static FAILURES: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
 }
 
-#[no_mangle]
+/// Every how many-th error of a feature is logged
+const FAILURE_LOG_SAMPLE: u64 = 100;
+
+// unmangled, `_start` clashes with the entry point of native test binaries
+#[cfg_attr(not(test), no_mangle)]
 pub fn _start() {
     proxy_wasm::set_log_level(LogLevel::Trace);
     proxy_wasm::set_root_context(|context_id| -> Box<dyn RootContext> {
         CONFIGS.with(|configs| {
-            configs
-                .borrow_mut()
-                .insert(context_id, FilterConfig::default());
+            configs.borrow_mut().insert(context_id, Filter::default());
         });
 
         Box::new(RootHandler { context_id })
     });
     proxy_wasm::set_http_context(|_context_id, root_context_id| -> Box<dyn HttpContext> {
-        Box::new(HttpHandler { root_context_id })
+        Box::new(HttpHandler {
+            root_context_id,
+            response_headers: vec![],
+        })
     })
 }
 
@@ -54,21 +184,53 @@ struct RootHandler {
 impl Context for RootHandler {}
 
 impl RootContext for RootHandler {
+    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+        if let Some(vm_configuration) = self.get_vm_configuration() {
+            VM_CONFIGURATION.with(|c| c.replace(Some(vm_configuration)));
+        }
+
+        true
+    }
+
     fn on_configure(&mut self, _config_size: usize) -> bool {
         let configuration: Vec<u8> = match self.get_configuration() {
             Some(c) => c,
             None => return false,
         };
 
-        match serde_json::from_slice(configuration.as_ref()) {
-            Ok(new_config) => {
-                info!("Configuration changed: {:?}", new_config);
-                CONFIGS.with(|configs| configs.borrow_mut().insert(self.context_id, new_config));
+        let new_config: FilterConfig = match serde_json::from_slice(configuration.as_ref()) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("Error parsing configuration: {:?}", e);
+
+                return false;
+            }
+        };
+
+        if let Some(Err(e)) = new_config.visitor.as_ref().map(|v| v.validate()) {
+            warn!("Invalid configuration: visitor: {}", e);
+
+            return false;
+        }
+
+        let problems = features::validate(&new_config.features);
+        if !problems.is_empty() {
+            for problem in problems {
+                warn!("Invalid configuration: {}", problem);
+            }
+
+            return false;
+        }
+
+        match new_config.compile(Arc::new(HostClock)) {
+            Ok(filter) => {
+                info!("Configuration changed: {}", new_config.summary());
+                CONFIGS.with(|configs| configs.borrow_mut().insert(self.context_id, filter));
 
                 true
             }
             Err(e) => {
-                warn!("Error parsing configuration: {:?}", e);
+                warn!("Error compiling configuration: {:?}", e);
 
                 false
             }
@@ -78,6 +240,61 @@ impl RootContext for RootHandler {
 
 struct HttpHandler {
     root_context_id: u32,
+    /// Headers to add to the response, e.g. a newly minted visitor cookie
+    response_headers: Vec<(String, String)>,
+}
+
+impl HttpHandler {
+    /// Mints a visitor ID when the request has none, and adds it to the
+    /// request, both for targeting and for the upstream
+    fn assign_visitor(&mut self, config: &visitor::Config, request: &mut Request) {
+        match mint_visitor(config, request) {
+            Ok(Some(minted)) => {
+                self.set_http_request_header("cookie", Some(&minted.cookie));
+                self.response_headers
+                    .push(("set-cookie".to_owned(), minted.set_cookie));
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to mint a visitor ID: {}", e),
+        }
+    }
+}
+
+/// Headers carrying a newly minted visitor ID
+#[derive(Debug)]
+struct MintedVisitor {
+    /// The request's `cookie` header, with the visitor cookie added
+    cookie: String,
+    /// The `set-cookie` response header
+    set_cookie: String,
+}
+
+/// Mints a visitor ID when the request has none, and adds it to the request
+fn mint_visitor(config: &visitor::Config, request: &mut Request) -> Result<Option<MintedVisitor>> {
+    if config.id(request).is_some() {
+        return Ok(None);
+    }
+
+    let id = mint_visitor_id()?;
+    let pair = config.cookie_pair(&id);
+    let cookie = match request.header("cookie") {
+        Some(cookies) if !cookies.is_empty() => format!("{}; {}", cookies, pair),
+        _ => pair,
+    };
+    config.assign(request, &id);
+
+    Ok(Some(MintedVisitor {
+        cookie,
+        set_cookie: config.set_cookie(&id),
+    }))
+}
+
+/// A random, 128 bit visitor ID, from the host's secure random source
+fn mint_visitor_id() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("No random source: {}", e))?;
+
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
 }
 
 impl Context for HttpHandler {}
@@ -86,19 +303,78 @@ impl HttpContext for HttpHandler {
     fn on_http_request_headers(&mut self, _num_headers: usize) -> Action {
         CONFIGS.with(|configs| {
             if let Some(config) = configs.borrow().get(&self.root_context_id) {
-                let mut request: HashMap<&str, &str> = HashMap::new();
-
                 let headers = self.get_http_request_headers();
-                for (name, value) in &headers {
-                    request.insert(name.as_ref(), value.as_ref());
+                let mut request: Request = headers
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str()))
+                    .collect();
+
+                if let Some(address) = self
+                    .get_property(vec!["source", "address"])
+                    .and_then(|bytes| String::from_utf8(bytes).ok())
+                {
+                    request.set_attribute(request::SOURCE_ADDRESS, address);
+                }
+
+                if let Some(visitor) = &config.visitor {
+                    self.assign_visitor(visitor, &mut request);
+                }
+
+                debug!("Targeting on request: {:?}", request);
+                let targeted = features::target(
+                    &request,
+                    &config.explicit,
+                    &config.implicit,
+                    &config.dependencies,
+                    config.precedence,
+                );
+                if !targeted.rejected.is_empty() {
+                    let total = REJECTED_OVERRIDES.with(|count| {
+                        count.set(count.get() + targeted.rejected.len() as u64);
+                        count.get()
+                    });
+                    warn!(
+                        "Dropped overrides: {:?}, {} in total",
+                        targeted.rejected, total
+                    );
                 }
+                FAILURES.with(|failures| {
+                    let mut failures = failures.borrow_mut();
+                    for failure in &targeted.errors {
+                        let count = failures.entry(failure.feature.clone()).or_insert(0);
+                        *count += 1;
+
+                        if *count % FAILURE_LOG_SAMPLE == 1 {
+                            warn!(
+                                "Feature '{}' failed to evaluate ({} times in total), it is {}: {}",
+                                failure.feature,
+                                count,
+                                if failure.enabled { "on" } else { "off" },
+                                failure.error
+                            );
+                        }
+                    }
+                    for failure in &targeted.explicit_errors {
+                        let key = format!("explicit source {}", failure.source);
+                        let count = failures.entry(key).or_insert(0);
+                        *count += 1;
 
-                info!(
-                    "Targeting on request: {:?}, with configuration: {:?}",
-                    request, config
+                        if *count % FAILURE_LOG_SAMPLE == 1 {
+                            warn!(
+                                "Explicit source {} failed to evaluate ({} times in total): {}",
+                                failure.source, count, failure.error
+                            );
+                        }
+                    }
+                });
+                self.set_http_request_header(
+                    config.header_name.as_ref(),
+                    Some(targeted.features.as_ref()),
                 );
-                let output = features::target(&request, &config.explicit, &config.implicit);
-                self.set_http_request_header(config.header_name.as_ref(), Some(output.as_ref()));
+                if let Some(echo) = &config.echo {
+                    self.response_headers
+                        .extend(echo.headers(&targeted.features));
+                }
 
                 Action::Continue
             } else {
@@ -111,4 +387,59 @@ impl HttpContext for HttpHandler {
             }
         })
     }
+
+    fn on_http_response_headers(&mut self, _num_headers: usize) -> Action {
+        for (name, value) in std::mem::take(&mut self.response_headers) {
+            self.add_http_response_header(&name, &value);
+        }
+
+        Action::Continue
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mints_visitor_ids_for_new_visitors() {
+        let config = visitor::Config::default();
+        let mut request = Request::new().with_header("cookie", "theme=dark");
+
+        let minted = mint_visitor(&config, &mut request).unwrap().unwrap();
+
+        let id = config.id(&request).unwrap().to_owned();
+        assert_eq!(id.len(), 32);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(minted.cookie, format!("theme=dark; visitor_id={}", id));
+        assert_eq!(
+            minted.set_cookie,
+            format!("visitor_id={}; Path=/; Max-Age=31536000; SameSite=Lax", id)
+        );
+    }
+
+    #[test]
+    fn mints_a_cookie_header_for_requests_without_one() {
+        let config = visitor::Config::default();
+        let mut request = Request::new();
+
+        let minted = mint_visitor(&config, &mut request).unwrap().unwrap();
+
+        let id = config.id(&request).unwrap();
+        assert_eq!(minted.cookie, format!("visitor_id={}", id));
+    }
+
+    #[test]
+    fn keeps_existing_visitor_ids() {
+        let config = visitor::Config::default();
+        let mut request = Request::new().with_header("cookie", "visitor_id=abc");
+
+        assert!(mint_visitor(&config, &mut request).unwrap().is_none());
+        assert_eq!(request.header_values("cookie").count(), 1);
+    }
+
+    #[test]
+    fn mints_distinct_visitor_ids() {
+        assert_ne!(mint_visitor_id().unwrap(), mint_visitor_id().unwrap());
+    }
 }
@@ -0,0 +1,53 @@
+//! Binary data referenced by the configuration, like geo databases
+//!
+//! Data is too large to embed in expressions, so the configuration says where
+//! to load it from, and it is loaded once when the configuration is compiled.
+//! Some sources are only available in particular hosts, adapters provide their
+//! own `Loader` to support them.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs};
+
+/// Where to load data from
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// A file on the local file system
+    Path(String),
+    /// Inline in the configuration, base64 encoded
+    Base64(String),
+    /// The VM configuration of the proxy-wasm filter, set as a data source
+    /// in Envoy's `vm_config`
+    VmConfiguration,
+}
+
+/// Describes the source for error messages, leaving out inline data, which
+/// can be large or sensitive, like a list of user IDs
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Path(path) => write!(f, "path {:?}", path),
+            Source::Base64(data) => write!(f, "inline base64 ({} characters)", data.len()),
+            Source::VmConfiguration => f.write_str("the VM configuration"),
+        }
+    }
+}
+
+pub trait Loader {
+    fn load(&self, source: &Source) -> Result<Vec<u8>>;
+}
+
+/// Loads data from files and inline in the configuration
+#[derive(Debug, Default)]
+pub struct DefaultLoader;
+
+impl Loader for DefaultLoader {
+    fn load(&self, source: &Source) -> Result<Vec<u8>> {
+        match source {
+            Source::Path(path) => Ok(fs::read(path)?),
+            Source::Base64(data) => Ok(base64::decode(data)?),
+            Source::VmConfiguration => bail!("VM configuration is only available in proxy-wasm"),
+        }
+    }
+}
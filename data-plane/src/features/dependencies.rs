@@ -0,0 +1,257 @@
+//! Dependencies between features
+//!
+//! A feature can require other features, and is turned off unless all of its
+//! prerequisites are on. Features in an exclusion group are never on together.
+//! Both are resolved after explicit and implicit targeting, in three steps:
+//!
+//! 1. features with a prerequisite that is off are turned off, transitively
+//! 2. in each exclusion group, all but one of the features that are on are
+//!    turned off. A feature targeted explicitly beats one targeted implicitly,
+//!    otherwise the feature listed first in the group wins
+//! 3. features that lost a prerequisite in step 2 are turned off
+//!
+//! When a group's winner is turned off in step 3, because it required the
+//! loser of another group, the steps are repeated without it, so the next
+//! feature of the group can win instead.
+//!
+//! Prerequisites must not form a cycle, which is checked when compiling.
+
+use crate::features::parse_feature;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct Config {
+    /// The prerequisites of each feature
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub requires: BTreeMap<String, Vec<String>>,
+    /// Groups of features of which at most one is on
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclusive: Vec<Vec<String>>,
+}
+
+impl Config {
+    /// Compile the dependencies, failing if prerequisites form a cycle
+    pub fn compile(&self) -> Result<CompiledConfig> {
+        let requires = self
+            .sorted()?
+            .into_iter()
+            .map(|feature| (feature.to_owned(), self.requires[feature].clone()))
+            .collect();
+
+        Ok(CompiledConfig {
+            requires,
+            exclusive: self.exclusive.clone(),
+        })
+    }
+
+    /// Features with prerequisites, each after all of its prerequisites
+    pub fn sorted(&self) -> Result<Vec<&str>> {
+        sort(&self.requires)
+            .map_err(|cycle| anyhow!("Prerequisites form a cycle: {}", cycle.join(" -> ")))
+    }
+}
+
+/// Sorts the keys of a dependency graph so that each comes after the keys it
+/// depends on, or returns a cycle, e.g. `["a", "b", "a"]`
+pub(crate) fn sort(graph: &BTreeMap<String, Vec<String>>) -> Result<Vec<&str>, Vec<&str>> {
+    let mut sorter = Sorter {
+        graph,
+        marks: HashMap::new(),
+        path: vec![],
+        sorted: vec![],
+    };
+    for node in graph.keys() {
+        sorter.visit(node)?;
+    }
+
+    Ok(sorter.sorted)
+}
+
+enum Mark {
+    Visiting,
+    Done,
+}
+
+/// Depth first topological sort
+struct Sorter<'g> {
+    graph: &'g BTreeMap<String, Vec<String>>,
+    marks: HashMap<&'g str, Mark>,
+    /// Nodes being visited, to report cycles
+    path: Vec<&'g str>,
+    sorted: Vec<&'g str>,
+}
+
+impl<'g> Sorter<'g> {
+    fn visit(&mut self, node: &'g str) -> Result<(), Vec<&'g str>> {
+        match self.marks.get(node) {
+            Some(Mark::Done) => return Ok(()),
+            Some(Mark::Visiting) => {
+                let start = self.path.iter().position(|n| *n == node).unwrap_or(0);
+                let mut cycle = self.path[start..].to_vec();
+                cycle.push(node);
+
+                return Err(cycle);
+            }
+            None => {}
+        }
+
+        self.marks.insert(node, Mark::Visiting);
+        self.path.push(node);
+        if let Some(dependencies) = self.graph.get(node) {
+            for dependency in dependencies {
+                self.visit(dependency)?;
+            }
+            self.sorted.push(node);
+        }
+        self.path.pop();
+        self.marks.insert(node, Mark::Done);
+
+        Ok(())
+    }
+}
+
+/// Dependencies ready for resolving, with prerequisites sorted
+#[derive(Debug, Default)]
+pub struct CompiledConfig {
+    requires: Vec<(String, Vec<String>)>,
+    exclusive: Vec<Vec<String>>,
+}
+
+impl CompiledConfig {
+    /// Removes the features turned off by their dependencies from lists of
+    /// explicitly and implicitly targeted features
+    pub fn resolve(&self, explicit: &mut Vec<&str>, implicit: &mut Vec<&str>) {
+        // winners which lost a prerequisite, and can't win
+        let mut excluded: Vec<&str> = vec![];
+        loop {
+            let mut resolved_explicit = explicit.clone();
+            let mut resolved_implicit = implicit.clone();
+            for feature in &excluded {
+                remove(&mut resolved_explicit, feature);
+                remove(&mut resolved_implicit, feature);
+            }
+
+            let winners = self.resolve_once(&mut resolved_explicit, &mut resolved_implicit);
+            let lost: Vec<&str> = winners
+                .into_iter()
+                .filter(|w| !contains(&resolved_explicit, w) && !contains(&resolved_implicit, w))
+                .collect();
+
+            if lost.is_empty() {
+                *explicit = resolved_explicit;
+                *implicit = resolved_implicit;
+
+                return;
+            }
+            excluded.extend(lost);
+        }
+    }
+
+    /// The three steps, returning the winner of each exclusion group
+    fn resolve_once(&self, explicit: &mut Vec<&str>, implicit: &mut Vec<&str>) -> Vec<&str> {
+        self.remove_unmet(explicit, implicit);
+
+        let mut winners = vec![];
+        for group in &self.exclusive {
+            let winner = group
+                .iter()
+                .find(|f| contains(explicit, f))
+                .or_else(|| group.iter().find(|f| contains(implicit, f)));
+
+            if let Some(winner) = winner {
+                for loser in group.iter().filter(|f| *f != winner) {
+                    remove(explicit, loser);
+                    remove(implicit, loser);
+                }
+                winners.push(winner.as_str());
+            }
+        }
+
+        self.remove_unmet(explicit, implicit);
+
+        winners
+    }
+
+    fn remove_unmet(&self, explicit: &mut Vec<&str>, implicit: &mut Vec<&str>) {
+        for (feature, prerequisites) in &self.requires {
+            let met = prerequisites
+                .iter()
+                .all(|p| contains(explicit, p) || contains(implicit, p));
+
+            if !met {
+                remove(explicit, feature);
+                remove(implicit, feature);
+            }
+        }
+    }
+}
+
+fn contains(features: &[&str], name: &str) -> bool {
+    features.iter().any(|f| parse_feature(f).0 == name)
+}
+
+fn remove(features: &mut Vec<&str>, name: &str) {
+    features.retain(|f| parse_feature(f).0 != name);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use test_case::test_case;
+
+    fn config() -> CompiledConfig {
+        let config: Config = serde_json::from_value(json!({
+            "requires": {
+                "tips": ["checkout"],
+                "tips-video": ["tips", "video"]
+            },
+            "exclusive": [["tips", "banner"], ["checkout", "one-page"], ["dark", "light"]]
+        }))
+        .unwrap();
+
+        config.compile().unwrap()
+    }
+
+    #[test_case(&[], &["tips", "video"], "video" ; "missing prerequisite")]
+    #[test_case(&[], &["tips-video", "tips", "video"], "video" ; "transitive")]
+    #[test_case(&[], &["checkout", "tips", "tips-video", "video"], "checkout tips tips-video video" ; "all met")]
+    #[test_case(&[], &["checkout=a", "tips"], "checkout=a tips" ; "variant")]
+    #[test_case(&["one-page"], &["checkout", "tips"], "one-page" ; "lost prerequisite")]
+    #[test_case(&["one-page"], &["checkout", "tips", "banner"], "banner one-page" ; "winner lost prerequisite")]
+    #[test_case(&[], &["light", "dark"], "dark" ; "first listed wins")]
+    #[test_case(&["light"], &["dark"], "light" ; "explicit wins")]
+    #[test_case(&["light", "dark"], &[], "dark" ; "both explicit")]
+    fn resolves(explicit: &[&str], implicit: &[&str], expected: &str) {
+        let mut explicit = explicit.to_vec();
+        let mut implicit = implicit.to_vec();
+
+        config().resolve(&mut explicit, &mut implicit);
+
+        assert_eq!(crate::features::union(&explicit, &implicit), expected);
+    }
+
+    #[test]
+    fn sorts_prerequisites_first() {
+        let config: Config = serde_json::from_value(json!({
+            "requires": { "a": ["b"], "b": ["c"], "c": ["d"] }
+        }))
+        .unwrap();
+
+        assert_eq!(config.sorted().unwrap(), vec!["c", "b", "a"]);
+    }
+
+    #[test_case(json!({ "a": ["b"], "b": ["c"], "c": ["a"] }), "a -> b -> c -> a" ; "cycle")]
+    #[test_case(json!({ "a": ["a"] }), "a -> a" ; "self")]
+    #[test_case(json!({ "a": ["b"], "b": ["c", "d"], "d": ["b"] }), "b -> d -> b" ; "inner")]
+    fn rejects_cycles(requires: serde_json::Value, cycle: &str) {
+        let config: Config = serde_json::from_value(json!({ "requires": requires })).unwrap();
+
+        assert_eq!(
+            config.compile().unwrap_err().to_string(),
+            format!("Prerequisites form a cycle: {}", cycle)
+        );
+    }
+}
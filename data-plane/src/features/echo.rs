@@ -0,0 +1,188 @@
+//! Echoing the targeted features back to the client
+//!
+//! Single page apps need to know which features were decided on, to branch in
+//! the browser as well. Adapters can copy the feature list to a response
+//! header, a cookie readable by scripts, or both. Only features matching the
+//! allowlist are echoed, so internal features are not revealed to browsers.
+//!
+//! The cookie holds the percent-encoded feature list, e.g.
+//! `features=beta%20checkout%3Dexpress`, which the `sticky` error policy of a
+//! feature can read on later requests.
+
+use crate::features::{
+    explicit::globs,
+    expression::url,
+    parse_feature,
+    visitor::{is_token, set_cookie, SameSite},
+};
+use anyhow::{bail, Result};
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Response header to set to the feature list, e.g. `x-features`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+    /// Cookie to set to the feature list
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cookie: Option<Cookie>,
+    /// Names or globs of the features to echo, e.g. `["ui-*", "dark-mode"]`
+    pub allow: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Cookie {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// Lifetime of the cookie in seconds, until the browser is closed by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+    #[serde(default)]
+    pub same_site: SameSite,
+    /// Only send the cookie over HTTPS, always the case with `SameSite=None`
+    #[serde(default)]
+    pub secure: bool,
+}
+
+impl Config {
+    pub fn compile(&self) -> Result<Echo> {
+        if self.header.is_none() && self.cookie.is_none() {
+            bail!("Echoing features needs a header or a cookie");
+        }
+        if let Some(header) = &self.header {
+            if !is_token(header) || header.contains(':') {
+                bail!("Invalid echo header name '{}'", header);
+            }
+        }
+        if let Some(cookie) = &self.cookie {
+            if !is_token(&cookie.name) {
+                bail!("Invalid echo cookie name '{}'", cookie.name);
+            }
+            if let Some(domain) = &cookie.domain {
+                if !is_token(domain) {
+                    bail!("Invalid echo cookie domain '{}'", domain);
+                }
+            }
+        }
+
+        Ok(Echo {
+            header: self.header.clone(),
+            cookie: self.cookie.clone(),
+            allow: globs(&self.allow)?,
+        })
+    }
+}
+
+/// Compiled configuration, ready for echoing
+#[derive(Debug)]
+pub struct Echo {
+    header: Option<String>,
+    cookie: Option<Cookie>,
+    allow: RegexSet,
+}
+
+impl Echo {
+    /// The allowed features of a feature list, as a feature list
+    pub fn features(&self, features: &str) -> String {
+        features
+            .split_whitespace()
+            .filter(|f| self.allow.is_match(parse_feature(f).0))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Response headers to add, by name, for the targeted feature list
+    pub fn headers(&self, features: &str) -> Vec<(String, String)> {
+        let mut headers = vec![];
+        if let Some(header) = &self.header {
+            headers.push((header.clone(), self.features(features)));
+        }
+        if let Some(cookie) = self.set_cookie(features) {
+            headers.push(("set-cookie".to_owned(), cookie));
+        }
+
+        headers
+    }
+
+    /// The `Set-Cookie` header value for the targeted feature list, when
+    /// echoing to a cookie
+    pub fn set_cookie(&self, features: &str) -> Option<String> {
+        let cookie = self.cookie.as_ref()?;
+        let pair = format!("{}={}", cookie.name, url::encode(&self.features(features)));
+
+        Some(set_cookie(
+            &pair,
+            cookie.domain.as_deref(),
+            cookie.max_age,
+            cookie.same_site,
+            cookie.secure,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{features::implicit, request::Request};
+    use serde_json::json;
+    use test_case::test_case;
+
+    fn echo(config: serde_json::Value) -> Result<Echo> {
+        serde_json::from_value::<Config>(config).unwrap().compile()
+    }
+
+    #[test]
+    fn echoes_allowed_features() {
+        let echo = echo(json!({
+            "header": "x-features",
+            "cookie": { "name": "features", "max_age": 3600 },
+            "allow": ["ui-*", "checkout"]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            echo.headers("beta checkout=express internal-api ui-dark"),
+            vec![
+                (
+                    "x-features".to_owned(),
+                    "checkout=express ui-dark".to_owned()
+                ),
+                (
+                    "set-cookie".to_owned(),
+                    "features=checkout%3Dexpress%20ui-dark; Path=/; Max-Age=3600; SameSite=Lax"
+                        .to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn echoed_cookie_keeps_features_sticky() {
+        let echo = echo(json!({ "cookie": { "name": "features" }, "allow": ["*"] })).unwrap();
+        let (_, set_cookie) = echo.headers("beta checkout=express").remove(0);
+        let cookie = set_cookie.split(';').next().unwrap();
+
+        let config: implicit::Config = serde_json::from_value(json!([{
+            "name": "checkout",
+            "rule": "header(\"x-missing\") == \"1\"",
+            "on_error": { "sticky": { "cookie": "features" } }
+        }]))
+        .unwrap();
+        let request = Request::new().with_header("cookie", cookie);
+
+        assert_eq!(
+            implicit::from_request(&request, &config.compile().unwrap()),
+            vec!["checkout=express"]
+        );
+    }
+
+    #[test_case(json!({ "allow": ["*"] }), "Echoing features needs a header or a cookie" ; "nowhere")]
+    #[test_case(json!({ "header": "x features", "allow": [] }), "Invalid echo header name 'x features'" ; "header")]
+    #[test_case(json!({ "cookie": { "name": "" }, "allow": [] }), "Invalid echo cookie name ''" ; "cookie")]
+    #[test_case(json!({ "cookie": { "name": "f", "domain": "a;b" }, "allow": [] }), "Invalid echo cookie domain 'a;b'" ; "domain")]
+    fn rejects_invalid_configuration(config: serde_json::Value, error: &str) {
+        assert_eq!(echo(config).unwrap_err().to_string(), error);
+    }
+}
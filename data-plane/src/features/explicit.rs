@@ -1,29 +1,339 @@
-use crate::features::expression::{Str, StrList};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+//! Explicit targeting, where the request names the features it wants
+//!
+//! Features are read from the request by a list of expressions, e.g. split
+//! from an override header or extracted from the hostname. A policy limits
+//! which features clients may switch on or off themselves, and overrides that
+//! break it are dropped and reported, rather than forwarded. Overrides can
+//! also come from signed preview tokens, which bypass the policy.
 
-pub fn from_request(request: &HashMap<&str, &str>, config: &Config) -> Vec<String> {
-    let mut features = config
-        .0
-        .iter()
-        .flat_map(|x| x.eval(request).unwrap_or_else(|_| vec![]))
-        .collect::<Vec<_>>();
+use crate::features::{
+    expression::{
+        compiled::{self, Environment},
+        trace::Trace,
+        NotFound, Str, StrList,
+    },
+    parse_feature, preview,
+};
+use crate::request::Request;
+use anyhow::{anyhow, Result};
+use regex::RegexSet;
+use serde::{Deserialize, Serialize, Serializer};
+
+pub fn from_request(request: &Request, config: &CompiledConfig) -> Vec<String> {
+    overrides(request, config).features
+}
+
+/// Features requested explicitly, split into those the policy allows and
+/// those it rejects
+#[derive(Debug, Default, PartialEq)]
+pub struct Overrides {
+    /// Allowed overrides, sorted
+    pub features: Vec<String>,
+    pub rejected: Vec<Rejected>,
+    /// Expressions which failed to evaluate, for adapters to count and log
+    pub errors: Vec<SourceError>,
+}
+
+/// An override dropped because it breaks the policy
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Rejected {
+    /// The override as requested, e.g. `-name` or `name=variant`
+    pub feature: String,
+    pub reason: Reason,
+}
 
-    features.sort();
+/// An explicit targeting expression which failed to evaluate
+///
+/// A request without the header or cookie an expression reads simply has no
+/// overrides, which is not an error.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SourceError {
+    /// Position of the expression in the configured sources
+    pub source: usize,
+    pub error: String,
+}
 
-    features
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// The feature is not in the allowlist
+    NotAllowed,
+    /// The feature can only be targeted implicitly
+    ImplicitOnly,
+    /// The request has more overrides than allowed, all of them are dropped
+    TooMany,
+}
+
+/// Evaluates the explicit targeting expressions and applies the policy
+pub fn overrides(request: &Request, config: &CompiledConfig) -> Overrides {
+    let mut requested = vec![];
+    let mut errors = vec![];
+    for (source, expression) in config.sources.iter().enumerate() {
+        match expression.eval(request) {
+            Ok(features) => requested.extend(features),
+            Err(error) if NotFound::is(&error) => {}
+            Err(error) => errors.push(SourceError {
+                source,
+                error: format!("{:#}", error),
+            }),
+        }
+    }
+
+    requested.sort();
+
+    let mut overrides = config.policy.apply(requested);
+    overrides.errors = errors;
+    if let Some(tokens) = &config.tokens {
+        overrides.features.extend(tokens.features(request));
+        overrides.features.sort();
+        overrides.features.dedup();
+    }
+
+    overrides
+}
+
+/// Traces of the evaluation of each of the explicit targeting expressions
+pub fn explain(request: &Request, config: &CompiledConfig) -> Vec<Trace> {
+    config.sources.iter().map(|x| x.explain(request)).collect()
 }
 
 /// Configuration
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Config(pub Vec<StrList>);
+///
+/// Without a policy or preview tokens, this is serialised as just the list of
+/// expressions.
+#[derive(Debug, Deserialize)]
+#[serde(from = "ConfigRepr")]
+pub struct Config {
+    /// Expressions reading the requested features from the request
+    pub sources: Vec<StrList>,
+    pub policy: Policy,
+    pub tokens: Option<preview::Config>,
+}
+
+impl Config {
+    /// Compile all the extraction expressions, failing on the first invalid one
+    pub fn compile(&self) -> Result<CompiledConfig> {
+        self.compile_with(&Environment::default())
+    }
+
+    /// Compile all the extraction expressions in a given environment, e.g.
+    /// with a different clock for checking the expiry of preview tokens
+    pub fn compile_with(&self, env: &Environment) -> Result<CompiledConfig> {
+        let sources = self
+            .sources
+            .iter()
+            .map(|list| list.compile_with(env))
+            .collect::<Result<Vec<_>>>()?;
+        let tokens = match &self.tokens {
+            Some(tokens) => Some(tokens.compile_with(env)?),
+            None => None,
+        };
+
+        Ok(CompiledConfig {
+            sources,
+            policy: self.policy.compile()?,
+            tokens,
+        })
+    }
+
+    /// Whether the configuration is just the list of expressions
+    pub fn is_list(&self) -> bool {
+        self.policy == Policy::default() && self.tokens.is_none()
+    }
+}
 
 impl Default for Config {
     fn default() -> Self {
-        Self(vec![StrList::Split {
-            separator: " ".to_owned(),
-            value: Str::Attribute("x-feature-overrides".to_owned()),
-        }])
+        Self {
+            sources: vec![StrList::Split {
+                separator: " ".to_owned(),
+                value: Str::Attribute("x-feature-overrides".into()),
+            }],
+            policy: Policy::default(),
+            tokens: None,
+        }
+    }
+}
+
+impl From<Vec<StrList>> for Config {
+    fn from(sources: Vec<StrList>) -> Self {
+        Self {
+            sources,
+            policy: Policy::default(),
+            tokens: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConfigRepr {
+    Sources(Vec<StrList>),
+    WithPolicy {
+        sources: Vec<StrList>,
+        #[serde(default)]
+        policy: Policy,
+        #[serde(default)]
+        tokens: Option<preview::Config>,
+    },
+}
+
+impl From<ConfigRepr> for Config {
+    fn from(repr: ConfigRepr) -> Self {
+        match repr {
+            ConfigRepr::Sources(sources) => sources.into(),
+            ConfigRepr::WithPolicy {
+                sources,
+                policy,
+                tokens,
+            } => Self {
+                sources,
+                policy,
+                tokens,
+            },
+        }
+    }
+}
+
+impl Serialize for Config {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct WithPolicy<'a> {
+            sources: &'a [StrList],
+            policy: &'a Policy,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            tokens: &'a Option<preview::Config>,
+        }
+
+        if self.is_list() {
+            self.sources.serialize(serializer)
+        } else {
+            WithPolicy {
+                sources: &self.sources,
+                policy: &self.policy,
+                tokens: &self.tokens,
+            }
+            .serialize(serializer)
+        }
+    }
+}
+
+/// Which features clients may enable, or disable, explicitly
+///
+/// Features are given as exact names or as globs, where `*` matches any run
+/// of characters, e.g. `preview-*`.
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct Policy {
+    /// The only features which may be targeted explicitly, any feature may
+    /// be when this is not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow: Option<Vec<String>>,
+    /// Features which may only be targeted implicitly, even if allowed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub implicit_only: Vec<String>,
+    /// The most overrides a request may have
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_overrides: Option<usize>,
+}
+
+impl Policy {
+    fn compile(&self) -> Result<CompiledPolicy> {
+        let allow = match &self.allow {
+            Some(allow) => Some(globs(allow)?),
+            None => None,
+        };
+
+        Ok(CompiledPolicy {
+            allow,
+            implicit_only: globs(&self.implicit_only)?,
+            max_overrides: self.max_overrides,
+        })
+    }
+}
+
+/// Matches any of the globs, as a set of anchored regular expressions
+pub(crate) fn globs(globs: &[String]) -> Result<RegexSet> {
+    let patterns = globs
+        .iter()
+        .map(|glob| format!("^{}$", regex::escape(glob).replace(r"\*", ".*")));
+
+    RegexSet::new(patterns).map_err(|e| anyhow!("Invalid feature pattern: {}", e))
+}
+
+#[derive(Debug)]
+struct CompiledPolicy {
+    allow: Option<RegexSet>,
+    implicit_only: RegexSet,
+    max_overrides: Option<usize>,
+}
+
+impl CompiledPolicy {
+    fn apply(&self, requested: Vec<String>) -> Overrides {
+        if matches!(self.max_overrides, Some(max) if requested.len() > max) {
+            let rejected = requested
+                .into_iter()
+                .map(|feature| Rejected {
+                    feature,
+                    reason: Reason::TooMany,
+                })
+                .collect();
+
+            return Overrides {
+                features: vec![],
+                rejected,
+                errors: vec![],
+            };
+        }
+
+        let mut overrides = Overrides::default();
+        for feature in requested {
+            let (name, _) = parse_feature(feature.trim_start_matches('-'));
+            let reason = if self.implicit_only.is_match(name) {
+                Some(Reason::ImplicitOnly)
+            } else if matches!(&self.allow, Some(allow) if !allow.is_match(name)) {
+                Some(Reason::NotAllowed)
+            } else {
+                None
+            };
+
+            match reason {
+                Some(reason) => overrides.rejected.push(Rejected { feature, reason }),
+                None => overrides.features.push(feature),
+            }
+        }
+
+        overrides
+    }
+}
+
+impl Default for CompiledPolicy {
+    fn default() -> Self {
+        Self {
+            allow: None,
+            implicit_only: RegexSet::empty(),
+            max_overrides: None,
+        }
+    }
+}
+
+/// Compiled configuration, ready for evaluation
+#[derive(Debug)]
+pub struct CompiledConfig {
+    sources: Vec<compiled::StrList>,
+    policy: CompiledPolicy,
+    tokens: Option<preview::Tokens>,
+}
+
+impl Default for CompiledConfig {
+    fn default() -> Self {
+        Self {
+            sources: vec![compiled::StrList::Split {
+                separator: " ".to_owned(),
+                value: compiled::Str::Attribute("x-feature-overrides".into()),
+            }],
+            policy: CompiledPolicy::default(),
+            tokens: None,
+        }
     }
 }
 
@@ -33,19 +343,22 @@ mod test {
     use lazy_static::lazy_static;
     use pretty_assertions::assert_eq as assert_eq_diff;
     use serde_json::json;
-    use std::collections::HashMap;
     use test_case::test_case;
 
     lazy_static! {
         static ref CONFIG: Config = {
-            Config(vec![
+            Config::from(vec![
                 StrList::Extract {
-                    value: Box::new(Str::Attribute("host".to_owned())),
+                    value: Box::new(Str::Attribute("host".into())),
                     regex: r#"f-([a-z]+)\.echo\.localhost"#.to_owned(),
                 },
                 StrList::Split {
                     separator: " ".to_owned(),
-                    value: Str::Attribute("x-features".to_owned()),
+                    value: Str::Attribute("x-features".into()),
+                },
+                StrList::QueryParam {
+                    name: "feature".to_owned(),
+                    value: Box::new(Str::Attribute(":path".into())),
                 },
             ])
         };
@@ -56,21 +369,22 @@ mod test {
     #[test_case(msg(vec![("x-features", "one two"), ("host", "echo.localhost")]), &*CONFIG, vec!["one", "two"]; "multiple")]
     #[test_case(msg(vec![("x-features", ""), ("host", "f-one.echo.localhost")]), &*CONFIG, vec!["one"]; "host")]
     #[test_case(msg(vec![("x-features", "one"), ("host", "f-two.echo.localhost")]), &*CONFIG, vec!["one", "two"]; "combo")]
-    fn targeting(request: HashMap<&str, &str>, config: &Config, features: Vec<&str>) {
-        assert_eq!(from_request(&request, config), features);
+    #[test_case(msg(vec![("x-features", ""), (":path", "/a?feature=one&x=y&feature=t%77o")]), &*CONFIG, vec!["one", "two"]; "query")]
+    fn targeting(request: Request, config: &Config, features: Vec<&str>) {
+        assert_eq!(from_request(&request, &config.compile().unwrap()), features);
     }
 
-    fn msg<'a, 'b>(headers: Vec<(&'a str, &'b str)>) -> HashMap<&'a str, &'b str> {
-        let mut map = HashMap::with_capacity(3 + headers.len());
-
-        map.insert("method", "GET");
-        map.insert("path", "/");
+    fn msg<'a>(headers: Vec<(&'a str, &'a str)>) -> Request<'a> {
+        let mut request = Request::new().with_header(":method", "GET");
+        if !headers.iter().any(|(name, _)| *name == ":path") {
+            request.add_header(":path", "/");
+        }
 
         for (name, value) in headers {
-            map.insert(name, value);
+            request.add_header(name, value);
         }
 
-        map
+        request
     }
 
     #[test]
@@ -87,6 +401,12 @@ mod test {
                     "separator": " ",
                     "value": { "attribute": "x-features" }
                 }
+            },
+            {
+                "query_param": {
+                    "name": "feature",
+                    "value": { "attribute": ":path" }
+                }
             }
         ])
         .to_string();
@@ -94,4 +414,134 @@ mod test {
 
         assert_eq_diff!(actual, expected);
     }
+
+    #[test]
+    fn reports_failing_sources() {
+        let config: Config = serde_json::from_value(json!([
+            { "split": { "separator": " ", "value": { "attribute": "x-features" } } },
+            { "split": { "separator": " ", "value": { "base64": { "attribute": "x-encoded" } } } },
+            { "split": { "separator": " ", "value": { "cookie": "features" } } }
+        ]))
+        .unwrap();
+        let config = config.compile().unwrap();
+
+        let missing = overrides(&msg(vec![]), &config);
+        let malformed = overrides(
+            &msg(vec![("x-features", "beta"), ("x-encoded", "not base64!")]),
+            &config,
+        );
+
+        assert_eq!(missing.errors, vec![]);
+        assert_eq!(malformed.features, vec!["beta"]);
+        assert_eq!(malformed.errors.len(), 1);
+        assert_eq!(malformed.errors[0].source, 1);
+    }
+
+    fn policy() -> Config {
+        serde_json::from_value(json!({
+            "sources": [{ "split": { "separator": " ", "value": { "attribute": "x-features" } } }],
+            "policy": {
+                "allow": ["preview-*", "dark-mode"],
+                "implicit_only": ["preview-payments*"],
+                "max_overrides": 3
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test_case("preview-cart dark-mode", vec!["dark-mode", "preview-cart"], vec![] ; "allowed")]
+    #[test_case("-dark-mode preview-cart=b", vec!["-dark-mode", "preview-cart=b"], vec![] ; "disabled and variant")]
+    #[test_case("dark-modes admin", vec![], vec![("admin", Reason::NotAllowed), ("dark-modes", Reason::NotAllowed)] ; "not allowed")]
+    #[test_case("preview-payments-v2 -preview-payments", vec![], vec![("-preview-payments", Reason::ImplicitOnly), ("preview-payments-v2", Reason::ImplicitOnly)] ; "implicit only")]
+    #[test_case("preview-a preview-b preview-c dark-mode", vec![], vec![("dark-mode", Reason::TooMany), ("preview-a", Reason::TooMany), ("preview-b", Reason::TooMany), ("preview-c", Reason::TooMany)] ; "too many")]
+    fn applies_policy(features: &str, allowed: Vec<&str>, rejected: Vec<(&str, Reason)>) {
+        let request = msg(vec![("x-features", features)]);
+
+        assert_eq!(
+            overrides(&request, &policy().compile().unwrap()),
+            Overrides {
+                features: allowed.into_iter().map(str::to_owned).collect(),
+                rejected: rejected
+                    .into_iter()
+                    .map(|(feature, reason)| Rejected {
+                        feature: feature.to_owned(),
+                        reason,
+                    })
+                    .collect(),
+                errors: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn serialises_policy_with_sources() {
+        let config = policy();
+
+        assert_eq!(config.sources.len(), 1);
+        assert_eq!(config.policy.max_overrides, Some(3));
+        assert_eq_diff!(
+            serde_json::to_value(&config).unwrap(),
+            json!({
+                "sources": [{ "split": { "separator": " ", "value": { "attribute": "x-features" } } }],
+                "policy": {
+                    "allow": ["preview-*", "dark-mode"],
+                    "implicit_only": ["preview-payments*"],
+                    "max_overrides": 3
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn accepts_overrides_from_signed_tokens() {
+        use crate::features::expression::time::FixedClock;
+        use crate::features::preview::{mint, Algorithm, Claims, SigningKey};
+        use chrono::{DateTime, Utc};
+        use std::sync::Arc;
+
+        let time = |text: &str| text.parse::<DateTime<Utc>>().unwrap();
+        let key = SigningKey::decode(Algorithm::Hs256, "c2VjcmV0").unwrap();
+        let token = |features: &[&str], exp: &str| {
+            let claims = Claims {
+                features: features.iter().map(|&f| f.to_owned()).collect(),
+                exp: time(exp).timestamp(),
+                kid: None,
+            };
+            mint(&claims, &key).unwrap()
+        };
+
+        let mut config = policy();
+        config.tokens = serde_json::from_value(json!({
+            "from": [
+                { "cookie": "preview" },
+                { "query_param": { "name": "preview", "value": { "attribute": ":path" } } }
+            ],
+            "keys": [{ "alg": "hs256", "key": "c2VjcmV0" }]
+        }))
+        .unwrap();
+        let env = Environment {
+            clock: Arc::new(FixedClock(time("2026-10-17T12:00:00Z"))),
+            ..Environment::default()
+        };
+        let config = config.compile_with(&env).unwrap();
+
+        let path = format!(
+            "/?preview={}",
+            token(&["internal-admin"], "2027-01-01T00:00:00Z")
+        );
+        let cookie = format!(
+            "preview={}",
+            token(&["dark-mode", "expired"], "2026-01-01T00:00:00Z")
+        );
+        let request = msg(vec![
+            ("x-features", "dark-mode"),
+            (":path", &path),
+            ("cookie", &cookie),
+        ]);
+
+        assert_eq!(
+            overrides(&request, &config).features,
+            vec!["dark-mode", "internal-admin"]
+        );
+    }
 }
@@ -0,0 +1,111 @@
+//! Stable bucketing for `Num::Rank`
+//!
+//! Ranks need to be the same for a given identity across releases, platforms
+//! and languages, so that users don't move in and out of rollouts. The scheme
+//! is versioned, this is `rank-v1`:
+//!
+//! 1. the key is the UTF-8 encoding of `salt + ":" + value`
+//! 2. the key is hashed with 32-bit MurmurHash3 (x86 variant) with seed 0
+//! 3. the bucket is the hash modulo 10000
+//! 4. the rank is the bucket divided by 100, i.e. between 0.00 and 99.99
+//!
+//! Test vectors for other implementations are in `test-vectors/rank-v1.json`.
+
+/// Number of buckets, giving a resolution of 0.01%
+pub const BUCKETS: u32 = 10_000;
+
+/// Rank of the value with a given salt, between 0.00 and 99.99
+pub fn rank(salt: &str, value: &str) -> f64 {
+    bucket(salt, value) as f64 / 100.0
+}
+
+/// Bucket of the value with a given salt, between 0 and 9999
+pub fn bucket(salt: &str, value: &str) -> u32 {
+    let mut key = Vec::with_capacity(salt.len() + value.len() + 1);
+    key.extend_from_slice(salt.as_bytes());
+    key.push(b':');
+    key.extend_from_slice(value.as_bytes());
+
+    murmur3_32(&key, 0) % BUCKETS
+}
+
+/// MurmurHash3 x86 32-bit
+fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut hash = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        let k = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+
+        hash ^= mix(k);
+        hash = hash
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe654_6b64);
+    }
+
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .rev()
+            .fold(0, |k, &byte| (k << 8) | u32::from(byte));
+        hash ^= mix(k);
+    }
+
+    hash ^= data.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^= hash >> 16;
+
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+    use test_case::test_case;
+
+    #[test_case("", 0, 0)]
+    #[test_case("", 1, 0x514e_28b7)]
+    #[test_case("hello", 0, 0x248b_fa47)]
+    #[test_case("abc", 0, 0xb3dd_93fa)]
+    #[test_case("The quick brown fox jumps over the lazy dog", 0, 0x2e4f_f723)]
+    fn murmur3_reference_values(input: &str, seed: u32, expected: u32) {
+        assert_eq!(murmur3_32(input.as_bytes(), seed), expected);
+    }
+
+    #[derive(Deserialize)]
+    struct Vectors {
+        vectors: Vec<Vector>,
+    }
+
+    #[derive(Deserialize)]
+    struct Vector {
+        salt: String,
+        value: String,
+        key: String,
+        hash: u32,
+        bucket: u32,
+        rank: f64,
+    }
+
+    #[test]
+    fn matches_published_test_vectors() {
+        let vectors: Vectors =
+            serde_json::from_str(include_str!("../../../test-vectors/rank-v1.json")).unwrap();
+
+        for v in vectors.vectors {
+            assert_eq!(murmur3_32(v.key.as_bytes(), 0), v.hash, "hash of {}", v.key);
+            assert_eq!(bucket(&v.salt, &v.value), v.bucket, "bucket of {}", v.key);
+            assert_eq!(rank(&v.salt, &v.value), v.rank, "rank of {}", v.key);
+        }
+    }
+}
@@ -0,0 +1,832 @@
+//! Compiled expressions, ready for evaluation
+//!
+//! The expression enums in the parent module are a serialisation format. Before
+//! evaluating them against requests they are compiled into the equivalents in
+//! this module, which hold pre-built regular expressions, constant lists as sets
+//! and a User-Agent parser, so that none of that work is repeated per request.
+//!
+//! Evaluation can optionally be traced, see the `trace` module.
+
+use super::{
+    bucket,
+    geo::{Geo, GeoError},
+    get_cookie, json_pointer,
+    jwt::Verifier,
+    network::{self, Network},
+    not_found, parse_q_value,
+    time::{self, SharedClock, SystemClock, Window},
+    trace::{NoTrace, Recorder, Trace, Tracer},
+    url,
+    version::Version,
+    Header,
+};
+use crate::{
+    features::lists::{List, Lists},
+    request::Request,
+};
+use anyhow::{anyhow, Result};
+use base64::decode as base64decode;
+use chrono_tz::Tz;
+use regex::Regex;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    net::IpAddr,
+    sync::Arc,
+};
+use woothee::parser::{Parser as UserAgentParser, WootheeResult};
+
+/// Context in which expressions are compiled
+#[derive(Debug, Clone)]
+pub struct Environment<'a> {
+    /// Salt for `Num::Rank`, the feature name for implicit targeting rules
+    pub salt: &'a str,
+    /// Source of the current time for time based expressions
+    pub clock: SharedClock,
+    /// Geo databases for `Str::Country` etc., loaded once per configuration
+    pub geo: Option<Arc<Geo>>,
+    /// Keys for verifying `Str::Jwt` tokens
+    pub jwt: Option<Arc<Verifier>>,
+    /// Compiled named segments for `Bool::Segment`
+    pub segments: Option<Arc<Segments>>,
+    /// Loaded named lists for `StrList::Named`
+    pub lists: Option<Arc<Lists>>,
+}
+
+impl Default for Environment<'_> {
+    fn default() -> Self {
+        Self {
+            salt: "",
+            clock: Arc::new(SystemClock),
+            geo: None,
+            jwt: None,
+            segments: None,
+            lists: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Bool {
+    Constant(bool),
+    Attribute(String),
+    In {
+        list: Set,
+        value: Str,
+    },
+    AnyIn {
+        list: Set,
+        values: StrList,
+    },
+    AllIn {
+        list: Set,
+        values: StrList,
+    },
+    JsonPointer {
+        pointer: String,
+        value: Str,
+    },
+    Matches(Regex, Str),
+    StrEq(Str, Str),
+    NumEq(Num, Num),
+    Gt(Num, Num),
+    Lt(Num, Num),
+    Gte(Num, Num),
+    Lte(Num, Num),
+    InNetwork {
+        networks: Vec<Network>,
+        address: Str,
+    },
+    VersionEq(VersionStr, VersionStr),
+    VersionGt(VersionStr, VersionStr),
+    VersionLt(VersionStr, VersionStr),
+    VersionGte(VersionStr, VersionStr),
+    VersionLte(VersionStr, VersionStr),
+    Not(Box<Bool>),
+    /// Evaluates operands left to right, stopping at the first which is false
+    /// or fails. The first failure is the result, later operands never run.
+    And(Vec<Bool>),
+    /// Evaluates operands left to right, stopping at the first which is true
+    /// or fails. The first failure is the result, later operands never run.
+    Or(Vec<Bool>),
+    /// The value, or the default when the value fails to evaluate
+    Try(Box<Bool>, bool),
+    Window(Window, SharedClock),
+    Segment(Arc<Segment>),
+}
+
+/// Compiled named segments, by name
+pub type Segments = HashMap<String, Arc<Segment>>;
+
+/// A named rule shared by feature rules
+#[derive(Debug)]
+pub struct Segment {
+    pub name: String,
+    pub rule: Bool,
+}
+
+impl Bool {
+    pub fn eval(&self, request: &Request) -> Result<bool> {
+        self.eval_traced(request, &mut NoTrace)
+    }
+
+    /// Evaluates the expression, recording how the result was reached
+    pub fn explain(&self, request: &Request) -> Trace {
+        let mut recorder = Recorder::default();
+        let result = self.eval_traced(request, &mut recorder);
+
+        recorder
+            .finish()
+            .unwrap_or_else(|| Trace::new("unknown", None, &result))
+    }
+
+    pub fn eval_traced<T: Tracer>(&self, request: &Request, tracer: &mut T) -> Result<bool> {
+        use Bool::*;
+        tracer.enter();
+
+        let mut decided_by = None;
+        let result = match self {
+            Constant(c) => Ok(*c),
+            Attribute(name) => request
+                .get(name)
+                .map(|_| true)
+                .ok_or_else(|| not_found(format!("Attribute '{}' not found.", name))),
+            In { list, value } => list.eval_traced(request, tracer).and_then(|haystack| {
+                value
+                    .eval_traced(request, tracer)
+                    .map(|needle| haystack.contains(&needle))
+            }),
+            AnyIn { list, values } => list.eval_traced(request, tracer).and_then(|haystack| {
+                values
+                    .eval_traced(request, tracer)
+                    .map(|needles| needles.iter().any(|needle| haystack.contains(needle)))
+            }),
+            AllIn { list, values } => list.eval_traced(request, tracer).and_then(|haystack| {
+                let items = haystack.items()?;
+                values.eval_traced(request, tracer).map(|needles| {
+                    let needles: HashSet<_> = needles.iter().collect();
+
+                    items.iter().all(|item| needles.contains(item))
+                })
+            }),
+            JsonPointer { pointer, value } => value
+                .eval_traced(request, tracer)
+                .and_then(|json| json_pointer(pointer, &json, "boolean", |v| v.as_bool())),
+            Matches(regex, value) => value
+                .eval_traced(request, tracer)
+                .map(|v| regex.is_match(&v)),
+            StrEq(left, right) => left
+                .eval_traced(request, tracer)
+                .and_then(|l| right.eval_traced(request, tracer).map(|r| l == r)),
+            NumEq(left, right) => compare(left, right, request, tracer, |l, r| {
+                (l - r).abs() < f64::EPSILON
+            }),
+            Gt(left, right) => compare(left, right, request, tracer, |l, r| l > r),
+            Lt(left, right) => compare(left, right, request, tracer, |l, r| l < r),
+            Gte(left, right) => compare(left, right, request, tracer, |l, r| l >= r),
+            Lte(left, right) => compare(left, right, request, tracer, |l, r| l <= r),
+            InNetwork { networks, address } => address
+                .eval_traced(request, tracer)
+                .and_then(|a| network::parse_address(&a))
+                .map(|a| networks.iter().any(|n| n.contains(&a))),
+            VersionEq(left, right) => compare_versions(left, right, request, tracer, |l, r| l == r),
+            VersionGt(left, right) => compare_versions(left, right, request, tracer, |l, r| l > r),
+            VersionLt(left, right) => compare_versions(left, right, request, tracer, |l, r| l < r),
+            VersionGte(left, right) => {
+                compare_versions(left, right, request, tracer, |l, r| l >= r)
+            }
+            VersionLte(left, right) => {
+                compare_versions(left, right, request, tracer, |l, r| l <= r)
+            }
+            Not(value) => value.eval_traced(request, tracer).map(|v| !v),
+            And(values) => {
+                let mut result = Ok(true);
+                for (i, value) in values.iter().enumerate() {
+                    match value.eval_traced(request, tracer) {
+                        Ok(true) => {}
+                        decisive => {
+                            decided_by = Some(i);
+                            result = decisive;
+                            break;
+                        }
+                    }
+                }
+                result
+            }
+            Or(values) => {
+                let mut result = Ok(false);
+                for (i, value) in values.iter().enumerate() {
+                    match value.eval_traced(request, tracer) {
+                        Ok(false) => {}
+                        decisive => {
+                            decided_by = Some(i);
+                            result = decisive;
+                            break;
+                        }
+                    }
+                }
+                result
+            }
+            Try(value, default) => value.eval_traced(request, tracer).or(Ok(*default)),
+            Window(window, clock) => clock.now().map(|now| window.contains(now)),
+            Segment(segment) => tracer.segment(&segment.name, |tracer| {
+                segment.rule.eval_traced(request, tracer)
+            }),
+        };
+
+        tracer.exit(|| Trace::new(self.name(), self.argument(), &result).decided_by(decided_by));
+        result
+    }
+
+    fn name(&self) -> &'static str {
+        use Bool::*;
+        match self {
+            Constant(_) => "constant",
+            Attribute(_) => "attribute",
+            In { .. } => "in",
+            AnyIn { .. } => "any_in",
+            AllIn { .. } => "all_in",
+            JsonPointer { .. } => "json_pointer",
+            Matches(_, _) => "matches",
+            StrEq(_, _) => "str_eq",
+            NumEq(_, _) => "num_eq",
+            Gt(_, _) => "gt",
+            Lt(_, _) => "lt",
+            Gte(_, _) => "gte",
+            Lte(_, _) => "lte",
+            InNetwork { .. } => "in_network",
+            VersionEq(_, _) => "version_eq",
+            VersionGt(_, _) => "version_gt",
+            VersionLt(_, _) => "version_lt",
+            VersionGte(_, _) => "version_gte",
+            VersionLte(_, _) => "version_lte",
+            Not(_) => "not",
+            And(_) => "and",
+            Or(_) => "or",
+            Try(_, _) => "try",
+            Window(_, _) => "window",
+            Segment(_) => "segment",
+        }
+    }
+
+    fn argument(&self) -> Option<&str> {
+        match self {
+            Bool::Attribute(name) => Some(name),
+            Bool::JsonPointer { pointer, .. } => Some(pointer),
+            Bool::Matches(regex, _) => Some(regex.as_str()),
+            Bool::Window(window, _) => Some(window.timezone()),
+            Bool::Segment(segment) => Some(&segment.name),
+            _ => None,
+        }
+    }
+}
+
+fn compare<T, F>(left: &Num, right: &Num, request: &Request, tracer: &mut T, op: F) -> Result<bool>
+where
+    T: Tracer,
+    F: FnOnce(f64, f64) -> bool,
+{
+    let l = left.eval_traced(request, tracer)?;
+    let r = right.eval_traced(request, tracer)?;
+    Ok(op(l, r))
+}
+
+fn compare_versions<T, F>(
+    left: &VersionStr,
+    right: &VersionStr,
+    request: &Request,
+    tracer: &mut T,
+    op: F,
+) -> Result<bool>
+where
+    T: Tracer,
+    F: FnOnce(&Version, &Version) -> bool,
+{
+    let l = left.eval_traced(request, tracer)?;
+    let r = right.eval_traced(request, tracer)?;
+    Ok(op(&l, &r))
+}
+
+/// An operand of a version comparison
+///
+/// Constant versions are parsed once, other strings are parsed per request.
+#[derive(Debug)]
+pub enum VersionStr {
+    Constant { text: String, version: Version },
+    Dynamic(Str),
+}
+
+impl VersionStr {
+    pub fn eval_traced<T: Tracer>(
+        &self,
+        request: &Request,
+        tracer: &mut T,
+    ) -> Result<Cow<'_, Version>> {
+        match self {
+            VersionStr::Constant { text, version } => {
+                tracer.enter();
+                tracer.exit(|| Trace::new("constant", None, &Ok(text)));
+                Ok(Cow::Borrowed(version))
+            }
+            VersionStr::Dynamic(value) => value
+                .eval_traced(request, tracer)
+                .and_then(|s| s.parse())
+                .map(Cow::Owned),
+        }
+    }
+}
+
+/// A list used as a haystack for membership tests
+///
+/// Constant lists are turned into a set once, named lists are loaded once and
+/// other lists are evaluated per request.
+#[derive(Debug)]
+pub enum Set {
+    Constant(HashSet<String>),
+    Dynamic(StrList),
+    Named(Arc<List>),
+}
+
+impl Set {
+    pub fn eval_traced<T: Tracer>(
+        &self,
+        request: &Request,
+        tracer: &mut T,
+    ) -> Result<Haystack<'_>> {
+        match self {
+            Set::Constant(set) => {
+                tracer.enter();
+                tracer.exit(|| {
+                    let mut items: Vec<_> = set.iter().collect();
+                    items.sort();
+                    Trace::new("constant", None, &Ok(items))
+                });
+                Ok(Haystack::Items(Cow::Borrowed(set)))
+            }
+            Set::Dynamic(list) => list
+                .eval_traced(request, tracer)
+                .map(|items| Haystack::Items(Cow::Owned(items.into_iter().collect()))),
+            Set::Named(list) => {
+                tracer.enter();
+                tracer.exit(|| Trace::new("named", Some(&list.name), &Ok(list.len())));
+                Ok(Haystack::Named(list))
+            }
+        }
+    }
+}
+
+/// An evaluated `Set`
+pub enum Haystack<'a> {
+    Items(Cow<'a, HashSet<String>>),
+    Named(&'a List),
+}
+
+impl Haystack<'_> {
+    pub fn contains(&self, needle: &str) -> bool {
+        match self {
+            Haystack::Items(items) => items.contains(needle),
+            Haystack::Named(list) => list.contains(needle),
+        }
+    }
+
+    /// All the items, which named lists don't provide
+    fn items(&self) -> Result<&HashSet<String>> {
+        match self {
+            Haystack::Items(items) => Ok(items),
+            Haystack::Named(list) => Err(anyhow!("Named list '{}' can't be listed", list.name)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StrList {
+    Constant(Vec<String>),
+    Split { separator: String, value: Str },
+    Extract { regex: Regex, value: Box<Str> },
+    HttpQualityValue(Str),
+    PathSegments(Box<Str>),
+    QueryParam { name: String, value: Box<Str> },
+}
+
+impl StrList {
+    pub fn eval(&self, request: &Request) -> Result<Vec<String>> {
+        self.eval_traced(request, &mut NoTrace)
+    }
+
+    /// Evaluates the expression, recording how the result was reached
+    pub fn explain(&self, request: &Request) -> Trace {
+        let mut recorder = Recorder::default();
+        let result = self.eval_traced(request, &mut recorder);
+
+        recorder
+            .finish()
+            .unwrap_or_else(|| Trace::new("unknown", None, &result))
+    }
+
+    pub fn eval_traced<T: Tracer>(&self, request: &Request, tracer: &mut T) -> Result<Vec<String>> {
+        use StrList::*;
+        tracer.enter();
+
+        let result = match self {
+            Constant(c) => Ok(c.clone()),
+            Split { separator, value } => value.eval_traced(request, tracer).map(|s| {
+                if s.is_empty() {
+                    return vec![];
+                }
+
+                s.split(separator.as_str()).map(|s| s.to_string()).collect()
+            }),
+            Extract { regex, value } => value.eval_traced(request, tracer).and_then(|value| {
+                let captures = regex
+                    .captures(&value)
+                    .ok_or_else(|| anyhow!("'{}' does not match '{}'", value, regex))?;
+
+                Ok(captures
+                    .iter()
+                    .filter_map(|m| m.map(|s| s.as_str().to_string()))
+                    .skip(1)
+                    .collect())
+            }),
+            HttpQualityValue(value) => value
+                .eval_traced(request, tracer)
+                .map(|s| parse_q_value(&s).iter().map(|s| s.to_string()).collect()),
+            PathSegments(value) => value
+                .eval_traced(request, tracer)
+                .and_then(|s| url::segments(&s)),
+            QueryParam { name, value } => value
+                .eval_traced(request, tracer)
+                .and_then(|s| url::query_values(&s, name)),
+        };
+
+        tracer.exit(|| Trace::new(self.name(), self.argument(), &result));
+        result
+    }
+
+    fn name(&self) -> &'static str {
+        use StrList::*;
+        match self {
+            Constant(_) => "constant",
+            Split { .. } => "split",
+            Extract { .. } => "extract",
+            HttpQualityValue(_) => "http_quality_value",
+            PathSegments(_) => "path_segments",
+            QueryParam { .. } => "query_param",
+        }
+    }
+
+    fn argument(&self) -> Option<&str> {
+        match self {
+            StrList::Split { separator, .. } => Some(separator),
+            StrList::Extract { regex, .. } => Some(regex.as_str()),
+            StrList::QueryParam { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Str {
+    Constant(String),
+    Attribute(Header),
+    Base64(Box<Str>),
+    Extract { regex: Regex, value: Box<Str> },
+    Cookie(String),
+    Browser(UserAgent),
+    BrowserVersion(UserAgent),
+    OperatingSystem(UserAgent),
+    JsonPointer { pointer: String, value: Box<Str> },
+    First(Box<StrList>),
+    Last(Box<StrList>),
+    ClientIp(usize),
+    Country(GeoLookup),
+    Region(GeoLookup),
+    Asn(GeoLookup),
+    Jwt(JwtClaims),
+    Host(Box<Str>),
+    Port(Box<Str>),
+    Path(Box<Str>),
+    PathSegment { index: usize, value: Box<Str> },
+    QueryParam { name: String, value: Box<Str> },
+}
+
+impl Str {
+    pub fn eval(&self, request: &Request) -> Result<String> {
+        self.eval_traced(request, &mut NoTrace)
+    }
+
+    pub fn eval_traced<T: Tracer>(&self, request: &Request, tracer: &mut T) -> Result<String> {
+        use Str::*;
+        tracer.enter();
+
+        let result = match self {
+            Constant(c) => Ok(c.clone()),
+            Attribute(header) => request
+                .select(&header.name, header.select)
+                .map(|s| s.into_owned())
+                .ok_or_else(|| not_found(format!("Attribute '{}' not found.", header.name))),
+            Base64(value) => value.eval_traced(request, tracer).and_then(|s| {
+                let bytes = base64decode(s)?;
+                let v = String::from_utf8(bytes)?;
+
+                Ok(v)
+            }),
+            Extract { regex, value } => value.eval_traced(request, tracer).and_then(|value| {
+                regex
+                    .captures(&value)
+                    .and_then(|c| c.get(1).map(|m| m.as_str().to_string()))
+                    .ok_or_else(|| anyhow!("'{}' does not match '{}'", value, regex))
+            }),
+            Cookie(name) => get_cookie(request, name).map(|s| s.to_string()),
+            Browser(ua) => ua.map(request, |ua| ua.name.to_string()),
+            BrowserVersion(ua) => ua.map(request, |ua| ua.version.to_string()),
+            OperatingSystem(ua) => ua.map(request, |ua| ua.os.to_string()),
+            JsonPointer { pointer, value } => value.eval_traced(request, tracer).and_then(|json| {
+                json_pointer(pointer, &json, "string", |v| {
+                    v.as_str().map(|s| s.to_string())
+                })
+            }),
+            First(list) => list
+                .eval_traced(request, tracer)
+                .and_then(|v| v.first().cloned().ok_or_else(|| anyhow!("List is empty."))),
+            Last(list) => list
+                .eval_traced(request, tracer)
+                .and_then(|v| v.last().cloned().ok_or_else(|| anyhow!("List is empty."))),
+            ClientIp(trusted_proxies) => {
+                network::client_address(request, *trusted_proxies).map(|a| a.to_string())
+            }
+            Country(lookup) => lookup.eval_traced(request, tracer, Geo::country),
+            Region(lookup) => lookup.eval_traced(request, tracer, Geo::region),
+            Asn(lookup) => lookup.eval_traced(request, tracer, Geo::asn),
+            Jwt(claims) => claims.eval_traced(request, tracer),
+            Host(value) => value
+                .eval_traced(request, tracer)
+                .and_then(|s| url::host(&s).map(str::to_owned)),
+            Port(value) => value
+                .eval_traced(request, tracer)
+                .and_then(|s| url::port(&s).map(|p| p.to_string())),
+            Path(value) => value
+                .eval_traced(request, tracer)
+                .and_then(|s| url::path(&s)),
+            PathSegment { index, value } => value.eval_traced(request, tracer).and_then(|s| {
+                url::segments(&s)?
+                    .into_iter()
+                    .nth(*index)
+                    .ok_or_else(|| anyhow!("No path segment {} in '{}'", index, s))
+            }),
+            QueryParam { name, value } => value.eval_traced(request, tracer).and_then(|s| {
+                url::query_values(&s, name)?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| anyhow!("Query parameter '{}' not found in '{}'", name, s))
+            }),
+        };
+
+        tracer.exit(|| Trace::new(self.name(), self.argument(), &result));
+        result
+    }
+
+    fn name(&self) -> &'static str {
+        use Str::*;
+        match self {
+            Constant(_) => "constant",
+            Attribute(_) => "attribute",
+            Base64(_) => "base64",
+            Extract { .. } => "extract",
+            Cookie(_) => "cookie",
+            Browser(_) => "browser",
+            BrowserVersion(_) => "browser_version",
+            OperatingSystem(_) => "operating_system",
+            JsonPointer { .. } => "json_pointer",
+            First(_) => "first",
+            Last(_) => "last",
+            ClientIp(_) => "client_ip",
+            Country(_) => "country",
+            Region(_) => "region",
+            Asn(_) => "asn",
+            Jwt(_) => "jwt",
+            Host(_) => "host",
+            Port(_) => "port",
+            Path(_) => "path",
+            PathSegment { .. } => "path_segment",
+            QueryParam { .. } => "query_param",
+        }
+    }
+
+    fn argument(&self) -> Option<&str> {
+        match self {
+            Str::Attribute(header) => Some(&header.name),
+            Str::Cookie(name) => Some(name),
+            Str::Extract { regex, .. } => Some(regex.as_str()),
+            Str::JsonPointer { pointer, .. } => Some(pointer),
+            Str::QueryParam { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// A lookup of an IP address in the geo databases
+#[derive(Debug)]
+pub struct GeoLookup {
+    pub address: Box<Str>,
+    pub geo: Arc<Geo>,
+}
+
+impl GeoLookup {
+    fn eval_traced<T, F>(&self, request: &Request, tracer: &mut T, lookup: F) -> Result<String>
+    where
+        T: Tracer,
+        F: Fn(&Geo, IpAddr) -> Result<String, GeoError>,
+    {
+        let address = self.address.eval_traced(request, tracer)?;
+        let address = network::parse_address(&address)?;
+
+        Ok(lookup(&self.geo, address)?)
+    }
+}
+
+/// Verified claims of a token
+#[derive(Debug)]
+pub struct JwtClaims {
+    pub token: Box<Str>,
+    pub verifier: Arc<Verifier>,
+    pub clock: SharedClock,
+}
+
+impl JwtClaims {
+    fn eval_traced<T: Tracer>(&self, request: &Request, tracer: &mut T) -> Result<String> {
+        let token = self.token.eval_traced(request, tracer)?;
+        let claims = self.verifier.verify(&token, self.clock.now()?)?;
+
+        Ok(claims.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub enum Num {
+    Constant(f64),
+    Attribute(String),
+    Rank { value: Str, salt: String },
+    JsonPointer { pointer: String, value: Str },
+    Now(SharedClock),
+    Time { value: Str, timezone: Option<Tz> },
+    Since(Box<Num>, SharedClock),
+}
+
+impl Num {
+    pub fn eval(&self, request: &Request) -> Result<f64> {
+        self.eval_traced(request, &mut NoTrace)
+    }
+
+    pub fn eval_traced<T: Tracer>(&self, request: &Request, tracer: &mut T) -> Result<f64> {
+        use Num::*;
+        tracer.enter();
+
+        let result = match self {
+            Constant(c) => Ok(*c),
+            Attribute(name) => match request.get(name) {
+                Some(s) => s.parse::<f64>().map_err(|e| e.into()),
+                None => Err(not_found(format!("Attribute '{}' not found.", name))),
+            },
+            Rank { value, salt } => value
+                .eval_traced(request, tracer)
+                .map(|s| bucket::rank(salt, &s)),
+            JsonPointer { pointer, value } => value
+                .eval_traced(request, tracer)
+                .and_then(|json| json_pointer(pointer, &json, "number", |v| v.as_f64())),
+            Now(clock) => clock.now().map(time::seconds),
+            Time { value, timezone } => value
+                .eval_traced(request, tracer)
+                .and_then(|s| time::parse_time(&s, timezone.as_ref()).map(time::seconds)),
+            Since(value, clock) => value
+                .eval_traced(request, tracer)
+                .and_then(|t| clock.now().map(|now| time::seconds(now) - t)),
+        };
+
+        tracer.exit(|| Trace::new(self.name(), self.argument(), &result));
+        result
+    }
+
+    fn name(&self) -> &'static str {
+        use Num::*;
+        match self {
+            Constant(_) => "constant",
+            Attribute(_) => "attribute",
+            Rank { .. } => "rank",
+            JsonPointer { .. } => "json_pointer",
+            Now(_) => "now",
+            Time { .. } => "time",
+            Since(_, _) => "since",
+        }
+    }
+
+    fn argument(&self) -> Option<&str> {
+        match self {
+            Num::Attribute(name) => Some(name),
+            Num::Rank { salt, .. } => Some(salt),
+            Num::JsonPointer { pointer, .. } => Some(pointer),
+            Num::Time {
+                timezone: Some(tz), ..
+            } => Some(tz.name()),
+            _ => None,
+        }
+    }
+}
+
+/// A User-Agent parser, created once when the expression is compiled
+#[derive(Default)]
+pub struct UserAgent(UserAgentParser);
+
+impl UserAgent {
+    pub fn new() -> Self {
+        Self(UserAgentParser::new())
+    }
+
+    fn map<'r, V, F>(&self, request: &'r Request, map: F) -> Result<V>
+    where
+        F: FnOnce(WootheeResult<'r>) -> V,
+        V: 'r,
+    {
+        if let Some(ua) = request.get("user-agent") {
+            if let Some(ua) = self.0.parse(ua) {
+                Ok(map(ua))
+            } else {
+                Err(anyhow!("Malformed User-Agent string: {}", ua))
+            }
+        } else {
+            Err(anyhow!("User-Agent header not found"))
+        }
+    }
+}
+
+impl fmt::Debug for UserAgent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("UserAgent")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::features::expression as source;
+
+    #[test]
+    fn rejects_invalid_regex() {
+        let expr = source::Bool::Matches("(unclosed".into(), source::Str::Constant("a".into()));
+
+        assert!(expr.compile().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_regex_in_nested_expression() {
+        let expr = source::Bool::And(vec![
+            source::Bool::Constant(true),
+            source::Bool::In {
+                list: source::StrList::Constant(vec!["a".into()]),
+                value: source::Str::Extract {
+                    regex: "[a-".into(),
+                    value: Box::new(source::Str::Attribute("host".into())),
+                },
+            },
+        ]);
+
+        assert!(expr.compile().is_err());
+    }
+
+    #[test]
+    fn stops_evaluating_once_decided() {
+        let expr: source::Bool =
+            r#"header("a") == "1" or header("b") == "1" or header("c") == "1""#
+                .parse()
+                .unwrap();
+        let request = [("a", "0"), ("b", "1"), ("c", "1")]
+            .iter()
+            .cloned()
+            .collect();
+
+        let trace = expr.compile().unwrap().explain(&request);
+
+        assert_eq!(trace.value, Some(true.into()));
+        assert_eq!(trace.decided_by, Some(1));
+        assert_eq!(trace.operands.len(), 2);
+    }
+
+    #[test]
+    fn compiles_constant_lists_to_sets() {
+        let expr = source::Bool::In {
+            list: source::StrList::Constant(vec!["a".into(), "b".into()]),
+            value: source::Str::Attribute("letter".into()),
+        };
+        let compiled = expr.compile().unwrap();
+
+        assert!(matches!(
+            compiled,
+            super::Bool::In {
+                list: super::Set::Constant(_),
+                ..
+            }
+        ));
+
+        let request = [("letter", "b")].iter().cloned().collect();
+        assert!(compiled.eval(&request).unwrap());
+    }
+}
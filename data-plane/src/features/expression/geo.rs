@@ -0,0 +1,209 @@
+//! Geo location of IP addresses from MaxMind format databases
+//!
+//! Any number of `.mmdb` databases can be configured, e.g. GeoLite2 City for
+//! countries and regions and GeoLite2 ASN for autonomous systems. Each lookup
+//! uses the first database with the requested data for the address.
+
+use crate::features::data::{Loader, Source};
+use anyhow::{Context, Result};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use serde::{Deserialize, Serialize};
+use std::{error, fmt, net::IpAddr};
+
+/// Geo databases to load
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct Config {
+    pub databases: Vec<Source>,
+}
+
+impl Config {
+    pub fn load(&self, loader: &dyn Loader) -> Result<Geo> {
+        let databases = self
+            .databases
+            .iter()
+            .map(|source| {
+                loader
+                    .load(source)
+                    .and_then(|bytes| Ok(Reader::from_source(bytes)?))
+                    .with_context(|| format!("Failed to load geo database from {}", source))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Geo { databases })
+    }
+}
+
+/// Geo data looked up for an address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Country,
+    Region,
+    Asn,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Field::Country => "country",
+            Field::Region => "region",
+            Field::Asn => "ASN",
+        })
+    }
+}
+
+/// Error looking up geo data
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeoError {
+    /// The configuration has no geo databases
+    NoDatabase,
+    /// None of the databases have the data for the address
+    NotFound { field: Field, address: IpAddr },
+    /// A database is corrupted
+    InvalidDatabase(String),
+}
+
+impl fmt::Display for GeoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoError::NoDatabase => write!(f, "Geo lookups need a geo database"),
+            GeoError::NotFound { field, address } => {
+                write!(f, "No {} found for {}", field, address)
+            }
+            GeoError::InvalidDatabase(e) => write!(f, "Invalid geo database: {}", e),
+        }
+    }
+}
+
+impl error::Error for GeoError {}
+
+/// Loaded geo databases
+pub struct Geo {
+    databases: Vec<Reader<Vec<u8>>>,
+}
+
+impl Geo {
+    /// ISO 3166-1 country code, e.g. `DE`
+    pub fn country(&self, address: IpAddr) -> Result<String, GeoError> {
+        self.find(Field::Country, address, |db| {
+            let city: geoip2::City = db.lookup(address)?;
+            Ok(city.country.and_then(|c| c.iso_code).map(str::to_owned))
+        })
+    }
+
+    /// ISO 3166-2 code of the largest subdivision, e.g. `BY` for Bavaria
+    pub fn region(&self, address: IpAddr) -> Result<String, GeoError> {
+        self.find(Field::Region, address, |db| {
+            let city: geoip2::City = db.lookup(address)?;
+            Ok(city
+                .subdivisions
+                .and_then(|s| s.into_iter().next())
+                .and_then(|s| s.iso_code)
+                .map(str::to_owned))
+        })
+    }
+
+    /// Autonomous system number, e.g. `15169`
+    pub fn asn(&self, address: IpAddr) -> Result<String, GeoError> {
+        self.find(Field::Asn, address, |db| {
+            let asn: geoip2::Asn = db.lookup(address)?;
+            Ok(asn.autonomous_system_number.map(|n| n.to_string()))
+        })
+    }
+
+    fn find<F>(&self, field: Field, address: IpAddr, lookup: F) -> Result<String, GeoError>
+    where
+        F: Fn(&Reader<Vec<u8>>) -> Result<Option<String>, MaxMindDBError>,
+    {
+        if self.databases.is_empty() {
+            return Err(GeoError::NoDatabase);
+        }
+
+        for db in &self.databases {
+            match lookup(db) {
+                Ok(Some(value)) => return Ok(value),
+                Ok(None) | Err(MaxMindDBError::AddressNotFoundError(_)) => {}
+                // e.g. looking up an IPv6 address in an IPv4 only database
+                Err(MaxMindDBError::InvalidNetworkError(_)) => {}
+                Err(e) => return Err(GeoError::InvalidDatabase(e.to_string())),
+            }
+        }
+
+        Err(GeoError::NotFound { field, address })
+    }
+}
+
+impl fmt::Debug for Geo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Geo({} databases)", self.databases.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::features::data::DefaultLoader;
+    use test_case::test_case;
+
+    // The test database has:
+    //
+    // - 81.2.69.0/24: country GB, region ENG
+    // - 89.160.20.0/24: country SE, region E, ASN 29518
+    // - 2.125.160.0/24: country DE
+    // - 1.0.0.0/24: ASN 15169
+    fn test_geo() -> Geo {
+        let config = Config {
+            databases: vec![Source::Path(format!(
+                "{}/test-data/geo.mmdb",
+                env!("CARGO_MANIFEST_DIR")
+            ))],
+        };
+
+        config.load(&DefaultLoader).unwrap()
+    }
+
+    fn not_found(field: Field, address: &str) -> Result<String, GeoError> {
+        Err(GeoError::NotFound {
+            field,
+            address: address.parse().unwrap(),
+        })
+    }
+
+    #[test_case(Field::Country, "81.2.69.160", Ok("GB".into()))]
+    #[test_case(Field::Region, "81.2.69.160", Ok("ENG".into()))]
+    #[test_case(Field::Asn, "89.160.20.112", Ok("29518".into()))]
+    #[test_case(Field::Country, "2.125.160.216", Ok("DE".into()))]
+    #[test_case(Field::Region, "2.125.160.216", not_found(Field::Region, "2.125.160.216") ; "missing region")]
+    #[test_case(Field::Country, "1.0.0.1", not_found(Field::Country, "1.0.0.1") ; "missing country")]
+    #[test_case(Field::Country, "8.8.8.8", not_found(Field::Country, "8.8.8.8") ; "unknown address")]
+    #[test_case(Field::Country, "2001:db8::1", not_found(Field::Country, "2001:db8::1") ; "ipv6")]
+    fn looks_up_addresses(field: Field, address: &str, expected: Result<String, GeoError>) {
+        let geo = test_geo();
+        let address = address.parse().unwrap();
+
+        let actual = match field {
+            Field::Country => geo.country(address),
+            Field::Region => geo.region(address),
+            Field::Asn => geo.asn(address),
+        };
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn fails_without_databases() {
+        let geo = Config::default().load(&DefaultLoader).unwrap();
+
+        assert_eq!(
+            geo.country("81.2.69.160".parse().unwrap()),
+            Err(GeoError::NoDatabase)
+        );
+    }
+
+    #[test]
+    fn fails_to_load_invalid_databases() {
+        let config = Config {
+            databases: vec![Source::Base64("bm90IGEgZGF0YWJhc2U=".into())],
+        };
+
+        assert!(config.load(&DefaultLoader).is_err());
+    }
+}
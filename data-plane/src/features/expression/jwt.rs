@@ -0,0 +1,488 @@
+//! Verified JSON Web Tokens
+//!
+//! Tokens are verified against a configured JSON Web Key Set before any of
+//! their claims are trusted. RS256 (RSA keys), ES256 (P-256 keys) and HS256
+//! (shared secrets) signatures are supported. A key is chosen by the `kid` in
+//! the token header, or when there is none, every key for the algorithm is
+//! tried. Besides the signature, the `exp` and `nbf` times and, when
+//! configured, the `iss` and `aud` claims are checked.
+
+use anyhow::{anyhow, bail, Result};
+use base64::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use p256::ecdsa;
+use rsa::{pkcs1v15, signature::Verifier as _, BigUint, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use std::{convert::TryFrom, error, fmt};
+
+/// Token verification settings
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct Config {
+    /// Keys trusted to sign tokens
+    pub jwks: Jwks,
+    /// The required `iss` claim
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    /// The required `aud` claim, or one of its values when it is a list
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    /// Allowed clock skew in seconds when checking `exp` and `nbf`
+    #[serde(default)]
+    pub leeway: u64,
+}
+
+impl Config {
+    pub fn load(&self) -> Result<Verifier> {
+        let keys = self
+            .jwks
+            .keys
+            .iter()
+            .map(Key::from_jwk)
+            .collect::<Result<_>>()?;
+
+        Ok(Verifier {
+            keys,
+            issuer: self.issuer.clone(),
+            audience: self.audience.clone(),
+            leeway: self.leeway as i64,
+        })
+    }
+}
+
+/// A JSON Web Key Set, as defined by
+/// [RFC7517](https://tools.ietf.org/html/rfc7517#section-5)
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+/// A public key or shared secret, only the parameters needed for verification
+/// are kept
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    /// RSA modulus
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    /// RSA exponent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    /// Elliptic curve point
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+    /// Shared secret
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub k: Option<String>,
+}
+
+/// Shared secrets are redacted, so configurations can be logged
+impl fmt::Debug for Jwk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Jwk")
+            .field("kty", &self.kty)
+            .field("kid", &self.kid)
+            .field("alg", &self.alg)
+            .field("crv", &self.crv)
+            .field("n", &self.n)
+            .field("e", &self.e)
+            .field("x", &self.x)
+            .field("y", &self.y)
+            .field("k", &self.k.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl Jwk {
+    /// Checks the key is supported and complete
+    pub fn validate(&self) -> Result<()> {
+        Key::from_jwk(self).map(|_| ())
+    }
+}
+
+/// Reason a token was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum JwtError {
+    /// The configuration has no token verification settings
+    NotConfigured,
+    /// The token is not a well formed JWT
+    Malformed(String),
+    UnsupportedAlgorithm(String),
+    /// No configured key can verify the token
+    UnknownKey {
+        algorithm: String,
+        kid: Option<String>,
+    },
+    InvalidSignature,
+    Expired,
+    NotYetValid,
+    InvalidIssuer(Option<String>),
+    InvalidAudience,
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use JwtError::*;
+        match self {
+            NotConfigured => write!(f, "Verifying tokens needs JWT keys in the configuration"),
+            Malformed(reason) => write!(f, "Malformed token: {}", reason),
+            UnsupportedAlgorithm(alg) => write!(f, "Unsupported token algorithm '{}'", alg),
+            UnknownKey {
+                algorithm,
+                kid: Some(kid),
+            } => write!(f, "No {} key with id '{}'", algorithm, kid),
+            UnknownKey {
+                algorithm,
+                kid: None,
+            } => write!(f, "No {} key", algorithm),
+            InvalidSignature => write!(f, "Invalid token signature"),
+            Expired => write!(f, "Token has expired"),
+            NotYetValid => write!(f, "Token is not valid yet"),
+            InvalidIssuer(Some(iss)) => write!(f, "Token issuer '{}' is not trusted", iss),
+            InvalidIssuer(None) => write!(f, "Token has no issuer"),
+            InvalidAudience => write!(f, "Token is not intended for this audience"),
+        }
+    }
+}
+
+impl error::Error for JwtError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Algorithm {
+    RS256,
+    ES256,
+    HS256,
+}
+
+impl Algorithm {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "RS256" => Some(Algorithm::RS256),
+            "ES256" => Some(Algorithm::ES256),
+            "HS256" => Some(Algorithm::HS256),
+            _ => None,
+        }
+    }
+}
+
+enum KeyMaterial {
+    Rsa(pkcs1v15::VerifyingKey<Sha256>),
+    Ec(ecdsa::VerifyingKey),
+    Hmac(Vec<u8>),
+}
+
+struct Key {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    material: KeyMaterial,
+}
+
+impl Key {
+    /// Builds a key from a JWK, failing on unsupported or incomplete keys
+    fn from_jwk(jwk: &Jwk) -> Result<Self> {
+        let param = |value: &Option<String>, name: &str| -> Result<Vec<u8>> {
+            let value = value
+                .as_ref()
+                .ok_or_else(|| anyhow!("{} key is missing '{}'", jwk.kty, name))?;
+
+            base64::decode_config(value, URL_SAFE_NO_PAD)
+                .map_err(|e| anyhow!("Invalid '{}' of {} key: {}", name, jwk.kty, e))
+        };
+
+        let (algorithm, material) = match jwk.kty.as_str() {
+            "RSA" => {
+                let n = BigUint::from_bytes_be(&param(&jwk.n, "n")?);
+                let e = BigUint::from_bytes_be(&param(&jwk.e, "e")?);
+                let key = RsaPublicKey::new(n, e)?;
+
+                (
+                    Algorithm::RS256,
+                    KeyMaterial::Rsa(pkcs1v15::VerifyingKey::new(key)),
+                )
+            }
+            "EC" => {
+                if jwk.crv.as_deref() != Some("P-256") {
+                    bail!("Unsupported curve {:?}, only P-256 is supported", jwk.crv);
+                }
+                // an uncompressed SEC1 point
+                let mut point = vec![0x04];
+                point.extend(check_len(param(&jwk.x, "x")?, 32)?);
+                point.extend(check_len(param(&jwk.y, "y")?, 32)?);
+                let key = ecdsa::VerifyingKey::from_sec1_bytes(&point)?;
+
+                (Algorithm::ES256, KeyMaterial::Ec(key))
+            }
+            "oct" => (Algorithm::HS256, KeyMaterial::Hmac(param(&jwk.k, "k")?)),
+            kty => bail!("Unsupported key type '{}'", kty),
+        };
+
+        if let Some(alg) = &jwk.alg {
+            if Algorithm::parse(alg) != Some(algorithm) {
+                bail!("Unsupported algorithm '{}' for {} key", alg, jwk.kty);
+            }
+        }
+
+        Ok(Self {
+            kid: jwk.kid.clone(),
+            algorithm,
+            material,
+        })
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match &self.material {
+            KeyMaterial::Rsa(key) => pkcs1v15::Signature::try_from(signature)
+                .map(|s| key.verify(message, &s).is_ok())
+                .unwrap_or(false),
+            KeyMaterial::Ec(key) => ecdsa::Signature::from_slice(signature)
+                .map(|s| key.verify(message, &s).is_ok())
+                .unwrap_or(false),
+            KeyMaterial::Hmac(secret) => Hmac::<Sha256>::new_from_slice(secret)
+                .map(|mut mac| {
+                    mac.update(message);
+                    mac.verify_slice(signature).is_ok()
+                })
+                .unwrap_or(false),
+        }
+    }
+}
+
+fn check_len(bytes: Vec<u8>, len: usize) -> Result<Vec<u8>> {
+    if bytes.len() != len {
+        bail!("Expected a {} byte coordinate, found {}", len, bytes.len());
+    }
+
+    Ok(bytes)
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// Verifies tokens with a set of loaded keys
+pub struct Verifier {
+    keys: Vec<Key>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: i64,
+}
+
+impl Verifier {
+    /// Verifies a token at the time `now`, returning its claims
+    ///
+    /// Tokens can have a `Bearer ` prefix, as in the `authorization` header.
+    pub fn verify(&self, token: &str, now: DateTime<Utc>) -> Result<Value, JwtError> {
+        let token = token.trim();
+        let token = token.strip_prefix("Bearer ").unwrap_or(token);
+
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(malformed("expected three segments"));
+        }
+
+        let header: Header = decode_json(parts[0])?;
+        let algorithm = Algorithm::parse(&header.alg)
+            .ok_or_else(|| JwtError::UnsupportedAlgorithm(header.alg.clone()))?;
+
+        let mut keys = self
+            .keys
+            .iter()
+            .filter(|key| key.algorithm == algorithm)
+            .filter(|key| header.kid.is_none() || key.kid == header.kid)
+            .peekable();
+        if keys.peek().is_none() {
+            return Err(JwtError::UnknownKey {
+                algorithm: header.alg,
+                kid: header.kid,
+            });
+        }
+
+        let message = &token[..parts[0].len() + 1 + parts[1].len()];
+        let signature = decode(parts[2])?;
+        if !keys.any(|key| key.verify(message.as_bytes(), &signature)) {
+            return Err(JwtError::InvalidSignature);
+        }
+
+        let claims: Value = decode_json(parts[1])?;
+        self.check_claims(&claims, now.timestamp())?;
+
+        Ok(claims)
+    }
+
+    fn check_claims(&self, claims: &Value, now: i64) -> Result<(), JwtError> {
+        let time = |name: &str| match claims.get(name) {
+            None => Ok(None),
+            Some(value) => value
+                .as_i64()
+                .map(Some)
+                .ok_or_else(|| malformed(&format!("'{}' is not a number of seconds", name))),
+        };
+
+        if let Some(exp) = time("exp")? {
+            if now >= exp + self.leeway {
+                return Err(JwtError::Expired);
+            }
+        }
+        if let Some(nbf) = time("nbf")? {
+            if now + self.leeway < nbf {
+                return Err(JwtError::NotYetValid);
+            }
+        }
+
+        if let Some(issuer) = &self.issuer {
+            let iss = claims.get("iss").and_then(Value::as_str);
+            if iss != Some(issuer) {
+                return Err(JwtError::InvalidIssuer(iss.map(str::to_owned)));
+            }
+        }
+
+        if let Some(audience) = &self.audience {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud == audience.as_str()),
+                _ => false,
+            };
+            if !matches {
+                return Err(JwtError::InvalidAudience);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Verifier({} keys)", self.keys.len())
+    }
+}
+
+fn malformed(reason: &str) -> JwtError {
+    JwtError::Malformed(reason.to_owned())
+}
+
+fn decode(segment: &str) -> Result<Vec<u8>, JwtError> {
+    base64::decode_config(segment, URL_SAFE_NO_PAD).map_err(|e| malformed(&e.to_string()))
+}
+
+fn decode_json<T: serde::de::DeserializeOwned>(segment: &str) -> Result<T, JwtError> {
+    serde_json::from_slice(&decode(segment)?).map_err(|e| malformed(&e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use test_case::test_case;
+
+    // Keys and tokens signed with them. The tokens are valid during 2026,
+    // issued by `https://auth.example.com` for `feature-targeting`.
+    const FIXTURE: &str = include_str!("../../../test-data/jwt.json");
+
+    fn verifier(issuer: Option<&str>, audience: Option<&str>) -> Verifier {
+        let fixture: Value = serde_json::from_str(FIXTURE).unwrap();
+        let config = Config {
+            jwks: serde_json::from_value(fixture["jwks"].clone()).unwrap(),
+            issuer: issuer.map(str::to_owned),
+            audience: audience.map(str::to_owned),
+            leeway: 60,
+        };
+
+        config.load().unwrap()
+    }
+
+    fn token(name: &str) -> String {
+        let fixture: Value = serde_json::from_str(FIXTURE).unwrap();
+
+        fixture["tokens"][name].as_str().unwrap().to_owned()
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn redacts_shared_secrets() {
+        let config: Config = serde_json::from_value(json!({
+            "jwks": { "keys": [{ "kty": "oct", "kid": "hs", "k": "c2hhcmVkLXNlY3JldA" }] }
+        }))
+        .unwrap();
+
+        let debug = format!("{:?}", config);
+
+        assert!(!debug.contains("c2hhcmVkLXNlY3JldA"), "{}", debug);
+        assert!(debug.contains(r#"kid: Some("hs")"#), "{}", debug);
+        assert!(debug.contains(r#"k: Some("<redacted>")"#), "{}", debug);
+    }
+
+    #[test_case("rs256", Ok("alice"))]
+    #[test_case("es256", Ok("bob"))]
+    #[test_case("hs256", Ok("carol") ; "without kid")]
+    #[test_case("tampered", Err(JwtError::InvalidSignature))]
+    #[test_case("wrong_issuer", Err(JwtError::InvalidIssuer(Some("https://evil.example.com".into()))))]
+    #[test_case("wrong_audience", Err(JwtError::InvalidAudience))]
+    #[test_case("unknown_key", Err(JwtError::UnknownKey { algorithm: "HS256".into(), kid: Some("hmac-2".into()) }))]
+    #[test_case("key_confusion", Err(JwtError::UnknownKey { algorithm: "HS256".into(), kid: Some("rsa-1".into()) }))]
+    #[test_case("unsigned", Err(JwtError::UnsupportedAlgorithm("none".into())))]
+    fn verifies_tokens(name: &str, expected: Result<&str, JwtError>) {
+        let verifier = verifier(Some("https://auth.example.com"), Some("feature-targeting"));
+
+        let actual = verifier.verify(&token(name), at("2026-06-01T12:00:00Z"));
+        assert_eq!(
+            actual.map(|claims| claims["sub"].clone()),
+            expected.map(|sub| json!(sub))
+        );
+    }
+
+    #[test_case("2025-12-31T23:58:00Z", Err(JwtError::NotYetValid) ; "before nbf")]
+    #[test_case("2025-12-31T23:59:30Z", Ok(()) ; "within leeway of nbf")]
+    #[test_case("2027-01-01T00:00:30Z", Ok(()) ; "within leeway of exp")]
+    #[test_case("2027-01-01T00:01:00Z", Err(JwtError::Expired) ; "after exp")]
+    fn checks_validity_period(now: &str, expected: Result<(), JwtError>) {
+        let verifier = verifier(None, None);
+
+        let actual = verifier.verify(&token("rs256"), at(now));
+        assert_eq!(actual.map(|_| ()), expected);
+    }
+
+    #[test]
+    fn accepts_bearer_tokens() {
+        let verifier = verifier(None, None);
+        let header = format!("Bearer {}", token("es256"));
+
+        let claims = verifier
+            .verify(&header, at("2026-06-01T12:00:00Z"))
+            .unwrap();
+        assert_eq!(claims["roles"], json!(["beta", "staff"]));
+    }
+
+    #[test_case("not a token", "Malformed token: expected three segments")]
+    #[test_case("e30.e30.", "Malformed token: missing field `alg` at line 1 column 2")]
+    fn rejects_malformed_tokens(token: &str, expected: &str) {
+        let actual = verifier(None, None).verify(token, at("2026-06-01T12:00:00Z"));
+
+        assert_eq!(actual.unwrap_err().to_string(), expected);
+    }
+
+    #[test_case(json!({ "kty": "RSA", "n": "AQAB" }), "RSA key is missing 'e'")]
+    #[test_case(json!({ "kty": "EC", "crv": "P-384", "x": "", "y": "" }), "Unsupported curve Some(\"P-384\"), only P-256 is supported")]
+    #[test_case(json!({ "kty": "oct", "alg": "HS512", "k": "c2VjcmV0" }), "Unsupported algorithm 'HS512' for oct key")]
+    #[test_case(json!({ "kty": "OKP" }), "Unsupported key type 'OKP'")]
+    fn rejects_unsupported_keys(jwk: Value, expected: &str) {
+        let jwk: Jwk = serde_json::from_value(jwk).unwrap();
+
+        assert_eq!(Key::from_jwk(&jwk).err().unwrap().to_string(), expected);
+    }
+}
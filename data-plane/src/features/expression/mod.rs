@@ -1,14 +1,24 @@
+use crate::request::{Request, Select};
 use anyhow::{anyhow, Result};
-use base64::decode as base64decode;
+use compiled::{Environment, UserAgent};
+use geo::GeoError;
+use jwt::JwtError;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{
-    collections::{hash_map::DefaultHasher, HashMap, HashSet},
-    hash::{Hash, Hasher},
-};
-use woothee::parser::{Parser as UserAgentParser, WootheeResult};
-
-#[derive(Deserialize, Serialize, Debug)]
+use std::fmt;
+
+pub mod bucket;
+pub mod compiled;
+pub mod geo;
+pub mod jwt;
+pub mod network;
+pub mod syntax;
+pub mod time;
+pub mod trace;
+pub mod url;
+pub mod version;
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum Bool {
     /// The identity expression
@@ -47,96 +57,174 @@ pub enum Bool {
     Gte(Num, Num),
     /// <= for numbers
     Lte(Num, Num),
+    /// The IP address is in any of the networks, e.g. `10.0.0.0/8` or `2001:db8::/32`
+    InNetwork { networks: Vec<String>, address: Str },
+    /// == for version numbers, see the `version` module for how they compare
+    VersionEq(Str, Str),
+    /// > for version numbers
+    VersionGt(Str, Str),
+    /// < for version numbers
+    VersionLt(Str, Str),
+    /// >= for version numbers
+    VersionGte(Str, Str),
+    /// <= for version numbers
+    VersionLte(Str, Str),
     /// Logical NOT
     Not(Box<Bool>),
-    /// Logical AND
+    /// Logical AND, short-circuiting from left to right
+    ///
+    /// Evaluation stops at the first operand which is false or fails to
+    /// evaluate. `false and <error>` is false, `<error> and false` is the
+    /// error, which turns the feature off unless its error policy says
+    /// otherwise. Put cheap and reliable operands first.
     And(Vec<Bool>),
-    /// Logical OR
+    /// Logical OR, short-circuiting from left to right
+    ///
+    /// Evaluation stops at the first operand which is true or fails to
+    /// evaluate, so `true or <error>` is true and `<error> or true` is the
+    /// error.
     Or(Vec<Bool>),
+    /// The value, or the default when the value fails to evaluate, e.g.
+    /// `try(header("x-beta") == "yes", false)` is false without the header
+    Try { value: Box<Bool>, default: bool },
+    /// The current time is within a recurring window
+    ///
+    /// `from` and `until` are local times of day in the time zone, e.g. `09:00`
+    /// and `17:30`. If `until` is before `from`, the window ends the next day.
+    /// The window recurs daily, or only starting on the given `days`, e.g.
+    /// `["sat", "sun"]`.
+    Window {
+        from: String,
+        until: String,
+        timezone: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        days: Vec<String>,
+    },
+    /// The request is in a named segment, defined next to the features
+    Segment(String),
 }
 
 impl Bool {
-    pub fn eval(&self, request: &HashMap<&str, &str>) -> Result<bool> {
-        use Bool::*;
-        match self {
-            Constant(c) => Ok(*c),
-            Attribute(name) => request
-                .get::<str>(&name)
-                .map(|_| true)
-                .ok_or_else(|| anyhow!("Attribute '{}' not found.", name)),
-            In { list, value } => list
-                .eval(request)
-                .and_then(|haystack| value.eval(request).map(|needle| haystack.contains(&needle))),
-            AnyIn { list, values } => list.eval(request).and_then(|haystack| {
-                values.eval(request).map(|needles| {
-                    let a: HashSet<_> = haystack.iter().collect();
-                    let b: HashSet<_> = needles.iter().collect();
-
-                    a.intersection(&b).next().is_some()
-                })
-            }),
-            AllIn { list, values } => list.eval(request).and_then(|haystack| {
-                values.eval(request).map(|needles| {
-                    let a: HashSet<_> = haystack.iter().collect();
-                    let b: HashSet<_> = needles.iter().collect();
+    /// Compiles and evaluates the expression in one go, for tests
+    ///
+    /// Targeting compiles expressions once, when the configuration is loaded.
+    #[cfg(test)]
+    pub(crate) fn eval(&self, request: &Request) -> Result<bool> {
+        self.compile()?.eval(request)
+    }
 
-                    a.intersection(&b).count() == a.len()
-                })
-            }),
-            JsonPointer { pointer, value } => value
-                .eval(request)
-                .and_then(|json| json_pointer(&pointer, &json, "boolean", |v| v.as_bool())),
+    /// Compile the expression for repeated evaluation
+    ///
+    /// Fails if any of the regular expressions in the tree are invalid.
+    pub fn compile(&self) -> Result<compiled::Bool> {
+        self.compile_with(&Environment::default())
+    }
+
+    /// Compile the expression in a given environment
+    pub fn compile_with(&self, env: &Environment) -> Result<compiled::Bool> {
+        use Bool::*;
+        Ok(match self {
+            Constant(c) => compiled::Bool::Constant(*c),
+            Attribute(name) => compiled::Bool::Attribute(name.clone()),
+            In { list, value } => compiled::Bool::In {
+                list: list.compile_set(env)?,
+                value: value.compile_with(env)?,
+            },
+            AnyIn { list, values } => compiled::Bool::AnyIn {
+                list: list.compile_set(env)?,
+                values: values.compile_with(env)?,
+            },
+            AllIn { list, values } => compiled::Bool::AllIn {
+                list: match list {
+                    StrList::Named(name) => return Err(only_searchable(name)),
+                    list => list.compile_set(env)?,
+                },
+                values: values.compile_with(env)?,
+            },
+            JsonPointer { pointer, value } => compiled::Bool::JsonPointer {
+                pointer: pointer.clone(),
+                value: value.compile_with(env)?,
+            },
             Matches(regex, value) => {
-                let v = value.eval(request)?;
-                let r = Regex::new(&regex)?;
-                Ok(r.is_match(&v))
+                compiled::Bool::Matches(Regex::new(regex)?, value.compile_with(env)?)
             }
             StrEq(left, right) => {
-                let l = left.eval(request)?;
-                let r = right.eval(request)?;
-                Ok(l == r)
+                compiled::Bool::StrEq(left.compile_with(env)?, right.compile_with(env)?)
             }
             NumEq(left, right) => {
-                let l = left.eval(request)?;
-                let r = right.eval(request)?;
-                Ok((l - r).abs() < std::f64::EPSILON)
+                compiled::Bool::NumEq(left.compile_with(env)?, right.compile_with(env)?)
             }
             Gt(left, right) => {
-                let l = left.eval(request)?;
-                let r = right.eval(request)?;
-                Ok(l > r)
+                compiled::Bool::Gt(left.compile_with(env)?, right.compile_with(env)?)
             }
             Lt(left, right) => {
-                let l = left.eval(request)?;
-                let r = right.eval(request)?;
-                Ok(l < r)
+                compiled::Bool::Lt(left.compile_with(env)?, right.compile_with(env)?)
             }
             Gte(left, right) => {
-                let l = left.eval(request)?;
-                let r = right.eval(request)?;
-                Ok(l >= r)
+                compiled::Bool::Gte(left.compile_with(env)?, right.compile_with(env)?)
             }
             Lte(left, right) => {
-                let l = left.eval(request)?;
-                let r = right.eval(request)?;
-                Ok(l <= r)
+                compiled::Bool::Lte(left.compile_with(env)?, right.compile_with(env)?)
             }
-            Not(value) => value.eval(request).map(|v| !v),
-            And(values) => values
-                .iter()
-                .map(|v| v.eval(request))
-                .collect::<Result<Vec<_>, _>>()
-                .map(|it| it.iter().all(|v| *v)),
-            Or(values) => values
-                .iter()
-                .map(|v| v.eval(request))
-                .collect::<Result<Vec<_>, _>>()
-                .map(|it| it.iter().any(|v| *v)),
-        }
+            InNetwork { networks, address } => compiled::Bool::InNetwork {
+                networks: networks
+                    .iter()
+                    .map(|n| network::Network::parse(n))
+                    .collect::<Result<_>>()?,
+                address: address.compile_with(env)?,
+            },
+            VersionEq(left, right) => {
+                compiled::Bool::VersionEq(left.compile_version(env)?, right.compile_version(env)?)
+            }
+            VersionGt(left, right) => {
+                compiled::Bool::VersionGt(left.compile_version(env)?, right.compile_version(env)?)
+            }
+            VersionLt(left, right) => {
+                compiled::Bool::VersionLt(left.compile_version(env)?, right.compile_version(env)?)
+            }
+            VersionGte(left, right) => {
+                compiled::Bool::VersionGte(left.compile_version(env)?, right.compile_version(env)?)
+            }
+            VersionLte(left, right) => {
+                compiled::Bool::VersionLte(left.compile_version(env)?, right.compile_version(env)?)
+            }
+            Not(value) => compiled::Bool::Not(Box::new(value.compile_with(env)?)),
+            Try { value, default } => {
+                compiled::Bool::Try(Box::new(value.compile_with(env)?), *default)
+            }
+            And(values) => compiled::Bool::And(
+                values
+                    .iter()
+                    .map(|v| v.compile_with(env))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            Or(values) => compiled::Bool::Or(
+                values
+                    .iter()
+                    .map(|v| v.compile_with(env))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            Window {
+                from,
+                until,
+                timezone,
+                days,
+            } => compiled::Bool::Window(
+                time::Window::new(from, until, timezone, days)?,
+                env.clock.clone(),
+            ),
+            Segment(name) => compiled::Bool::Segment(
+                env.segments
+                    .as_ref()
+                    .and_then(|segments| segments.get(name))
+                    .cloned()
+                    .ok_or_else(|| anyhow!("Unknown segment '{}'", name))?,
+            ),
+        })
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum StrList {
     /// The identity expression
@@ -150,50 +238,156 @@ pub enum StrList {
     /// Parse a HTTP header with q-values,
     /// i.e. Accept, Accept-Charset, Accept-Language, Accept-Encoding
     HttpQualityValue(Str),
+    /// Decoded segments of the path of a `:path` or URL
+    PathSegments(Box<Str>),
+    /// All decoded values of a query parameter of a `:path` or URL
+    QueryParam { name: String, value: Box<Str> },
+    /// A named list, defined next to the features, see the `lists` module
+    ///
+    /// Named lists can only be searched, with `In` or `AnyIn`.
+    Named(String),
 }
 
 impl StrList {
-    pub fn eval(&self, request: &HashMap<&str, &str>) -> Result<Vec<String>> {
-        use StrList::*;
-        match self {
-            Constant(c) => Ok(c.clone()),
-            Split { separator, value } => {
-                let s = value.eval(request)?;
-                if s == "" {
-                    return Ok(vec![]);
-                }
-
-                Ok(s.split(separator).map(|s| s.to_string()).collect())
-            }
-            Extract { regex, value } => {
-                let value = value.eval(request)?;
-                let regex = Regex::new(regex)?;
+    /// Compiles and evaluates the expression in one go, for tests
+    ///
+    /// Targeting compiles expressions once, when the configuration is loaded.
+    #[cfg(test)]
+    pub(crate) fn eval(&self, request: &Request) -> Result<Vec<String>> {
+        self.compile()?.eval(request)
+    }
 
-                let captures = regex
-                    .captures(&value)
-                    .ok_or_else(|| anyhow!("'{}' does not match '{}'", value, regex))?;
+    /// Compile the expression for repeated evaluation
+    pub fn compile(&self) -> Result<compiled::StrList> {
+        self.compile_with(&Environment::default())
+    }
 
-                Ok(captures
-                    .iter()
-                    .filter_map(|m| m.map(|s| s.as_str().to_string()))
-                    .skip(1)
-                    .collect())
-            }
+    /// Compile the expression in a given environment
+    pub fn compile_with(&self, env: &Environment) -> Result<compiled::StrList> {
+        use StrList::*;
+        Ok(match self {
+            Constant(c) => compiled::StrList::Constant(c.clone()),
+            Split { separator, value } => compiled::StrList::Split {
+                separator: separator.clone(),
+                value: value.compile_with(env)?,
+            },
+            Extract { regex, value } => compiled::StrList::Extract {
+                regex: Regex::new(regex)?,
+                value: Box::new(value.compile_with(env)?),
+            },
             HttpQualityValue(value) => {
-                let s = value.eval(request)?;
-                Ok(parse_q_value(&s).iter().map(|s| s.to_string()).collect())
+                compiled::StrList::HttpQualityValue(value.compile_with(env)?)
+            }
+            PathSegments(value) => {
+                compiled::StrList::PathSegments(Box::new(value.compile_with(env)?))
             }
+            QueryParam { name, value } => compiled::StrList::QueryParam {
+                name: name.clone(),
+                value: Box::new(value.compile_with(env)?),
+            },
+            Named(name) => return Err(only_searchable(name)),
+        })
+    }
+
+    /// Compile the expression for use as a haystack in membership tests
+    fn compile_set(&self, env: &Environment) -> Result<compiled::Set> {
+        Ok(match self {
+            StrList::Constant(c) => compiled::Set::Constant(c.iter().cloned().collect()),
+            StrList::Named(name) => compiled::Set::Named(
+                env.lists
+                    .as_ref()
+                    .and_then(|lists| lists.get(name))
+                    .cloned()
+                    .ok_or_else(|| anyhow!("Unknown list '{}'", name))?,
+            ),
+            list => compiled::Set::Dynamic(list.compile_with(env)?),
+        })
+    }
+}
+
+/// Named lists may be filters, which can't list their values
+fn only_searchable(name: &str) -> anyhow::Error {
+    anyhow!(
+        "Named list '{}' can only be searched, with `in` or `any ... in`",
+        name
+    )
+}
+
+/// A header, or a connection attribute, by name
+///
+/// Selects the first value of a repeated header unless told otherwise.
+/// Serialised as just the name when the first value is selected, otherwise
+/// as `{ "name": "x-forwarded-for", "select": "last" }`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(from = "HeaderRepr", into = "HeaderRepr")]
+pub struct Header {
+    pub name: String,
+    pub select: Select,
+}
+
+impl Header {
+    pub fn new(name: &str, select: Select) -> Self {
+        Self {
+            name: name.to_owned(),
+            select,
+        }
+    }
+}
+
+impl From<&str> for Header {
+    fn from(name: &str) -> Self {
+        Self::new(name, Select::First)
+    }
+}
+
+impl From<String> for Header {
+    fn from(name: String) -> Self {
+        Self {
+            name,
+            select: Select::First,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum HeaderRepr {
+    Name(String),
+    Selected {
+        name: String,
+        #[serde(default)]
+        select: Select,
+    },
+}
+
+impl From<HeaderRepr> for Header {
+    fn from(repr: HeaderRepr) -> Self {
+        match repr {
+            HeaderRepr::Name(name) => name.into(),
+            HeaderRepr::Selected { name, select } => Self { name, select },
+        }
+    }
+}
+
+impl From<Header> for HeaderRepr {
+    fn from(header: Header) -> Self {
+        match header.select {
+            Select::First => HeaderRepr::Name(header.name),
+            select => HeaderRepr::Selected {
+                name: header.name,
+                select,
+            },
         }
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum Str {
     /// The identity expression
     Constant(String),
-    /// Request attribute value
-    Attribute(String),
+    /// Request attribute value, a header or connection attribute
+    Attribute(Header),
     /// Base 64 decode value
     Base64(Box<Str>),
     /// Extract using a regular expression
@@ -224,109 +418,176 @@ pub enum Str {
     First(Box<StrList>),
     /// Last item of a list
     Last(Box<StrList>),
+    /// Address of the client from the `forwarded` or `x-forwarded-for` header
+    ///
+    /// `trusted_proxies` is the number of proxies in front of this one, see
+    /// the `network` module for details.
+    ClientIp {
+        #[serde(default)]
+        trusted_proxies: usize,
+    },
+    /// ISO 3166-1 code of the country of an IP address, e.g. `GB`
+    ///
+    /// Needs a geo database in the configuration, see the `geo` module.
+    Country(Box<Str>),
+    /// ISO 3166-2 code of the region of an IP address, e.g. `ENG`
+    Region(Box<Str>),
+    /// Autonomous system number of an IP address, e.g. `15169`
+    Asn(Box<Str>),
+    /// Claims of a JSON Web Token, as JSON, after verifying the token
+    ///
+    /// Needs JWT keys in the configuration, see the `jwt` module. Claims are
+    /// read with a JSON pointer, e.g. `json(jwt(cookie("token")), "/sub")`.
+    Jwt(Box<Str>),
+    /// Host of an `:authority` or URL
+    Host(Box<Str>),
+    /// Port of an `:authority` or URL
+    Port(Box<Str>),
+    /// Decoded path of a `:path` or URL, without the query
+    Path(Box<Str>),
+    /// A decoded segment of the path of a `:path` or URL, counting from 0
+    PathSegment { index: usize, value: Box<Str> },
+    /// The first decoded value of a query parameter of a `:path` or URL
+    QueryParam { name: String, value: Box<Str> },
 }
 
 impl Str {
-    pub fn eval(&self, request: &HashMap<&str, &str>) -> Result<String> {
+    /// Compiles and evaluates the expression in one go, for tests
+    ///
+    /// Targeting compiles expressions once, when the configuration is loaded.
+    #[cfg(test)]
+    pub(crate) fn eval(&self, request: &Request) -> Result<String> {
+        self.compile()?.eval(request)
+    }
+
+    /// Compile the expression for repeated evaluation
+    pub fn compile(&self) -> Result<compiled::Str> {
+        self.compile_with(&Environment::default())
+    }
+
+    /// Compile the expression in a given environment
+    pub fn compile_with(&self, env: &Environment) -> Result<compiled::Str> {
         use Str::*;
-        match self {
-            Constant(c) => Ok(c.clone()),
-            Attribute(name) => request.get::<str>(name).map_or_else(
-                || Err(anyhow!("Attribute '{}' not found.", name)),
-                |s| Ok((*s).to_string()),
-            ),
-            Base64(value) => {
-                // Transformations like this one mean we can't use string slices
-                // as output, as the return values are not simply substrings of
-                // the request or the config
-                let s = value.eval(request)?;
-                let bytes = base64decode(s)?;
-                let v = String::from_utf8(bytes)?;
-
-                Ok(v)
-            }
-            Extract { regex, value } => {
-                let value = value.eval(request)?;
-                let regex = Regex::new(regex)?;
-
-                regex
-                    .captures(&value)
-                    .and_then(|c| c.get(1).map(|m| m.as_str().to_string()))
-                    .ok_or_else(|| anyhow!("'{}' does not match '{}'", value, regex))
-            }
-            Cookie(name) => get_cookie(request, name).map(|s| s.to_string()),
-            Browser => map_user_agent(request, |ua| ua.name.to_string()),
-            BrowserVersion => map_user_agent(request, |ua| ua.version.to_string()),
-            OperatingSystem => map_user_agent(request, |ua| ua.os.to_string()),
-            JsonPointer { pointer, value } => {
-                let json = value.eval(request)?;
-                json_pointer(pointer, &json, "string", |v| {
-                    v.as_str().map(|s| s.to_string())
-                })
-            }
-            First(list) => {
-                let v = list.eval(request)?;
-                if let Some(s) = v.first() {
-                    Ok(s.clone())
-                } else {
-                    Err(anyhow!("List is empty."))
-                }
-            }
-            Last(list) => {
-                let v = list.eval(request)?;
-                if let Some(s) = v.last() {
-                    Ok(s.clone())
-                } else {
-                    Err(anyhow!("List is empty."))
-                }
-            }
-        }
+        Ok(match self {
+            Constant(c) => compiled::Str::Constant(c.clone()),
+            Attribute(header) => compiled::Str::Attribute(header.clone()),
+            Base64(value) => compiled::Str::Base64(Box::new(value.compile_with(env)?)),
+            Extract { regex, value } => compiled::Str::Extract {
+                regex: Regex::new(regex)?,
+                value: Box::new(value.compile_with(env)?),
+            },
+            Cookie(name) => compiled::Str::Cookie(name.clone()),
+            Browser => compiled::Str::Browser(UserAgent::new()),
+            BrowserVersion => compiled::Str::BrowserVersion(UserAgent::new()),
+            OperatingSystem => compiled::Str::OperatingSystem(UserAgent::new()),
+            JsonPointer { pointer, value } => compiled::Str::JsonPointer {
+                pointer: pointer.clone(),
+                value: Box::new(value.compile_with(env)?),
+            },
+            First(list) => compiled::Str::First(Box::new(list.compile_with(env)?)),
+            Last(list) => compiled::Str::Last(Box::new(list.compile_with(env)?)),
+            ClientIp { trusted_proxies } => compiled::Str::ClientIp(*trusted_proxies),
+            Country(address) => compiled::Str::Country(address.compile_geo(env)?),
+            Region(address) => compiled::Str::Region(address.compile_geo(env)?),
+            Asn(address) => compiled::Str::Asn(address.compile_geo(env)?),
+            Jwt(token) => compiled::Str::Jwt(compiled::JwtClaims {
+                token: Box::new(token.compile_with(env)?),
+                verifier: env.jwt.clone().ok_or(JwtError::NotConfigured)?,
+                clock: env.clock.clone(),
+            }),
+            Host(value) => compiled::Str::Host(Box::new(value.compile_with(env)?)),
+            Port(value) => compiled::Str::Port(Box::new(value.compile_with(env)?)),
+            Path(value) => compiled::Str::Path(Box::new(value.compile_with(env)?)),
+            PathSegment { index, value } => compiled::Str::PathSegment {
+                index: *index,
+                value: Box::new(value.compile_with(env)?),
+            },
+            QueryParam { name, value } => compiled::Str::QueryParam {
+                name: name.clone(),
+                value: Box::new(value.compile_with(env)?),
+            },
+        })
+    }
+
+    /// Compile the expression as the address of a geo lookup
+    fn compile_geo(&self, env: &Environment) -> Result<compiled::GeoLookup> {
+        let geo = env.geo.clone().ok_or(GeoError::NoDatabase)?;
+
+        Ok(compiled::GeoLookup {
+            address: Box::new(self.compile_with(env)?),
+            geo,
+        })
+    }
+
+    /// Compile the expression as an operand of a version comparison, constant
+    /// versions are parsed once
+    fn compile_version(&self, env: &Environment) -> Result<compiled::VersionStr> {
+        Ok(match self {
+            Str::Constant(text) => compiled::VersionStr::Constant {
+                text: text.clone(),
+                version: text.parse()?,
+            },
+            value => compiled::VersionStr::Dynamic(value.compile_with(env)?),
+        })
     }
 }
 
-fn map_user_agent<'a, V, F>(request: &HashMap<&str, &'a str>, map: F) -> Result<V>
-where
-    F: FnOnce(WootheeResult<'a>) -> V,
-    V: 'a,
-{
-    if let Some(ua) = request.get("user-agent") {
-        if let Some(ua) = UserAgentParser::new().parse(ua) {
-            Ok(map(ua))
-        } else {
-            Err(anyhow!("Malformed User-Agent string: {}", ua))
-        }
-    } else {
-        Err(anyhow!("User-Agent header not found"))
+/// The request lacks a header, attribute or cookie an expression reads
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotFound(pub String);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
     }
 }
 
-fn get_cookie<'a>(request: &HashMap<&str, &'a str>, name: &str) -> Result<&'a str> {
-    let cookie_header = request
-        .get("cookie")
-        .ok_or_else(|| anyhow!("No cookies found in request"))?;
+impl std::error::Error for NotFound {}
 
-    cookie_header
-        .split("; ")
-        .map(|pair| {
-            let items: Vec<_> = pair.split('=').collect();
+impl NotFound {
+    /// Whether an evaluation error is only that the request lacks a value
+    pub fn is(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<NotFound>().is_some()
+    }
+}
 
-            (items[0], items[1])
+pub(crate) fn not_found(message: String) -> anyhow::Error {
+    NotFound(message).into()
+}
+
+pub(crate) fn get_cookie<'r>(request: &'r Request, name: &str) -> Result<&'r str> {
+    let mut headers = request.header_values("cookie").peekable();
+    if headers.peek().is_none() {
+        return Err(not_found("No cookies found in request".to_owned()));
+    }
+
+    // HTTP/2 clients may send each cookie in a separate header
+    headers
+        .flat_map(|header| header.split(';'))
+        .find_map(|pair| match pair.trim().split_once('=') {
+            Some((n, value)) if n == name => Some(value),
+            _ => None,
         })
-        .collect::<HashMap<_, _>>()
-        .get(name)
-        .copied()
-        .ok_or_else(|| anyhow!("Cookie {} not found", name))
+        .ok_or_else(|| not_found(format!("Cookie {} not found", name)))
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum Num {
     /// The identity expression
     Constant(f64),
     /// Request attribute value
     Attribute(String),
-    /// Randomly assigns a uniformly distributed stable number between 0.0 and 100.0
+    /// Randomly assigns a uniformly distributed stable number between 0.00 and 99.99
+    ///
+    /// The number is derived from the value and a salt, which is the name of
+    /// the feature, so the same user lands in different buckets for different
+    /// features. See the `bucket` module for the exact, versioned scheme.
     Rank(Str),
+    /// Same as `Rank`, with an explicit salt instead of the feature name
+    ///
+    /// Features using the same salt will enable for the same users first.
+    SaltedRank { value: Str, salt: String },
     /// Looks up a number value by a JSON Pointer
     ///
     /// JSON Pointer defines a string syntax for identifying a specific value
@@ -339,28 +600,72 @@ pub enum Num {
     ///
     /// For more information read [RFC6901](https://tools.ietf.org/html/rfc6901).
     JsonPointer { pointer: String, value: Str },
+    /// The current time, in seconds since the Unix epoch
+    Now,
+    /// Parses an RFC 3339 timestamp into seconds since the Unix epoch
+    ///
+    /// With a time zone, the timestamp can be a local time without an offset,
+    /// e.g. `2026-11-27T00:00` in `Europe/London`.
+    Time {
+        value: Str,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timezone: Option<String>,
+    },
+    /// A duration like `1h30m` in seconds, units are `w`, `d`, `h`, `m` and `s`
+    Duration(String),
+    /// Seconds elapsed since a time, e.g. `since(time(cookie("signed-up")))`
+    Since(Box<Num>),
 }
 
 impl Num {
-    fn eval(&self, request: &HashMap<&str, &str>) -> Result<f64> {
+    /// Compiles and evaluates the expression in one go, for tests
+    ///
+    /// Targeting compiles expressions once, when the configuration is loaded.
+    #[cfg(test)]
+    pub(crate) fn eval(&self, request: &Request) -> Result<f64> {
+        self.compile()?.eval(request)
+    }
+
+    /// Compile the expression for repeated evaluation
+    pub fn compile(&self) -> Result<compiled::Num> {
+        self.compile_with(&Environment::default())
+    }
+
+    /// Compile the expression in a given environment
+    pub fn compile_with(&self, env: &Environment) -> Result<compiled::Num> {
         use Num::*;
-        match &self {
-            Constant(c) => Ok(*c),
-            Attribute(name) => {
-                if let Some(s) = request.get::<str>(&name) {
-                    return Ok((*s).parse::<f64>()?);
+        Ok(match self {
+            Constant(c) => compiled::Num::Constant(*c),
+            Attribute(name) => compiled::Num::Attribute(name.clone()),
+            Rank(value) => compiled::Num::Rank {
+                value: value.compile_with(env)?,
+                salt: env.salt.to_owned(),
+            },
+            SaltedRank { value, salt } => compiled::Num::Rank {
+                value: value.compile_with(env)?,
+                salt: salt.clone(),
+            },
+            JsonPointer { pointer, value } => compiled::Num::JsonPointer {
+                pointer: pointer.clone(),
+                value: value.compile_with(env)?,
+            },
+            Now => compiled::Num::Now(env.clock.clone()),
+            Time { value, timezone } => {
+                let timezone = timezone.as_deref().map(time::parse_timezone).transpose()?;
+                if let Str::Constant(text) = value {
+                    time::parse_time(text, timezone.as_ref())?;
+                }
+
+                compiled::Num::Time {
+                    value: value.compile_with(env)?,
+                    timezone,
                 }
-                Err(anyhow!("Attribute '{}' not found.", name))
             }
-            Rank(str_exp) => str_exp.eval(request).map(|s| {
-                let mut hasher = DefaultHasher::new();
-                s.hash(&mut hasher);
-                (hasher.finish() % 1000) as f64 / 10.0
-            }),
-            JsonPointer { pointer, value } => value
-                .eval(request)
-                .and_then(|json| json_pointer(pointer, &json, "number", |v| v.as_f64())),
-        }
+            Duration(text) => compiled::Num::Constant(time::parse_duration(text)?),
+            Since(value) => {
+                compiled::Num::Since(Box::new(value.compile_with(env)?), env.clock.clone())
+            }
+        })
     }
 }
 
@@ -419,7 +724,8 @@ mod test {
     #[test_case(Num::Attribute("number".into()), Ok(1.4))]
     #[test_case(Num::Attribute("nope".into()), Err(anyhow!("Attribute 'nope' not found.")))]
     #[test_case(Num::Attribute("not-number".into()), Err(anyhow!("invalid float literal")))]
-    #[test_case(Num::Rank(Str::Attribute("not-number".into())), Ok(40.9))]
+    #[test_case(Num::Rank(Str::Attribute("not-number".into())), Ok(31.35))]
+    #[test_case(Num::SaltedRank { value: Str::Attribute("not-number".into()), salt: "beta".into() }, Ok(47.09))]
     #[test_case(Num::JsonPointer { pointer: "/foo/0".into(), value: Str::Constant(r#"{"foo":[0.3]}"#.into()) }, Ok(0.3))]
     #[test_case(Num::JsonPointer { pointer: "/bar/0".into(), value: Str::Constant(r#"{"foo":[0.3]}"#.into()) }, Err(anyhow!("Cannot find a number at pointer /bar/0 in JSON {\"foo\":[0.3]}")))]
     fn evaluate_numerical_expressions(expr: Num, expected: Result<f64>) {
@@ -447,6 +753,13 @@ mod test {
     #[test_case(Str::OperatingSystem, Ok("Mac OSX".into()))]
     #[test_case(Str::JsonPointer { pointer: "/foo/0".into(), value: Box::new(Str::Constant(r#"{"foo":["bar"]}"#.into())) }, Ok("bar".into()))]
     #[test_case(Str::JsonPointer { pointer: "/foo/0".into(), value: Box::new(Str::Constant(r#"{"foo":[0.3]}"#.into())) }, Err(anyhow!("Cannot find a string at pointer /foo/0 in JSON {\"foo\":[0.3]}")))]
+    #[test_case(Str::Host(Box::new(Str::Constant("[::1]:8080".into()))), Ok("::1".into()))]
+    #[test_case(Str::Port(Box::new(Str::Constant("example.com:8080".into()))), Ok("8080".into()))]
+    #[test_case(Str::Path(Box::new(Str::Constant("/a%20b/c?d=e".into()))), Ok("/a b/c".into()))]
+    #[test_case(Str::PathSegment { index: 1, value: Box::new(Str::Constant("/shop/caf%C3%A9".into())) }, Ok("café".into()))]
+    #[test_case(Str::PathSegment { index: 2, value: Box::new(Str::Constant("/a/b".into())) }, Err(anyhow!("No path segment 2 in '/a/b'")))]
+    #[test_case(Str::QueryParam { name: "f".into(), value: Box::new(Str::Constant("/?f=a+b&f=c".into())) }, Ok("a b".into()))]
+    #[test_case(Str::QueryParam { name: "g".into(), value: Box::new(Str::Constant("/?f=a".into())) }, Err(anyhow!("Query parameter 'g' not found in '/?f=a'")))]
     fn evaluate_string_expressions(expr: Str, expected: Result<String>) {
         let request = [
             ("hello", "world"),
@@ -468,16 +781,19 @@ mod test {
     #[test_case(StrList::Split { separator: " ".into(), value: Str::Constant("a b".into())}, Ok(vec!["a".into(), "b".into()]))]
     #[test_case(StrList::Extract { regex: r#"http://f-([a-z]+).[a-z-]+.com/.+\?features=([a-z,]+,?)+"#.into(), value: Box::new(Str::Attribute(":authority".into())) }, Ok(vec!["one".into(), "two,three,four".into()]) )]
     #[test_case(StrList::HttpQualityValue(Str::Attribute("accept".into())), Ok(vec!["text/html".into(), "text/plain".into(), "text/*".into(), "*/*".into()]))]
+    #[test_case(StrList::PathSegments(Box::new(Str::Attribute(":authority".into()))), Ok(vec!["path".into(), "x".into()]))]
+    #[test_case(StrList::QueryParam { name: "features".into(), value: Box::new(Str::Attribute(":authority".into())) }, Ok(vec!["two,three,four".into()]))]
+    #[test_case(StrList::QueryParam { name: "nope".into(), value: Box::new(Str::Attribute(":authority".into())) }, Ok(vec![]))]
     fn evaluate_string_list_expressions(expr: StrList, expected: Result<Vec<String>>) {
-        let mut request = HashMap::new();
-        request.insert(
-            "accept",
-            "*/*;q=0.3, text/plain;q=0.7, text/html, text/*;q=0.5",
-        );
-        request.insert(
-            ":authority",
-            "http://f-one.example.com/path/x?features=two,three,four",
-        );
+        let request = Request::new()
+            .with_header(
+                "accept",
+                "*/*;q=0.3, text/plain;q=0.7, text/html, text/*;q=0.5",
+            )
+            .with_header(
+                ":authority",
+                "http://f-one.example.com/path/x?features=two,three,four",
+            );
         let actual = expr.eval(&request);
         assert_eq!(format!("{:?}", actual), format!("{:?}", expected));
     }
@@ -511,15 +827,101 @@ mod test {
     #[test_case(Bool::Lte(Num::Constant(1.3), Num::Constant(1.2)), Ok(false))]
     #[test_case(Bool::Lte(Num::Constant(1.2), Num::Constant(1.3)), Ok(true))]
     #[test_case(Bool::Lte(Num::Constant(1.3), Num::Constant(1.3)), Ok(true))]
+    #[test_case(Bool::InNetwork { networks: vec!["10.0.0.0/8".into(), "2001:db8::/32".into()], address: Str::Constant("2001:db8::1".into()) }, Ok(true))]
+    #[test_case(Bool::InNetwork { networks: vec!["10.0.0.0/8".into()], address: Str::Constant("192.168.0.1".into()) }, Ok(false))]
+    #[test_case(Bool::InNetwork { networks: vec!["10.0.0.0/8".into()], address: Str::Attribute("hello".into()) }, Err(anyhow!("'world' is not an IP address")))]
+    #[test_case(Bool::VersionGt(Str::Constant("4.10.2".into()), Str::Constant("4.9".into())), Ok(true))]
+    #[test_case(Bool::VersionEq(Str::Constant("4.9".into()), Str::Constant("v4.9.0".into())), Ok(true))]
+    #[test_case(Bool::VersionLt(Str::Constant("2.0.0-beta.2".into()), Str::Constant("2.0.0".into())), Ok(true))]
+    #[test_case(Bool::VersionGte(Str::Attribute("hello".into()), Str::Constant("1.0".into())), Err(anyhow!("'world' is not a version number")))]
+    #[test_case(Bool::VersionLte(Str::Constant("1.0".into()), Str::Attribute("nope".into())), Err(anyhow!("Attribute 'nope' not found.")))]
     #[test_case(Bool::Not(Box::new(Bool::Constant(true))), Ok(false))]
     #[test_case(Bool::And(vec![Bool::Constant(true), Bool::Constant(true)]), Ok(true))]
     #[test_case(Bool::And(vec![Bool::Constant(true), Bool::Constant(false)]), Ok(false))]
     #[test_case(Bool::Or(vec![Bool::Constant(true), Bool::Constant(false)]), Ok(true))]
     #[test_case(Bool::Or(vec![Bool::Constant(false), Bool::Constant(false)]), Ok(false))]
+    #[test_case(Bool::Or(vec![Bool::Constant(true), Bool::Attribute("missing".into())]), Ok(true); "or short-circuits")]
+    #[test_case(Bool::Or(vec![Bool::Attribute("missing".into()), Bool::Constant(true)]), Err(anyhow!("Attribute 'missing' not found.")); "or fails first")]
+    #[test_case(Bool::And(vec![Bool::Constant(false), Bool::Attribute("missing".into())]), Ok(false); "and short-circuits")]
+    #[test_case(Bool::And(vec![Bool::Attribute("missing".into()), Bool::Constant(false)]), Err(anyhow!("Attribute 'missing' not found.")); "and fails first")]
+    #[test_case(Bool::Try { value: Box::new(Bool::Attribute("missing".into())), default: false }, Ok(false); "try default")]
+    #[test_case(Bool::Try { value: Box::new(Bool::Attribute("hello".into())), default: false }, Ok(true); "try value")]
     fn evaluate_boolean_expressions(expr: Bool, expected: Result<bool>) {
         let request = [("hello", "world")].iter().cloned().collect();
 
         let actual = expr.eval(&request);
         assert_eq!(format!("{:?}", actual), format!("{:?}", expected));
     }
+
+    fn geo_env() -> Environment<'static> {
+        let config = geo::Config {
+            databases: vec![crate::features::data::Source::Path(format!(
+                "{}/test-data/geo.mmdb",
+                env!("CARGO_MANIFEST_DIR")
+            ))],
+        };
+
+        Environment {
+            geo: Some(std::sync::Arc::new(
+                config.load(&crate::features::data::DefaultLoader).unwrap(),
+            )),
+            ..Environment::default()
+        }
+    }
+
+    #[test_case(Str::Country(Box::new(Str::Attribute("client".into()))), Ok("GB".into()))]
+    #[test_case(Str::Region(Box::new(Str::Attribute("client".into()))), Ok("ENG".into()))]
+    #[test_case(Str::Asn(Box::new(Str::Constant("89.160.20.112".into()))), Ok("29518".into()))]
+    #[test_case(Str::Asn(Box::new(Str::Attribute("client".into()))), Err(GeoError::NotFound { field: geo::Field::Asn, address: "81.2.69.160".parse().unwrap() }))]
+    fn evaluates_geo_lookups(expr: Str, expected: Result<String, GeoError>) {
+        let request = [("client", "81.2.69.160:443")].iter().cloned().collect();
+
+        let actual = expr.compile_with(&geo_env()).unwrap().eval(&request);
+        assert_eq!(
+            actual.map_err(|e| e.downcast::<GeoError>().unwrap()),
+            expected
+        );
+    }
+
+    #[test]
+    fn geo_lookups_need_a_database() {
+        let expr = Str::Country(Box::new(Str::Constant("81.2.69.160".into())));
+
+        let error = expr.compile().unwrap_err();
+        assert_eq!(error.downcast_ref(), Some(&GeoError::NoDatabase));
+    }
+
+    #[test]
+    fn selects_values_of_repeated_headers() {
+        let request = Request::new()
+            .with_header("X-Forwarded-For", "198.51.100.1")
+            .with_header("x-forwarded-for", "203.0.113.7");
+        let select = |select| {
+            Str::Attribute(Header::new("x-forwarded-for", select))
+                .eval(&request)
+                .unwrap()
+        };
+
+        assert_eq!(select(Select::First), "198.51.100.1");
+        assert_eq!(select(Select::Last), "203.0.113.7");
+        assert_eq!(select(Select::Joined), "198.51.100.1, 203.0.113.7");
+    }
+
+    #[test]
+    fn serialises_header_selection() {
+        let first: Str = serde_json::from_str(r#"{"attribute":"via"}"#).unwrap();
+        let last: Str =
+            serde_json::from_str(r#"{"attribute":{"name":"via","select":"last"}}"#).unwrap();
+
+        assert_eq!(first, Str::Attribute(Header::new("via", Select::First)));
+        assert_eq!(last, Str::Attribute(Header::new("via", Select::Last)));
+        assert_eq!(
+            serde_json::to_string(&last).unwrap(),
+            r#"{"attribute":{"name":"via","select":"last"}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&first).unwrap(),
+            r#"{"attribute":"via"}"#
+        );
+    }
 }
@@ -0,0 +1,225 @@
+//! Client addresses and network ranges
+//!
+//! The address of the client is taken from the `forwarded` header defined by
+//! [RFC7239](https://tools.ietf.org/html/rfc7239), or from `x-forwarded-for`
+//! when there is none. Every proxy appends the address it received the request
+//! from, so only the entries added by trusted proxies can be relied on. The
+//! client is the entry added by the outermost trusted proxy, counting from the
+//! right, as Envoy does with `xff_num_trusted_hops`.
+
+use crate::request::{Request, SOURCE_ADDRESS};
+use anyhow::{anyhow, bail, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// A range of IPv4 or IPv6 addresses in CIDR notation, e.g. `10.0.0.0/8`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid network '{}', expected e.g. '10.0.0.0/8'", text);
+
+        let mut parts = text.splitn(2, '/');
+        let address: IpAddr = parts
+            .next()
+            .and_then(|a| a.parse().ok())
+            .ok_or_else(invalid)?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(p) => p.parse().ok().filter(|p| *p <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+
+        Ok(Self { address, prefix })
+    }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, normalise(*address)) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => same_prefix(
+                u32::from(network).into(),
+                u32::from(address).into(),
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                same_prefix(network.into(), address.into(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn same_prefix(network: u128, address: u128, bits: u8, prefix: u8) -> bool {
+    let host_bits = u32::from(bits - prefix);
+
+    network.checked_shr(host_bits).unwrap_or(0) == address.checked_shr(host_bits).unwrap_or(0)
+}
+
+/// Treats IPv4 addresses mapped to IPv6, like `::ffff:10.0.0.1`, as IPv4
+fn normalise(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => IpAddr::V4(Ipv4Addr::new(
+                (high >> 8) as u8,
+                high as u8,
+                (low >> 8) as u8,
+                low as u8,
+            )),
+            _ => address,
+        },
+        v4 => v4,
+    }
+}
+
+/// Parses an address, allowing a port and brackets around IPv6 addresses
+pub fn parse_address(text: &str) -> Result<IpAddr> {
+    let text = text.trim();
+    let invalid = || anyhow!("'{}' is not an IP address", text);
+
+    if let Ok(address) = text.parse() {
+        return Ok(address);
+    }
+
+    if let Some(rest) = text.strip_prefix('[') {
+        // [2001:db8::1] or [2001:db8::1]:8080
+        let end = rest.find(']').ok_or_else(invalid)?;
+        return rest[..end]
+            .parse::<Ipv6Addr>()
+            .map(IpAddr::V6)
+            .map_err(|_| invalid());
+    }
+
+    // 192.0.2.1:8080
+    match text.rsplit_once(':') {
+        Some((host, _port)) => host
+            .parse::<Ipv4Addr>()
+            .map(IpAddr::V4)
+            .map_err(|_| invalid()),
+        None => Err(invalid()),
+    }
+}
+
+/// The client address from the forwarding headers of the request
+///
+/// `trusted_proxies` is the number of proxies in front of this one, whose
+/// entries are skipped. Without forwarding headers, the client is the peer of
+/// the connection when no proxies are expected in front.
+pub fn client_address(request: &Request, trusted_proxies: usize) -> Result<IpAddr> {
+    // repeated headers are a single list split across lines
+    let hops: Vec<String> = if let Some(forwarded) = request.joined_header("forwarded") {
+        // elements without a `for` parameter still count as hops
+        forwarded
+            .split(',')
+            .map(|element| forwarded_for(element).unwrap_or("unknown").to_owned())
+            .collect()
+    } else if let Some(forwarded_for) = request.joined_header("x-forwarded-for") {
+        forwarded_for
+            .split(',')
+            .map(|hop| hop.trim().to_owned())
+            .collect()
+    } else if let (Some(peer), 0) = (request.attribute(SOURCE_ADDRESS), trusted_proxies) {
+        return parse_address(peer);
+    } else {
+        bail!("No forwarded or x-forwarded-for header found");
+    };
+
+    let hop = hops
+        .len()
+        .checked_sub(trusted_proxies + 1)
+        .map(|i| &hops[i])
+        .ok_or_else(|| {
+            anyhow!(
+                "Expected more than {} forwarding hops, found {}",
+                trusted_proxies,
+                hops.len()
+            )
+        })?;
+
+    parse_address(hop)
+}
+
+/// The value of the `for` parameter of a `forwarded` header element
+fn forwarded_for(element: &str) -> Option<&str> {
+    element.split(';').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        let name = parts.next()?.trim();
+        let value = parts.next()?.trim();
+
+        if name.eq_ignore_ascii_case("for") {
+            Some(value.trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("10.0.0.0/8", "10.1.2.3", true)]
+    #[test_case("10.0.0.0/8", "11.0.0.1", false)]
+    #[test_case("192.168.1.0/24", "192.168.1.255", true)]
+    #[test_case("192.168.1.7", "192.168.1.7", true ; "single address")]
+    #[test_case("0.0.0.0/0", "8.8.8.8", true ; "everything")]
+    #[test_case("2001:db8::/32", "2001:db8:cafe::17", true)]
+    #[test_case("2001:db8::/32", "2001:db9::1", false)]
+    #[test_case("10.0.0.0/8", "::ffff:10.0.0.1", true ; "mapped")]
+    #[test_case("10.0.0.0/8", "::1", false ; "different family")]
+    fn matches_networks(network: &str, address: &str, expected: bool) {
+        let network = Network::parse(network).unwrap();
+
+        assert_eq!(network.contains(&address.parse().unwrap()), expected);
+    }
+
+    #[test_case("10.0.0.0/33")]
+    #[test_case("10.0.0/8")]
+    #[test_case("office")]
+    fn rejects_invalid_networks(network: &str) {
+        assert!(Network::parse(network).is_err());
+    }
+
+    #[test_case("x-forwarded-for", "203.0.113.7", 0, Ok("203.0.113.7") ; "single hop")]
+    #[test_case("x-forwarded-for", "1.1.1.1, 203.0.113.7, 10.0.0.2", 1, Ok("203.0.113.7") ; "skips trusted")]
+    #[test_case("x-forwarded-for", "203.0.113.7:5555, 10.0.0.2", 1, Ok("203.0.113.7") ; "with port")]
+    #[test_case("x-forwarded-for", "10.0.0.2", 1, Err("Expected more than 1 forwarding hops, found 1") ; "too few hops")]
+    #[test_case("forwarded", r#"for=192.0.2.60;proto=http;by=203.0.113.43"#, 0, Ok("192.0.2.60") ; "forwarded")]
+    #[test_case("forwarded", r#"for="[2001:db8:cafe::17]:4711", For=10.0.0.2"#, 1, Ok("2001:db8:cafe::17") ; "forwarded ipv6")]
+    #[test_case("forwarded", "for=unknown", 0, Err("'unknown' is not an IP address") ; "forwarded unknown")]
+    #[test_case("x-nope", "", 0, Ok("10.0.0.3") ; "peer")]
+    #[test_case("x-nope", "", 1, Err("No forwarded or x-forwarded-for header found") ; "peer of a proxy")]
+    fn finds_client_address(
+        header: &str,
+        value: &str,
+        trusted: usize,
+        expected: Result<&str, &str>,
+    ) {
+        let request = Request::new()
+            .with_header(header, value)
+            .with_attribute(SOURCE_ADDRESS, "10.0.0.3:51234");
+
+        let actual = client_address(&request, trusted).map_err(|e| e.to_string());
+        assert_eq!(
+            actual,
+            expected
+                .map(|a| a.parse().unwrap())
+                .map_err(|e| e.to_string())
+        );
+    }
+
+    #[test]
+    fn joins_repeated_forwarding_headers() {
+        let request = Request::new()
+            .with_header("x-forwarded-for", "198.51.100.1, 203.0.113.7")
+            .with_header("x-forwarded-for", "10.0.0.2");
+
+        assert_eq!(
+            client_address(&request, 1).unwrap(),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+    }
+}
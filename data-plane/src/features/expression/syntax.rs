@@ -0,0 +1,1423 @@
+//! A compact text syntax for expressions
+//!
+//! Every expression can be written as text, which maps one-to-one onto the
+//! expression enums. Expressions are printed in this syntax by their `Display`
+//! implementation and parsed from it with `str::parse`.
+//!
+//! ```text
+//! "en-GB" in q_values(header("accept-language")) and rank(cookie("uid")) < 10
+//! ```
+//!
+//! Boolean expressions
+//!
+//! | Syntax                         | Expression          |
+//! |--------------------------------|---------------------|
+//! | `true`, `false`                | `Bool::Constant`    |
+//! | `exists(header("name"))`       | `Bool::Attribute`   |
+//! | `str in list`                  | `Bool::In`          |
+//! | `any list in list`             | `Bool::AnyIn`       |
+//! | `all list in list`             | `Bool::AllIn`       |
+//! | `bool(json(str, "/pointer"))`  | `Bool::JsonPointer` |
+//! | `str matches "regex"`          | `Bool::Matches`     |
+//! | `str == str`                   | `Bool::StrEq`       |
+//! | `num == num`                   | `Bool::NumEq`       |
+//! | `num > num`, `<`, `>=`, `<=`   | `Bool::Gt` etc.     |
+//! | `version(str) >= version(str)`, `==` etc. | `Bool::VersionGte` etc. |
+//! | `not bool`                     | `Bool::Not`         |
+//! | `bool and bool`, `and(bool)`   | `Bool::And`         |
+//! | `bool or bool`, `or(bool)`     | `Bool::Or`          |
+//! | `try(bool, false)`             | `Bool::Try`         |
+//! | `in_network(str, ["10.0.0.0/8"])` | `Bool::InNetwork` |
+//! | `window("09:00", "17:00", "Europe/London")` | `Bool::Window` |
+//! | `window("22:00", "02:00", "UTC", ["fri", "sat"])` | `Bool::Window` |
+//! | `in_segment("staff")`          | `Bool::Segment`     |
+//!
+//! String expressions
+//!
+//! | Syntax                      | Expression             |
+//! |-----------------------------|------------------------|
+//! | `"text"`                    | `Str::Constant`        |
+//! | `header("name")`            | `Str::Attribute`       |
+//! | `header("name", "last")`, `"joined"` | `Str::Attribute` |
+//! | `base64(str)`               | `Str::Base64`          |
+//! | `extract(str, "regex")`     | `Str::Extract`         |
+//! | `cookie("name")`            | `Str::Cookie`          |
+//! | `browser()`                 | `Str::Browser`         |
+//! | `browser_version()`         | `Str::BrowserVersion`  |
+//! | `operating_system()`        | `Str::OperatingSystem` |
+//! | `json(str, "/pointer")`     | `Str::JsonPointer`     |
+//! | `first(list)`, `last(list)` | `Str::First`, `Last`   |
+//! | `client_ip()`, `client_ip(1)` | `Str::ClientIp`      |
+//! | `country(str)`              | `Str::Country`         |
+//! | `region(str)`               | `Str::Region`          |
+//! | `asn(str)`                  | `Str::Asn`             |
+//! | `jwt(str)`                  | `Str::Jwt`             |
+//! | `host(str)`, `port(str)`    | `Str::Host`, `Port`    |
+//! | `path(str)`                 | `Str::Path`            |
+//! | `segment(str, 0)`           | `Str::PathSegment`     |
+//! | `query(str, "name")`        | `Str::QueryParam`      |
+//!
+//! String list expressions
+//!
+//! | Syntax                   | Expression                  |
+//! |--------------------------|-----------------------------|
+//! | `["a", "b"]`             | `StrList::Constant`         |
+//! | `split(str, " ")`        | `StrList::Split`            |
+//! | `captures(str, "regex")` | `StrList::Extract`          |
+//! | `q_values(str)`          | `StrList::HttpQualityValue` |
+//! | `segments(str)`          | `StrList::PathSegments`     |
+//! | `query_all(str, "name")` | `StrList::QueryParam`       |
+//! | `list("name")`           | `StrList::Named`            |
+//!
+//! Number expressions
+//!
+//! | Syntax                         | Expression         |
+//! |--------------------------------|--------------------|
+//! | `1.5`                          | `Num::Constant`    |
+//! | `number(header("name"))`       | `Num::Attribute`   |
+//! | `rank(str)`                    | `Num::Rank`        |
+//! | `rank(str, "salt")`            | `Num::SaltedRank`  |
+//! | `number(json(str, "/pointer"))`| `Num::JsonPointer` |
+//! | `now()`                        | `Num::Now`         |
+//! | `time(str)`, `time(str, "Europe/London")` | `Num::Time` |
+//! | `duration("1h30m")`            | `Num::Duration`    |
+//! | `since(num)`                   | `Num::Since`       |
+//!
+//! `and` binds tighter than `or`, `not` applies to a single comparison and
+//! parentheses can be used for grouping.
+
+use super::{Bool, Header, Num, Str, StrList};
+use crate::request::Select;
+use serde::{
+    de::{self, IntoDeserializer, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use std::{error, fmt, marker::PhantomData, str::FromStr};
+
+/// Error parsing an expression, with the position where it occurred
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl error::Error for ParseError {}
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+// Printing
+
+impl fmt::Display for Bool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Bool::*;
+        match self {
+            Constant(c) => write!(f, "{}", c),
+            Attribute(name) => write!(f, "exists(header({}))", Quoted(name)),
+            In { list, value } => write!(f, "{} in {}", value, list),
+            AnyIn { list, values } => write!(f, "any {} in {}", values, list),
+            AllIn { list, values } => write!(f, "all {} in {}", values, list),
+            JsonPointer { pointer, value } => {
+                write!(f, "bool(json({}, {}))", value, Quoted(pointer))
+            }
+            Matches(regex, value) => write!(f, "{} matches {}", value, Quoted(regex)),
+            StrEq(left, right) => write!(f, "{} == {}", left, right),
+            NumEq(left, right) => write!(f, "{} == {}", left, right),
+            Gt(left, right) => write!(f, "{} > {}", left, right),
+            Lt(left, right) => write!(f, "{} < {}", left, right),
+            Gte(left, right) => write!(f, "{} >= {}", left, right),
+            Lte(left, right) => write!(f, "{} <= {}", left, right),
+            InNetwork { networks, address } => write!(
+                f,
+                "in_network({}, {})",
+                address,
+                StrList::Constant(networks.clone())
+            ),
+            VersionEq(left, right) => write!(f, "version({}) == version({})", left, right),
+            VersionGt(left, right) => write!(f, "version({}) > version({})", left, right),
+            VersionLt(left, right) => write!(f, "version({}) < version({})", left, right),
+            VersionGte(left, right) => write!(f, "version({}) >= version({})", left, right),
+            VersionLte(left, right) => write!(f, "version({}) <= version({})", left, right),
+            Not(value) => match **value {
+                And(ref vs) | Or(ref vs) if vs.len() > 1 => write!(f, "not ({})", value),
+                _ => write!(f, "not {}", value),
+            },
+            And(values) => write_infix(f, "and", values, |v| matches!(v, And(_) | Or(_))),
+            Or(values) => write_infix(f, "or", values, |v| matches!(v, Or(_))),
+            Try { value, default } => write!(f, "try({}, {})", value, default),
+            Window {
+                from,
+                until,
+                timezone,
+                days,
+            } => {
+                write!(
+                    f,
+                    "window({}, {}, {}",
+                    Quoted(from),
+                    Quoted(until),
+                    Quoted(timezone)
+                )?;
+                if !days.is_empty() {
+                    write!(f, ", {}", StrList::Constant(days.clone()))?;
+                }
+                write!(f, ")")
+            }
+            Segment(name) => write!(f, "in_segment({})", Quoted(name)),
+        }
+    }
+}
+
+/// Writes a logical operator in infix form, falling back to a function call
+/// form when there are fewer than two operands
+fn write_infix<F>(
+    f: &mut fmt::Formatter<'_>,
+    op: &str,
+    values: &[Bool],
+    needs_parens: F,
+) -> fmt::Result
+where
+    F: Fn(&Bool) -> bool,
+{
+    if values.len() < 2 {
+        write!(f, "{}(", op)?;
+        write_separated(f, values)?;
+        return write!(f, ")");
+    }
+
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            write!(f, " {} ", op)?;
+        }
+
+        match value {
+            Bool::And(vs) | Bool::Or(vs) if vs.len() > 1 && needs_parens(value) => {
+                write!(f, "({})", value)?
+            }
+            _ => write!(f, "{}", value)?,
+        }
+    }
+
+    Ok(())
+}
+
+fn write_separated<T: fmt::Display>(f: &mut fmt::Formatter<'_>, values: &[T]) -> fmt::Result {
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", value)?;
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for StrList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use StrList::*;
+        match self {
+            Constant(items) => {
+                write!(f, "[")?;
+                write_separated(
+                    f,
+                    &items.iter().map(|s| Quoted(s.as_str())).collect::<Vec<_>>(),
+                )?;
+                write!(f, "]")
+            }
+            Split { separator, value } => write!(f, "split({}, {})", value, Quoted(separator)),
+            Extract { regex, value } => write!(f, "captures({}, {})", value, Quoted(regex)),
+            HttpQualityValue(value) => write!(f, "q_values({})", value),
+            PathSegments(value) => write!(f, "segments({})", value),
+            QueryParam { name, value } => write!(f, "query_all({}, {})", value, Quoted(name)),
+            Named(name) => write!(f, "list({})", Quoted(name)),
+        }
+    }
+}
+
+impl fmt::Display for Str {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Str::*;
+        match self {
+            Constant(c) => write!(f, "{}", Quoted(c)),
+            Attribute(Header {
+                name,
+                select: Select::First,
+            }) => write!(f, "header({})", Quoted(name)),
+            Attribute(Header { name, select }) => {
+                let select = match select {
+                    Select::First => "first",
+                    Select::Last => "last",
+                    Select::Joined => "joined",
+                };
+                write!(f, "header({}, {})", Quoted(name), Quoted(select))
+            }
+            Base64(value) => write!(f, "base64({})", value),
+            Extract { regex, value } => write!(f, "extract({}, {})", value, Quoted(regex)),
+            Cookie(name) => write!(f, "cookie({})", Quoted(name)),
+            Browser => write!(f, "browser()"),
+            BrowserVersion => write!(f, "browser_version()"),
+            OperatingSystem => write!(f, "operating_system()"),
+            JsonPointer { pointer, value } => write!(f, "json({}, {})", value, Quoted(pointer)),
+            First(list) => write!(f, "first({})", list),
+            Last(list) => write!(f, "last({})", list),
+            ClientIp { trusted_proxies: 0 } => write!(f, "client_ip()"),
+            ClientIp { trusted_proxies } => write!(f, "client_ip({})", trusted_proxies),
+            Country(address) => write!(f, "country({})", address),
+            Region(address) => write!(f, "region({})", address),
+            Asn(address) => write!(f, "asn({})", address),
+            Jwt(token) => write!(f, "jwt({})", token),
+            Host(value) => write!(f, "host({})", value),
+            Port(value) => write!(f, "port({})", value),
+            Path(value) => write!(f, "path({})", value),
+            PathSegment { index, value } => write!(f, "segment({}, {})", value, index),
+            QueryParam { name, value } => write!(f, "query({}, {})", value, Quoted(name)),
+        }
+    }
+}
+
+impl fmt::Display for Num {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Num::*;
+        match self {
+            Constant(c) => write!(f, "{}", c),
+            Attribute(name) => write!(f, "number(header({}))", Quoted(name)),
+            Rank(value) => write!(f, "rank({})", value),
+            SaltedRank { value, salt } => write!(f, "rank({}, {})", value, Quoted(salt)),
+            JsonPointer { pointer, value } => {
+                write!(f, "number(json({}, {}))", value, Quoted(pointer))
+            }
+            Now => write!(f, "now()"),
+            Time {
+                value,
+                timezone: None,
+            } => write!(f, "time({})", value),
+            Time {
+                value,
+                timezone: Some(tz),
+            } => write!(f, "time({}, {})", value, Quoted(tz)),
+            Duration(text) => write!(f, "duration({})", Quoted(text)),
+            Since(value) => write!(f, "since({})", value),
+        }
+    }
+}
+
+/// A string literal, quoted and escaped
+struct Quoted<'a>(&'a str);
+
+impl<'a> fmt::Display for Quoted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                '\r' => write!(f, "\\r")?,
+                '\t' => write!(f, "\\t")?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+// Parsing
+
+impl FromStr for Bool {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Parser::new(s)?.parse(|p| p.expression()).and_then(to_bool)
+    }
+}
+
+impl FromStr for StrList {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Parser::new(s)?.parse(|p| p.term()).and_then(to_str_list)
+    }
+}
+
+impl FromStr for Str {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Parser::new(s)?.parse(|p| p.term()).and_then(to_str)
+    }
+}
+
+impl FromStr for Num {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Parser::new(s)?.parse(|p| p.term()).and_then(to_num)
+    }
+}
+
+/// Deserializes a rule either from the text syntax or from its JSON representation
+pub fn deserialize_bool<'de, D>(deserializer: D) -> std::result::Result<Bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(TextOrExpression(PhantomData))
+}
+
+/// Deserializes a string expression either from the text syntax or from its
+/// JSON representation
+pub fn deserialize_str<'de, D>(deserializer: D) -> std::result::Result<Str, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(TextOrExpression(PhantomData))
+}
+
+struct TextOrExpression<T>(PhantomData<T>);
+
+impl<'de, T> Visitor<'de> for TextOrExpression<T>
+where
+    T: FromStr<Err = ParseError> + Deserialize<'de>,
+{
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an expression as text or JSON")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> std::result::Result<T, E> {
+        // expressions without parameters, e.g. `"browser"`, are strings in JSON too
+        value.parse().or_else(|error| {
+            T::deserialize(value.into_deserializer()).map_err(|_: E| de::Error::custom(error))
+        })
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, map: M) -> std::result::Result<T, M::Error> {
+        T::deserialize(de::value::MapAccessDeserializer::new(map))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Position {
+    line: usize,
+    column: usize,
+}
+
+impl Position {
+    fn error<T>(self, message: String) -> Result<T> {
+        Err(ParseError {
+            line: self.line,
+            column: self.column,
+            message,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Comma,
+    Op(&'static str),
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "'{}'", name),
+            Token::Str(s) => write!(f, "string {}", Quoted(s)),
+            Token::Num(n) => write!(f, "number {}", n),
+            Token::LParen => write!(f, "'('"),
+            Token::RParen => write!(f, "')'"),
+            Token::LBracket => write!(f, "'['"),
+            Token::RBracket => write!(f, "']'"),
+            Token::Comma => write!(f, "','"),
+            Token::Op(op) => write!(f, "'{}'", op),
+            Token::End => write!(f, "end of input"),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<(Token, Position)>> {
+    let mut tokens = vec![];
+    let mut chars = input.chars().peekable();
+    let mut pos = Position { line: 1, column: 1 };
+
+    fn advance(c: char, pos: &mut Position) {
+        if c == '\n' {
+            pos.line += 1;
+            pos.column = 1;
+        } else {
+            pos.column += 1;
+        }
+    }
+
+    while let Some(&c) = chars.peek() {
+        let start = pos;
+
+        if c.is_whitespace() {
+            chars.next();
+            advance(c, &mut pos);
+            continue;
+        }
+
+        let token = match c {
+            '(' | ')' | '[' | ']' | ',' => {
+                chars.next();
+                advance(c, &mut pos);
+                match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '[' => Token::LBracket,
+                    ']' => Token::RBracket,
+                    _ => Token::Comma,
+                }
+            }
+            '=' | '<' | '>' => {
+                chars.next();
+                advance(c, &mut pos);
+                let eq = chars.peek() == Some(&'=');
+                if eq {
+                    chars.next();
+                    advance('=', &mut pos);
+                }
+                match (c, eq) {
+                    ('=', true) => Token::Op("=="),
+                    ('<', false) => Token::Op("<"),
+                    ('<', true) => Token::Op("<="),
+                    ('>', false) => Token::Op(">"),
+                    ('>', true) => Token::Op(">="),
+                    _ => return start.error("Expected '=='".to_owned()),
+                }
+            }
+            '"' => {
+                chars.next();
+                advance(c, &mut pos);
+                let mut value = String::new();
+                loop {
+                    let c = match chars.next() {
+                        Some(c) => c,
+                        None => return start.error("Unterminated string".to_owned()),
+                    };
+                    let escape_pos = pos;
+                    advance(c, &mut pos);
+                    match c {
+                        '"' => break,
+                        '\\' => {
+                            let escaped = match chars.next() {
+                                Some(e) => e,
+                                None => return start.error("Unterminated string".to_owned()),
+                            };
+                            advance(escaped, &mut pos);
+                            value.push(match escaped {
+                                '"' => '"',
+                                '\\' => '\\',
+                                'n' => '\n',
+                                'r' => '\r',
+                                't' => '\t',
+                                e => {
+                                    return escape_pos
+                                        .error(format!("Unknown escape sequence '\\{}'", e))
+                                }
+                            });
+                        }
+                        c => value.push(c),
+                    }
+                }
+                Token::Str(value)
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let mut literal = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '+' {
+                        literal.push(c);
+                        chars.next();
+                        advance(c, &mut pos);
+                    } else {
+                        break;
+                    }
+                }
+                match literal.parse::<f64>() {
+                    Ok(n) if n.is_finite() => Token::Num(n),
+                    Ok(_) => return start.error(format!("Number '{}' is not finite", literal)),
+                    Err(_) => return start.error(format!("Invalid number '{}'", literal)),
+                }
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' {
+                        name.push(c);
+                        chars.next();
+                        advance(c, &mut pos);
+                    } else {
+                        break;
+                    }
+                }
+                Token::Ident(name)
+            }
+            c => return start.error(format!("Unexpected character '{}'", c)),
+        };
+
+        tokens.push((token, start));
+    }
+
+    tokens.push((Token::End, pos));
+
+    Ok(tokens)
+}
+
+/// Untyped syntax tree, which is converted to expressions once the expected
+/// type of each node is known
+#[derive(Debug)]
+struct Node {
+    pos: Position,
+    kind: Kind,
+}
+
+#[derive(Debug)]
+enum Kind {
+    Bool(bool),
+    Str(String),
+    Num(f64),
+    List(Vec<String>),
+    Call(String, Vec<Node>),
+    Group(Box<Node>),
+    Not(Box<Node>),
+    And(Vec<Node>),
+    Or(Vec<Node>),
+    Compare(&'static str, Box<Node>, Box<Node>),
+    In(Box<Node>, Box<Node>),
+    AnyIn(Box<Node>, Box<Node>),
+    AllIn(Box<Node>, Box<Node>),
+    Matches(Box<Node>, String),
+}
+
+struct Parser {
+    tokens: Vec<(Token, Position)>,
+    next: usize,
+}
+
+impl Parser {
+    fn new(input: &str) -> Result<Self> {
+        Ok(Self {
+            tokens: tokenize(input)?,
+            next: 0,
+        })
+    }
+
+    fn parse<F>(mut self, rule: F) -> Result<Node>
+    where
+        F: FnOnce(&mut Self) -> Result<Node>,
+    {
+        let node = rule(&mut self)?;
+        match self.peek() {
+            Token::End => Ok(node),
+            token => self.pos().error(format!("Unexpected {}", token)),
+        }
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].0
+    }
+
+    fn pos(&self) -> Position {
+        self.tokens[self.next].1
+    }
+
+    fn bump(&mut self) -> (Token, Position) {
+        let token = self.tokens[self.next].clone();
+        if token.0 != Token::End {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Ident(name) if name == keyword)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        let (token, pos) = self.bump();
+        if token == expected {
+            Ok(())
+        } else {
+            pos.error(format!("Expected {}, found {}", expected, token))
+        }
+    }
+
+    fn expression(&mut self) -> Result<Node> {
+        self.infix("or", Kind::Or, Self::conjunction)
+    }
+
+    fn conjunction(&mut self) -> Result<Node> {
+        self.infix("and", Kind::And, Self::unary)
+    }
+
+    fn infix<K, F>(&mut self, op: &str, kind: K, operand: F) -> Result<Node>
+    where
+        K: FnOnce(Vec<Node>) -> Kind,
+        F: Fn(&mut Self) -> Result<Node>,
+    {
+        let first = operand(self)?;
+        if !self.is_keyword(op) {
+            return Ok(first);
+        }
+
+        let pos = first.pos;
+        let mut operands = vec![first];
+        while self.is_keyword(op) {
+            self.bump();
+            operands.push(operand(self)?);
+        }
+
+        Ok(Node {
+            pos,
+            kind: kind(operands),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Node> {
+        let pos = self.pos();
+
+        if self.is_keyword("not") {
+            self.bump();
+            let value = self.unary()?;
+            return Ok(Node {
+                pos,
+                kind: Kind::Not(Box::new(value)),
+            });
+        }
+
+        if self.is_keyword("any") || self.is_keyword("all") {
+            let any = self.is_keyword("any");
+            self.bump();
+            let values = self.term()?;
+            if !self.is_keyword("in") {
+                let (token, pos) = self.bump();
+                return pos.error(format!("Expected 'in', found {}", token));
+            }
+            self.bump();
+            let list = self.term()?;
+            let (values, list) = (Box::new(values), Box::new(list));
+            let kind = if any {
+                Kind::AnyIn(values, list)
+            } else {
+                Kind::AllIn(values, list)
+            };
+            return Ok(Node { pos, kind });
+        }
+
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Node> {
+        let left = self.term()?;
+        let pos = left.pos;
+
+        let kind = match self.peek().clone() {
+            Token::Op(op) => {
+                self.bump();
+                Kind::Compare(op, Box::new(left), Box::new(self.term()?))
+            }
+            Token::Ident(ref name) if name == "in" => {
+                self.bump();
+                Kind::In(Box::new(left), Box::new(self.term()?))
+            }
+            Token::Ident(ref name) if name == "matches" => {
+                self.bump();
+                match self.bump() {
+                    (Token::Str(regex), _) => Kind::Matches(Box::new(left), regex),
+                    (token, pos) => {
+                        return pos.error(format!(
+                            "Expected a regular expression string, found {}",
+                            token
+                        ))
+                    }
+                }
+            }
+            _ => return Ok(left),
+        };
+
+        Ok(Node { pos, kind })
+    }
+
+    fn term(&mut self) -> Result<Node> {
+        let (token, pos) = self.bump();
+        let kind = match token {
+            Token::Str(s) => Kind::Str(s),
+            Token::Num(n) => Kind::Num(n),
+            Token::LParen => {
+                let inner = self.expression()?;
+                self.expect(Token::RParen)?;
+                Kind::Group(Box::new(inner))
+            }
+            Token::LBracket => {
+                let mut items = vec![];
+                if self.peek() != &Token::RBracket {
+                    loop {
+                        match self.bump() {
+                            (Token::Str(s), _) => items.push(s),
+                            (token, pos) => {
+                                return pos.error(format!("Expected a string, found {}", token))
+                            }
+                        }
+                        if self.peek() != &Token::Comma {
+                            break;
+                        }
+                        self.bump();
+                    }
+                }
+                self.expect(Token::RBracket)?;
+                Kind::List(items)
+            }
+            Token::Ident(name) if name == "true" => Kind::Bool(true),
+            Token::Ident(name) if name == "false" => Kind::Bool(false),
+            Token::Ident(name) => {
+                if self.peek() != &Token::LParen {
+                    return pos.error(format!("Expected '(' after '{}'", name));
+                }
+                self.bump();
+                let mut args = vec![];
+                if self.peek() != &Token::RParen {
+                    loop {
+                        args.push(self.expression()?);
+                        if self.peek() != &Token::Comma {
+                            break;
+                        }
+                        self.bump();
+                    }
+                }
+                self.expect(Token::RParen)?;
+                Kind::Call(name, args)
+            }
+            token => return pos.error(format!("Unexpected {}", token)),
+        };
+
+        Ok(Node { pos, kind })
+    }
+}
+
+// Typing
+
+fn describe(kind: &Kind) -> String {
+    match kind {
+        Kind::Bool(b) => format!("'{}'", b),
+        Kind::Str(s) => format!("string {}", Quoted(s)),
+        Kind::Num(n) => format!("number {}", n),
+        Kind::List(_) => "a list".to_owned(),
+        Kind::Call(name, _) => format!("'{}(...)'", name),
+        Kind::Group(inner) => describe(&inner.kind),
+        Kind::Compare(op, _, _) => format!("a '{}' comparison", op),
+        Kind::Matches(_, _) => "a 'matches' test".to_owned(),
+        Kind::Not(_)
+        | Kind::And(_)
+        | Kind::Or(_)
+        | Kind::In(_, _)
+        | Kind::AnyIn(_, _)
+        | Kind::AllIn(_, _) => "a boolean expression".to_owned(),
+    }
+}
+
+fn mismatch<T>(node: &Node, expected: &str) -> Result<T> {
+    node.pos.error(format!(
+        "Expected {}, found {}",
+        expected,
+        describe(&node.kind)
+    ))
+}
+
+/// Checks a call has the right number of arguments
+fn arity(node: &Node, name: &str, args: &[Node], count: usize) -> Result<()> {
+    if args.len() == count {
+        Ok(())
+    } else {
+        node.pos.error(format!(
+            "'{}' takes {} argument(s), found {}",
+            name,
+            count,
+            args.len()
+        ))
+    }
+}
+
+fn string_literal(node: &Node, what: &str) -> Result<String> {
+    match &node.kind {
+        Kind::Str(s) => Ok(s.clone()),
+        _ => mismatch(node, what),
+    }
+}
+
+/// Extracts the name from a `header("name")` call
+fn header_name(node: &Node) -> Result<String> {
+    match &node.kind {
+        Kind::Call(name, args) if name == "header" => {
+            arity(node, name, args, 1)?;
+            string_literal(&args[0], "a header name string")
+        }
+        _ => mismatch(node, "'header(...)'"),
+    }
+}
+
+/// Extracts the value and pointer from a `json(value, "/pointer")` call
+fn json_call(node: &Node) -> Result<(Str, String)> {
+    match &node.kind {
+        Kind::Call(name, args) if name == "json" => {
+            arity(node, name, args, 2)?;
+            Ok((
+                to_str_ref(&args[0])?,
+                string_literal(&args[1], "a JSON pointer string")?,
+            ))
+        }
+        _ => mismatch(node, "'json(...)'"),
+    }
+}
+
+fn is_num(node: &Node) -> bool {
+    match &node.kind {
+        Kind::Num(_) => true,
+        Kind::Call(name, _) => matches!(
+            name.as_str(),
+            "number" | "rank" | "now" | "time" | "duration" | "since"
+        ),
+        Kind::Group(inner) => is_num(inner),
+        _ => false,
+    }
+}
+
+fn is_version(node: &Node) -> bool {
+    match &node.kind {
+        Kind::Call(name, _) => name == "version",
+        Kind::Group(inner) => is_version(inner),
+        _ => false,
+    }
+}
+
+/// Converts an operand of a version comparison, `version(str)` or just `str`
+fn to_version_ref(node: &Node) -> Result<Str> {
+    match &node.kind {
+        Kind::Call(name, args) if name == "version" => {
+            arity(node, name, args, 1)?;
+            to_str_ref(&args[0])
+        }
+        Kind::Group(inner) => to_version_ref(inner),
+        _ => to_str_ref(node),
+    }
+}
+
+fn to_bool(node: Node) -> Result<Bool> {
+    to_bool_ref(&node)
+}
+
+fn to_bool_ref(node: &Node) -> Result<Bool> {
+    let bools = |args: &[Node]| args.iter().map(to_bool_ref).collect::<Result<Vec<_>>>();
+
+    Ok(match &node.kind {
+        Kind::Bool(b) => Bool::Constant(*b),
+        Kind::Group(inner) => to_bool_ref(inner)?,
+        Kind::Not(value) => Bool::Not(Box::new(to_bool_ref(value)?)),
+        Kind::And(values) => Bool::And(bools(values)?),
+        Kind::Or(values) => Bool::Or(bools(values)?),
+        Kind::In(value, list) => Bool::In {
+            list: to_str_list_ref(list)?,
+            value: to_str_ref(value)?,
+        },
+        Kind::AnyIn(values, list) => Bool::AnyIn {
+            list: to_str_list_ref(list)?,
+            values: to_str_list_ref(values)?,
+        },
+        Kind::AllIn(values, list) => Bool::AllIn {
+            list: to_str_list_ref(list)?,
+            values: to_str_list_ref(values)?,
+        },
+        Kind::Matches(value, regex) => Bool::Matches(regex.clone(), to_str_ref(value)?),
+        Kind::Compare(op, left, right) if is_version(left) || is_version(right) => {
+            let (l, r) = (to_version_ref(left)?, to_version_ref(right)?);
+            match *op {
+                "==" => Bool::VersionEq(l, r),
+                ">" => Bool::VersionGt(l, r),
+                "<" => Bool::VersionLt(l, r),
+                ">=" => Bool::VersionGte(l, r),
+                _ => Bool::VersionLte(l, r),
+            }
+        }
+        Kind::Compare("==", left, right) if is_num(left) || is_num(right) => {
+            Bool::NumEq(to_num_ref(left)?, to_num_ref(right)?)
+        }
+        Kind::Compare("==", left, right) => Bool::StrEq(to_str_ref(left)?, to_str_ref(right)?),
+        Kind::Compare(op, left, right) => {
+            let (l, r) = (to_num_ref(left)?, to_num_ref(right)?);
+            match *op {
+                ">" => Bool::Gt(l, r),
+                "<" => Bool::Lt(l, r),
+                ">=" => Bool::Gte(l, r),
+                _ => Bool::Lte(l, r),
+            }
+        }
+        Kind::Call(name, args) => match name.as_str() {
+            "exists" => {
+                arity(node, name, args, 1)?;
+                Bool::Attribute(header_name(&args[0])?)
+            }
+            "bool" => {
+                arity(node, name, args, 1)?;
+                let (value, pointer) = json_call(&args[0])?;
+                Bool::JsonPointer { pointer, value }
+            }
+            "and" => Bool::And(bools(args)?),
+            "or" => Bool::Or(bools(args)?),
+            "try" => {
+                arity(node, name, args, 2)?;
+                Bool::Try {
+                    value: Box::new(to_bool_ref(&args[0])?),
+                    default: match args[1].kind {
+                        Kind::Bool(default) => default,
+                        _ => return mismatch(&args[1], "'true' or 'false'"),
+                    },
+                }
+            }
+            "in_network" => {
+                arity(node, name, args, 2)?;
+                Bool::InNetwork {
+                    networks: match &args[1].kind {
+                        Kind::List(items) => items.clone(),
+                        _ => return mismatch(&args[1], "a list of networks"),
+                    },
+                    address: to_str_ref(&args[0])?,
+                }
+            }
+            "window" => {
+                if args.len() != 4 {
+                    arity(node, name, args, 3)?;
+                }
+                let days = match args.get(3).map(|days| (days, &days.kind)) {
+                    Some((_, Kind::List(items))) => items.clone(),
+                    Some((days, _)) => return mismatch(days, "a list of days"),
+                    None => vec![],
+                };
+
+                Bool::Window {
+                    from: string_literal(&args[0], "a time of day string")?,
+                    until: string_literal(&args[1], "a time of day string")?,
+                    timezone: string_literal(&args[2], "a time zone string")?,
+                    days,
+                }
+            }
+            "in_segment" => {
+                arity(node, name, args, 1)?;
+                Bool::Segment(string_literal(&args[0], "a segment name")?)
+            }
+            _ => return mismatch(node, "a boolean expression"),
+        },
+        _ => return mismatch(node, "a boolean expression"),
+    })
+}
+
+fn to_str_list(node: Node) -> Result<StrList> {
+    to_str_list_ref(&node)
+}
+
+fn to_str_list_ref(node: &Node) -> Result<StrList> {
+    Ok(match &node.kind {
+        Kind::List(items) => StrList::Constant(items.clone()),
+        Kind::Group(inner) => to_str_list_ref(inner)?,
+        Kind::Call(name, args) => match name.as_str() {
+            "split" => {
+                arity(node, name, args, 2)?;
+                StrList::Split {
+                    separator: string_literal(&args[1], "a separator string")?,
+                    value: to_str_ref(&args[0])?,
+                }
+            }
+            "captures" => {
+                arity(node, name, args, 2)?;
+                StrList::Extract {
+                    regex: string_literal(&args[1], "a regular expression string")?,
+                    value: Box::new(to_str_ref(&args[0])?),
+                }
+            }
+            "q_values" => {
+                arity(node, name, args, 1)?;
+                StrList::HttpQualityValue(to_str_ref(&args[0])?)
+            }
+            "segments" => {
+                arity(node, name, args, 1)?;
+                StrList::PathSegments(Box::new(to_str_ref(&args[0])?))
+            }
+            "query_all" => {
+                arity(node, name, args, 2)?;
+                StrList::QueryParam {
+                    name: string_literal(&args[1], "a query parameter name string")?,
+                    value: Box::new(to_str_ref(&args[0])?),
+                }
+            }
+            "list" => {
+                arity(node, name, args, 1)?;
+                StrList::Named(string_literal(&args[0], "a list name string")?)
+            }
+            _ => return mismatch(node, "a list expression"),
+        },
+        _ => return mismatch(node, "a list expression"),
+    })
+}
+
+fn to_str(node: Node) -> Result<Str> {
+    to_str_ref(&node)
+}
+
+fn to_str_ref(node: &Node) -> Result<Str> {
+    Ok(match &node.kind {
+        Kind::Str(s) => Str::Constant(s.clone()),
+        Kind::Group(inner) => to_str_ref(inner)?,
+        Kind::Call(name, args) => match name.as_str() {
+            "header" if args.len() == 2 => {
+                let select = match string_literal(&args[1], "'first', 'last' or 'joined'")?.as_str()
+                {
+                    "first" => Select::First,
+                    "last" => Select::Last,
+                    "joined" => Select::Joined,
+                    _ => return mismatch(&args[1], "'first', 'last' or 'joined'"),
+                };
+                Str::Attribute(Header::new(
+                    &string_literal(&args[0], "a header name string")?,
+                    select,
+                ))
+            }
+            "header" => Str::Attribute(header_name(node)?.into()),
+            "base64" => {
+                arity(node, name, args, 1)?;
+                Str::Base64(Box::new(to_str_ref(&args[0])?))
+            }
+            "extract" => {
+                arity(node, name, args, 2)?;
+                Str::Extract {
+                    regex: string_literal(&args[1], "a regular expression string")?,
+                    value: Box::new(to_str_ref(&args[0])?),
+                }
+            }
+            "cookie" => {
+                arity(node, name, args, 1)?;
+                Str::Cookie(string_literal(&args[0], "a cookie name string")?)
+            }
+            "browser" => {
+                arity(node, name, args, 0)?;
+                Str::Browser
+            }
+            "browser_version" => {
+                arity(node, name, args, 0)?;
+                Str::BrowserVersion
+            }
+            "operating_system" => {
+                arity(node, name, args, 0)?;
+                Str::OperatingSystem
+            }
+            "json" => {
+                let (value, pointer) = json_call(node)?;
+                Str::JsonPointer {
+                    pointer,
+                    value: Box::new(value),
+                }
+            }
+            "first" => {
+                arity(node, name, args, 1)?;
+                Str::First(Box::new(to_str_list_ref(&args[0])?))
+            }
+            "last" => {
+                arity(node, name, args, 1)?;
+                Str::Last(Box::new(to_str_list_ref(&args[0])?))
+            }
+            "client_ip" if args.is_empty() => Str::ClientIp { trusted_proxies: 0 },
+            "client_ip" => {
+                arity(node, name, args, 1)?;
+                match args[0].kind {
+                    Kind::Num(n) if n >= 0.0 && n.fract() == 0.0 => Str::ClientIp {
+                        trusted_proxies: n as usize,
+                    },
+                    _ => return mismatch(&args[0], "a number of trusted proxies"),
+                }
+            }
+            "country" => {
+                arity(node, name, args, 1)?;
+                Str::Country(Box::new(to_str_ref(&args[0])?))
+            }
+            "region" => {
+                arity(node, name, args, 1)?;
+                Str::Region(Box::new(to_str_ref(&args[0])?))
+            }
+            "asn" => {
+                arity(node, name, args, 1)?;
+                Str::Asn(Box::new(to_str_ref(&args[0])?))
+            }
+            "jwt" => {
+                arity(node, name, args, 1)?;
+                Str::Jwt(Box::new(to_str_ref(&args[0])?))
+            }
+            "host" => {
+                arity(node, name, args, 1)?;
+                Str::Host(Box::new(to_str_ref(&args[0])?))
+            }
+            "port" => {
+                arity(node, name, args, 1)?;
+                Str::Port(Box::new(to_str_ref(&args[0])?))
+            }
+            "path" => {
+                arity(node, name, args, 1)?;
+                Str::Path(Box::new(to_str_ref(&args[0])?))
+            }
+            "segment" => {
+                arity(node, name, args, 2)?;
+                match args[1].kind {
+                    Kind::Num(n) if n >= 0.0 && n.fract() == 0.0 => Str::PathSegment {
+                        index: n as usize,
+                        value: Box::new(to_str_ref(&args[0])?),
+                    },
+                    _ => return mismatch(&args[1], "a path segment index"),
+                }
+            }
+            "query" => {
+                arity(node, name, args, 2)?;
+                Str::QueryParam {
+                    name: string_literal(&args[1], "a query parameter name string")?,
+                    value: Box::new(to_str_ref(&args[0])?),
+                }
+            }
+            _ => return mismatch(node, "a string expression"),
+        },
+        _ => return mismatch(node, "a string expression"),
+    })
+}
+
+fn to_num(node: Node) -> Result<Num> {
+    to_num_ref(&node)
+}
+
+fn to_num_ref(node: &Node) -> Result<Num> {
+    Ok(match &node.kind {
+        Kind::Num(n) => Num::Constant(*n),
+        Kind::Group(inner) => to_num_ref(inner)?,
+        Kind::Call(name, args) => match name.as_str() {
+            "number" => {
+                arity(node, name, args, 1)?;
+                match &args[0].kind {
+                    Kind::Call(inner, _) if inner == "json" => {
+                        let (value, pointer) = json_call(&args[0])?;
+                        Num::JsonPointer { pointer, value }
+                    }
+                    _ => Num::Attribute(header_name(&args[0])?),
+                }
+            }
+            "rank" if args.len() == 2 => Num::SaltedRank {
+                value: to_str_ref(&args[0])?,
+                salt: string_literal(&args[1], "a salt string")?,
+            },
+            "rank" => {
+                arity(node, name, args, 1)?;
+                Num::Rank(to_str_ref(&args[0])?)
+            }
+            "now" => {
+                arity(node, name, args, 0)?;
+                Num::Now
+            }
+            "time" if args.len() == 2 => Num::Time {
+                value: to_str_ref(&args[0])?,
+                timezone: Some(string_literal(&args[1], "a time zone string")?),
+            },
+            "time" => {
+                arity(node, name, args, 1)?;
+                Num::Time {
+                    value: to_str_ref(&args[0])?,
+                    timezone: None,
+                }
+            }
+            "duration" => {
+                arity(node, name, args, 1)?;
+                Num::Duration(string_literal(&args[0], "a duration string")?)
+            }
+            "since" => {
+                arity(node, name, args, 1)?;
+                Num::Since(Box::new(to_num_ref(&args[0])?))
+            }
+            _ => return mismatch(node, "a number expression"),
+        },
+        _ => return mismatch(node, "a number expression"),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn header(name: &str) -> Str {
+        Str::Attribute(name.into())
+    }
+
+    #[test]
+    fn parses_the_example_rule() {
+        let rule: Bool =
+            r#""en-GB" in q_values(header("accept-language")) and rank(cookie("uid")) < 10"#
+                .parse()
+                .unwrap();
+
+        assert_eq!(
+            rule,
+            Bool::And(vec![
+                Bool::In {
+                    list: StrList::HttpQualityValue(header("accept-language")),
+                    value: Str::Constant("en-GB".into()),
+                },
+                Bool::Lt(Num::Rank(Str::Cookie("uid".into())), Num::Constant(10.0)),
+            ])
+        );
+    }
+
+    #[test_case(Bool::Constant(false), "false")]
+    #[test_case(Bool::Attribute("x-beta".into()), r#"exists(header("x-beta"))"#)]
+    #[test_case(Bool::AnyIn { list: StrList::Constant(vec!["en".into(), "en-GB".into()]), values: StrList::HttpQualityValue(header("accept-language")) }, r#"any q_values(header("accept-language")) in ["en", "en-GB"]"#)]
+    #[test_case(Bool::AllIn { list: StrList::Constant(vec![]), values: StrList::Split { separator: ",".into(), value: header("x-roles") } }, r#"all split(header("x-roles"), ",") in []"#)]
+    #[test_case(Bool::JsonPointer { pointer: "/staff".into(), value: Str::Base64(Box::new(Str::Cookie("token".into()))) }, r#"bool(json(base64(cookie("token")), "/staff"))"#)]
+    #[test_case(Bool::Matches(r#"^\+44"#.into(), header("phone")), r#"header("phone") matches "^\\+44""#)]
+    #[test_case(Bool::StrEq(Str::Browser, Str::Constant("Chrome".into())), r#"browser() == "Chrome""#)]
+    #[test_case(Bool::NumEq(Num::Attribute("x-tier".into()), Num::Constant(2.0)), r#"number(header("x-tier")) == 2"#)]
+    #[test_case(Bool::Lt(Num::SaltedRank { value: Str::Cookie("uid".into()), salt: "checkout".into() }, Num::Constant(12.5)), r#"rank(cookie("uid"), "checkout") < 12.5"#)]
+    #[test_case(Bool::Gte(Num::JsonPointer { pointer: "/age".into(), value: Str::Cookie("user".into()) }, Num::Constant(-1.5)), r#"number(json(cookie("user"), "/age")) >= -1.5"#)]
+    #[test_case(Bool::InNetwork { networks: vec!["10.0.0.0/8".into(), "2001:db8::/32".into()], address: Str::ClientIp { trusted_proxies: 1 } }, r#"in_network(client_ip(1), ["10.0.0.0/8", "2001:db8::/32"])"#)]
+    #[test_case(Bool::InNetwork { networks: vec![], address: Str::ClientIp { trusted_proxies: 0 } }, r#"in_network(client_ip(), [])"#)]
+    #[test_case(Bool::In { list: StrList::Constant(vec!["GB".into(), "IE".into()]), value: Str::Country(Box::new(Str::ClientIp { trusted_proxies: 1 })) }, r#"country(client_ip(1)) in ["GB", "IE"]"#)]
+    #[test_case(Bool::StrEq(Str::Region(Box::new(Str::Attribute("x-client".into()))), Str::Constant("ENG".into())), r#"region(header("x-client")) == "ENG""#)]
+    #[test_case(Bool::In { list: StrList::Constant(vec!["15169".into()]), value: Str::Asn(Box::new(Str::ClientIp { trusted_proxies: 0 })) }, r#"asn(client_ip()) in ["15169"]"#)]
+    #[test_case(Bool::StrEq(Str::JsonPointer { pointer: "/plan".into(), value: Box::new(Str::Jwt(Box::new(Str::Cookie("token".into())))) }, Str::Constant("pro".into())), r#"json(jwt(cookie("token")), "/plan") == "pro""#)]
+    #[test_case(Bool::AnyIn { list: StrList::Constant(vec!["beta".into()]), values: StrList::QueryParam { name: "features".into(), value: Box::new(header(":path")) } }, r#"any query_all(header(":path"), "features") in ["beta"]"#)]
+    #[test_case(Bool::StrEq(Str::Host(Box::new(header(":authority"))), Str::PathSegment { index: 0, value: Box::new(header(":path")) }), r#"host(header(":authority")) == segment(header(":path"), 0)"#)]
+    #[test_case(Bool::Try { value: Box::new(Bool::StrEq(header("x-beta"), Str::Constant("yes".into()))), default: false }, r#"try(header("x-beta") == "yes", false)"#)]
+    #[test_case(Bool::In { list: StrList::Named("beta-users".into()), value: header("x-user-id") }, r#"header("x-user-id") in list("beta-users")"#)]
+    #[test_case(Bool::In { list: StrList::PathSegments(Box::new(header(":path"))), value: Str::QueryParam { name: "f".into(), value: Box::new(header(":path")) } }, r#"query(header(":path"), "f") in segments(header(":path"))"#)]
+    #[test_case(
+        Bool::StrEq(
+            Str::Port(Box::new(header(":authority"))),
+            Str::Path(Box::new(header(":path")))
+        ),
+        r#"port(header(":authority")) == path(header(":path"))"#
+    )]
+    #[test_case(
+        Bool::StrEq(
+            Str::Attribute(Header::new("x-forwarded-for", Select::Last)),
+            Str::Attribute(Header::new("via", Select::Joined))
+        ),
+        r#"header("x-forwarded-for", "last") == header("via", "joined")"#
+    )]
+    #[test_case(Bool::VersionGte(Str::Attribute("x-app-version".into()), Str::Constant("4.9".into())), r#"version(header("x-app-version")) >= version("4.9")"#)]
+    #[test_case(Bool::VersionEq(Str::BrowserVersion, Str::Constant("91".into())), r#"version(browser_version()) == version("91")"#)]
+    #[test_case(Bool::Gte(Num::Now, Num::Time { value: Str::Constant("2026-11-27T00:00".into()), timezone: Some("Europe/London".into()) }), r#"now() >= time("2026-11-27T00:00", "Europe/London")"#)]
+    #[test_case(Bool::Lt(Num::Since(Box::new(Num::Time { value: Str::Cookie("joined".into()), timezone: None })), Num::Duration("7d".into())), r#"since(time(cookie("joined"))) < duration("7d")"#)]
+    #[test_case(Bool::Window { from: "09:00".into(), until: "17:30".into(), timezone: "UTC".into(), days: vec![] }, r#"window("09:00", "17:30", "UTC")"#)]
+    #[test_case(Bool::Window { from: "22:00".into(), until: "02:00".into(), timezone: "Europe/London".into(), days: vec!["fri".into(), "sat".into()] }, r#"window("22:00", "02:00", "Europe/London", ["fri", "sat"])"#)]
+    #[test_case(Bool::Segment("beta users".into()), r#"in_segment("beta users")"#)]
+    #[test_case(Bool::Not(Box::new(Bool::Or(vec![Bool::Constant(true), Bool::Constant(false)]))), "not (true or false)")]
+    #[test_case(Bool::And(vec![Bool::Or(vec![Bool::Constant(true), Bool::Constant(false)]), Bool::Not(Box::new(Bool::Constant(true)))]), "(true or false) and not true")]
+    #[test_case(Bool::Or(vec![Bool::And(vec![Bool::Constant(true), Bool::Constant(false)]), Bool::Or(vec![Bool::Constant(true), Bool::Constant(true)])]), "true and false or (true or true)")]
+    #[test_case(Bool::And(vec![Bool::And(vec![Bool::Constant(true), Bool::Constant(false)]), Bool::Constant(true)]), "(true and false) and true")]
+    #[test_case(Bool::And(vec![Bool::Constant(true)]), "and(true)")]
+    #[test_case(Bool::Or(vec![]), "or()")]
+    #[test_case(Bool::In { list: StrList::Extract { regex: "f-([a-z]+)".into(), value: Box::new(header(":authority")) }, value: Str::First(Box::new(StrList::Constant(vec!["a\"b\\c\n".into()]))) }, r#"first(["a\"b\\c\n"]) in captures(header(":authority"), "f-([a-z]+)")"#)]
+    #[test_case(Bool::StrEq(Str::Last(Box::new(StrList::Constant(vec!["a".into()]))), Str::Extract { regex: "v([0-9]+)".into(), value: Box::new(Str::JsonPointer { pointer: "/v".into(), value: Box::new(Str::OperatingSystem) }) }), r#"last(["a"]) == extract(json(operating_system(), "/v"), "v([0-9]+)")"#)]
+    #[test_case(Bool::StrEq(Str::BrowserVersion, Str::Constant("1".into())), r#"browser_version() == "1""#)]
+    #[test_case(Bool::Gt(Num::Rank(Str::Cookie("uid".into())), Num::Constant(1e21)), r#"rank(cookie("uid")) > 1000000000000000000000"#)]
+    #[test_case(Bool::Gt(Num::Rank(Str::Cookie("uid".into())), Num::Constant(1e-7)), r#"rank(cookie("uid")) > 0.0000001"#)]
+    fn prints_and_parses_back(expr: Bool, text: &str) {
+        assert_eq!(expr.to_string(), text);
+        assert_eq!(text.parse::<Bool>().unwrap(), expr);
+    }
+
+    #[test_case("true and", 1, 9, "Unexpected end of input")]
+    #[test_case("true\n  and nope", 2, 7, "Expected '(' after 'nope'"; "missing call parentheses")]
+    #[test_case(
+        r#"header("a") in "b""#,
+        1,
+        16,
+        r#"Expected a list expression, found string "b""#
+    )]
+    #[test_case(
+        r#"rank(header("a")) < "b""#,
+        1,
+        21,
+        r#"Expected a number expression, found string "b""#
+    )]
+    #[test_case(
+        r#"cookie("a", "b") == "c""#,
+        1,
+        1,
+        "'cookie' takes 1 argument(s), found 2"
+    )]
+    #[test_case("\"unterminated", 1, 1, "Unterminated string")]
+    #[test_case("true ! false", 1, 6, "Unexpected character '!'")]
+    #[test_case(
+        "browser()",
+        1,
+        1,
+        "Expected a boolean expression, found 'browser(...)'"
+    )]
+    #[test_case(
+        r#"window("09:00", "17:00", "UTC", "fri")"#,
+        1,
+        33,
+        "Expected a list of days, found string \"fri\""
+    )]
+    #[test_case(
+        r#"rank(cookie("uid")) < 1e999"#,
+        1,
+        23,
+        "Number '1e999' is not finite"
+    )]
+    #[test_case(r#"rank(cookie("uid")) > -inf"#, 1, 23, "Number '-inf' is not finite")]
+    fn reports_errors_with_position(text: &str, line: usize, column: usize, message: &str) {
+        let error = text.parse::<Bool>().unwrap_err();
+
+        assert_eq!(
+            error,
+            ParseError {
+                line,
+                column,
+                message: message.into()
+            }
+        );
+    }
+
+    #[test]
+    fn parses_other_expression_types() {
+        assert_eq!(
+            r#"split(header("x-features"), " ")"#.parse::<StrList>().unwrap(),
+            StrList::Split {
+                separator: " ".into(),
+                value: header("x-features")
+            }
+        );
+        assert_eq!(
+            r#"cookie("user")"#.parse::<Str>().unwrap(),
+            Str::Cookie("user".into())
+        );
+        assert_eq!(
+            r#"rank(header("x-user"))"#.parse::<Num>().unwrap(),
+            Num::Rank(header("x-user"))
+        );
+        assert_eq!(
+            r#"version(header("x-app-version")) < "4.10.2""#.parse::<Bool>().unwrap(),
+            Bool::VersionLt(header("x-app-version"), Str::Constant("4.10.2".into()))
+        );
+    }
+}
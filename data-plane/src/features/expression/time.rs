@@ -0,0 +1,250 @@
+//! Time based expressions, for scheduled launches and recurring windows
+//!
+//! Expressions which depend on the current time read it from a `Clock` given
+//! to them when they are compiled, so that adapters can use the host's notion
+//! of time and tests can fix it. Instants are represented as numbers of
+//! seconds since the Unix epoch and durations as numbers of seconds, so they
+//! can be compared with the existing number comparisons.
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use std::{fmt, sync::Arc};
+
+/// Source of the current time
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Result<DateTime<Utc>>;
+}
+
+/// A clock shared by all the expressions compiled with it
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The system clock of the host running the data plane
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Result<DateTime<Utc>> {
+        Ok(Utc::now())
+    }
+}
+
+/// A clock stopped at a given instant
+#[derive(Debug)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> Result<DateTime<Utc>> {
+        Ok(self.0)
+    }
+}
+
+/// Seconds since the Unix epoch, with millisecond precision
+pub fn seconds(instant: DateTime<Utc>) -> f64 {
+    instant.timestamp_millis() as f64 / 1000.0
+}
+
+pub fn parse_timezone(name: &str) -> Result<Tz> {
+    name.parse()
+        .map_err(|_| anyhow!("Unknown time zone '{}'", name))
+}
+
+/// Parses an RFC 3339 timestamp
+///
+/// When a time zone is given, timestamps without an offset, like
+/// `2026-11-27T00:00` or `2026-11-27`, are read as local time in that zone.
+pub fn parse_time(text: &str, timezone: Option<&Tz>) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let timezone = match timezone {
+        Some(tz) => tz,
+        None => bail!(
+            "'{}' is not an RFC 3339 timestamp, local times need a time zone",
+            text
+        ),
+    };
+
+    let local = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| anyhow!("'{}' is not a timestamp", text))?;
+
+    timezone
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| anyhow!("'{}' does not exist in time zone {}", text, timezone))
+}
+
+/// Parses a duration like `90s`, `15m`, `1h30m` or `2w`, in seconds
+pub fn parse_duration(text: &str) -> Result<f64> {
+    let invalid = || anyhow!("Invalid duration '{}', expected e.g. '1h30m'", text);
+
+    let mut seconds = 0.0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c {
+            's' => 1.0,
+            'm' => 60.0,
+            'h' => 3600.0,
+            'd' => 86400.0,
+            'w' => 604_800.0,
+            _ => return Err(invalid()),
+        };
+        let value: f64 = number.parse().map_err(|_| invalid())?;
+
+        seconds += value * unit;
+        number.clear();
+    }
+
+    if text.is_empty() || !number.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(seconds)
+}
+
+/// A recurring window of time, daily or on given days of the week
+#[derive(Debug)]
+pub struct Window {
+    from: NaiveTime,
+    until: NaiveTime,
+    timezone: Tz,
+    /// Days the window starts on, every day if empty
+    days: Vec<Weekday>,
+}
+
+impl Window {
+    /// Creates a window from times of day like `09:00` and day names like `mon`
+    ///
+    /// When `until` is before `from`, the window goes over midnight into the
+    /// next day.
+    pub fn new<S: AsRef<str>>(from: &str, until: &str, timezone: &str, days: &[S]) -> Result<Self> {
+        Ok(Self {
+            from: parse_time_of_day(from)?,
+            until: parse_time_of_day(until)?,
+            timezone: parse_timezone(timezone)?,
+            days: days
+                .iter()
+                .map(|day| {
+                    let day = day.as_ref();
+                    day.parse()
+                        .map_err(|_| anyhow!("Unknown day of the week '{}'", day))
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn contains(&self, instant: DateTime<Utc>) -> bool {
+        let local = instant.with_timezone(&self.timezone);
+        let time = local.time();
+        let today = local.weekday();
+        let on = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+
+        if self.from <= self.until {
+            on(today) && self.from <= time && time < self.until
+        } else {
+            (on(today) && self.from <= time) || (on(today.pred()) && time < self.until)
+        }
+    }
+
+    pub fn timezone(&self) -> &str {
+        self.timezone.name()
+    }
+}
+
+fn parse_time_of_day(text: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(text, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(text, "%H:%M:%S"))
+        .map_err(|_| anyhow!("Invalid time of day '{}', expected e.g. '09:30'", text))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        parse_time(text, None).unwrap()
+    }
+
+    #[test_case("2026-11-27T00:00:00Z", None, "2026-11-27T00:00:00Z" ; "utc")]
+    #[test_case("2026-11-27T00:00:00+01:00", None, "2026-11-26T23:00:00Z" ; "offset")]
+    #[test_case("2026-11-27T00:00", Some("Europe/London"), "2026-11-27T00:00:00Z" ; "london winter")]
+    #[test_case("2026-07-01T09:30:00", Some("Europe/London"), "2026-07-01T08:30:00Z" ; "london summer")]
+    #[test_case("2026-11-27", Some("America/New_York"), "2026-11-27T05:00:00Z" ; "date only")]
+    fn parses_timestamps(text: &str, timezone: Option<&str>, expected: &str) {
+        let timezone = timezone.map(|tz| parse_timezone(tz).unwrap());
+
+        assert_eq!(parse_time(text, timezone.as_ref()).unwrap(), utc(expected));
+    }
+
+    #[test_case("2026-11-27T00:00", None ; "local time without zone")]
+    #[test_case("next friday", Some("Europe/London") ; "not a timestamp")]
+    #[test_case("2026-03-29T01:30", Some("Europe/London") ; "skipped by daylight saving")]
+    fn rejects_invalid_timestamps(text: &str, timezone: Option<&str>) {
+        let timezone = timezone.map(|tz| parse_timezone(tz).unwrap());
+
+        assert!(parse_time(text, timezone.as_ref()).is_err());
+    }
+
+    #[test_case("90s", 90.0)]
+    #[test_case("15m", 900.0)]
+    #[test_case("1h30m", 5400.0)]
+    #[test_case("7d", 604_800.0)]
+    #[test_case("1.5h", 5400.0)]
+    fn parses_durations(text: &str, expected: f64) {
+        assert_eq!(parse_duration(text).unwrap(), expected);
+    }
+
+    #[test_case("" ; "empty")]
+    #[test_case("10" ; "no unit")]
+    #[test_case("1y" ; "unknown unit")]
+    #[test_case("h" ; "no number")]
+    fn rejects_invalid_durations(text: &str) {
+        assert!(parse_duration(text).is_err());
+    }
+
+    #[test_case("2026-11-27T09:00:00Z", true ; "start")]
+    #[test_case("2026-11-27T16:59:59Z", true ; "before end")]
+    #[test_case("2026-11-27T17:00:00Z", false ; "end")]
+    #[test_case("2026-11-27T08:00:00Z", false ; "before start")]
+    #[test_case("2026-11-28T12:00:00Z", false ; "saturday")]
+    fn matches_weekly_windows(instant: &str, expected: bool) {
+        let window = Window::new("09:00", "17:00", "Europe/London", &["mon", "fri"]).unwrap();
+
+        assert_eq!(window.contains(utc(instant)), expected);
+    }
+
+    #[test_case("2026-07-03T21:00:00Z", true ; "friday night")]
+    #[test_case("2026-07-04T00:59:00Z", true ; "after midnight")]
+    #[test_case("2026-07-04T01:00:00Z", false ; "end")]
+    #[test_case("2026-07-03T00:30:00Z", false ; "after thursday midnight")]
+    fn matches_windows_over_midnight(instant: &str, expected: bool) {
+        // 22:00 to 02:00 British Summer Time, starting on Fridays
+        let window = Window::new("22:00", "02:00", "Europe/London", &["fri"]).unwrap();
+
+        assert_eq!(window.contains(utc(instant)), expected);
+    }
+
+    #[test]
+    fn rejects_invalid_windows() {
+        let no_days: &[&str] = &[];
+
+        assert!(Window::new("9am", "17:00", "UTC", no_days).is_err());
+        assert!(Window::new("09:00", "17:00", "Mars/Olympus_Mons", no_days).is_err());
+        assert!(Window::new("09:00", "17:00", "UTC", &["someday"]).is_err());
+    }
+}
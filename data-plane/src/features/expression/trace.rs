@@ -0,0 +1,154 @@
+//! Evaluation traces
+//!
+//! Compiled expressions are evaluated with a `Tracer`, which is told about
+//! every sub-expression as it is evaluated. Normal evaluation uses `NoTrace`,
+//! which does nothing and compiles away, `Recorder` collects a `Trace` tree,
+//! explaining how the result was reached. `Memo` evaluates many rules against
+//! the same request, remembering the results of named segments.
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Record of the evaluation of a single expression and its operands
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Trace {
+    /// The kind of expression, named as in the JSON configuration
+    pub expression: &'static str,
+    /// The parameter of the expression, e.g. an attribute name or a regular expression
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub argument: Option<String>,
+    /// The resulting value, if evaluation succeeded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+    /// The error, if evaluation failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// For `and` and `or`, the index of the operand which decided the result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decided_by: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub operands: Vec<Trace>,
+}
+
+impl Trace {
+    pub fn new<T: Serialize>(
+        expression: &'static str,
+        argument: Option<&str>,
+        result: &Result<T>,
+    ) -> Self {
+        let (value, error) = match result {
+            Ok(v) => (Some(serde_json::to_value(v).unwrap_or(Value::Null)), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        Self {
+            expression,
+            argument: argument.map(|a| a.to_owned()),
+            value,
+            error,
+            decided_by: None,
+            operands: vec![],
+        }
+    }
+
+    pub fn decided_by(self, decided_by: Option<usize>) -> Self {
+        Self { decided_by, ..self }
+    }
+}
+
+pub trait Tracer {
+    /// Called before the operands of an expression are evaluated
+    fn enter(&mut self);
+    /// Called once the expression is evaluated, `trace` describes its result
+    fn exit<F: FnOnce() -> Trace>(&mut self, trace: F);
+
+    /// Evaluates the rule of a named segment, which tracers can remember
+    fn segment<F>(&mut self, _name: &str, eval: F) -> Result<bool>
+    where
+        Self: Sized,
+        F: FnOnce(&mut Self) -> Result<bool>,
+    {
+        eval(self)
+    }
+}
+
+/// Tracer which records nothing, used for normal evaluation
+pub struct NoTrace;
+
+impl Tracer for NoTrace {
+    #[inline(always)]
+    fn enter(&mut self) {}
+
+    #[inline(always)]
+    fn exit<F: FnOnce() -> Trace>(&mut self, _trace: F) {}
+}
+
+/// Tracer which records nothing, but evaluates each named segment at most
+/// once, however many rules use it
+///
+/// Results are keyed by segment name, so a `Memo` must only be used for one
+/// request and one configuration, e.g. for a single targeting pass.
+#[derive(Default)]
+pub struct Memo {
+    /// Results of segments, errors kept as their message
+    segments: HashMap<String, Result<bool, String>>,
+}
+
+impl Tracer for Memo {
+    #[inline(always)]
+    fn enter(&mut self) {}
+
+    #[inline(always)]
+    fn exit<F: FnOnce() -> Trace>(&mut self, _trace: F) {}
+
+    fn segment<F>(&mut self, name: &str, eval: F) -> Result<bool>
+    where
+        F: FnOnce(&mut Self) -> Result<bool>,
+    {
+        let result = match self.segments.get(name) {
+            Some(result) => result.clone(),
+            None => {
+                let result = eval(self).map_err(|e| format!("{:#}", e));
+                self.segments.insert(name.to_owned(), result.clone());
+
+                result
+            }
+        };
+
+        result.map_err(anyhow::Error::msg)
+    }
+}
+
+/// Tracer which records the evaluation of each expression
+#[derive(Default)]
+pub struct Recorder {
+    stack: Vec<Vec<Trace>>,
+    root: Option<Trace>,
+}
+
+impl Recorder {
+    /// The trace of the outermost expression evaluated
+    pub fn finish(self) -> Option<Trace> {
+        self.root
+    }
+}
+
+impl Tracer for Recorder {
+    fn enter(&mut self) {
+        self.stack.push(vec![]);
+    }
+
+    fn exit<F: FnOnce() -> Trace>(&mut self, trace: F) {
+        let trace = Trace {
+            operands: self.stack.pop().unwrap_or_default(),
+            ..trace()
+        };
+
+        match self.stack.last_mut() {
+            Some(parent) => parent.push(trace),
+            None => self.root = Some(trace),
+        }
+    }
+}
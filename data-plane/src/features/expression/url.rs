@@ -0,0 +1,202 @@
+//! Parts of URLs
+//!
+//! The functions take the `:authority` or `:path` pseudo-headers, or an
+//! absolute URL like `https://example.com:8443/a/b?features=one,two`. Path
+//! segments and query parameters are percent-decoded, and `+` in the query
+//! stands for a space.
+
+use anyhow::{anyhow, bail, Result};
+
+/// Host of an authority or URL, without brackets around IPv6 addresses
+pub fn host(text: &str) -> Result<&str> {
+    let authority = authority(text)?;
+
+    let host = match authority.strip_prefix('[') {
+        Some(rest) => rest
+            .find(']')
+            .map(|end| &rest[..end])
+            .ok_or_else(|| anyhow!("Invalid host in '{}'", text))?,
+        None => authority.split(':').next().unwrap_or_default(),
+    };
+
+    if host.is_empty() {
+        bail!("No host in '{}'", text);
+    }
+    Ok(host)
+}
+
+/// Port of an authority or URL, when given explicitly
+pub fn port(text: &str) -> Result<u16> {
+    let authority = authority(text)?;
+    let after_host = match authority.rfind(']') {
+        Some(end) => &authority[end + 1..],
+        None => authority,
+    };
+
+    match after_host.rsplit_once(':') {
+        Some((_, port)) => port
+            .parse()
+            .map_err(|_| anyhow!("Invalid port '{}' in '{}'", port, text)),
+        None => bail!("No port in '{}'", text),
+    }
+}
+
+/// The decoded path of a path or URL, without the query
+pub fn path(text: &str) -> Result<String> {
+    let (path, _) = path_and_query(text);
+
+    percent_decode(path, false)
+}
+
+/// The decoded, non-empty segments of the path of a path or URL
+pub fn segments(text: &str) -> Result<Vec<String>> {
+    let (path, _) = path_and_query(text);
+
+    path.split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| percent_decode(s, false))
+        .collect()
+}
+
+/// The decoded values of a query parameter of a path or URL, in order
+pub fn query_values(text: &str, name: &str) -> Result<Vec<String>> {
+    let (_, query) = path_and_query(text);
+
+    let mut values = vec![];
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+        if percent_decode(key, true)? == name {
+            values.push(percent_decode(value, true)?);
+        }
+    }
+
+    Ok(values)
+}
+
+/// Decodes percent-encoded text, e.g. a cookie value
+pub fn decode(text: &str) -> Result<String> {
+    percent_decode(text, false)
+}
+
+/// Percent-encodes all but unreserved characters, e.g. for a cookie value
+pub fn encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for b in text.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    encoded
+}
+
+fn authority(text: &str) -> Result<&str> {
+    let rest = match text.find("://") {
+        Some(i) => &text[i + 3..],
+        None if text.starts_with('/') => bail!("No authority in '{}'", text),
+        None => text,
+    };
+    let end = rest.find(&['/', '?', '#'][..]).unwrap_or(rest.len());
+    let authority = &rest[..end];
+
+    // user info
+    Ok(authority.rsplit('@').next().unwrap_or_default())
+}
+
+fn path_and_query(text: &str) -> (&str, &str) {
+    let rest = match text.find("://") {
+        Some(i) => {
+            let rest = &text[i + 3..];
+            &rest[rest.find(&['/', '?', '#'][..]).unwrap_or(rest.len())..]
+        }
+        None => text,
+    };
+    let rest = rest.split('#').next().unwrap_or_default();
+
+    rest.split_once('?').unwrap_or((rest, ""))
+}
+
+fn percent_decode(text: &str, plus_as_space: bool) -> Result<String> {
+    let invalid = || anyhow!("Invalid percent-encoding in '{}'", text);
+
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut input = text.bytes();
+    while let Some(b) = input.next() {
+        match b {
+            b'%' => {
+                let mut hex = || {
+                    input
+                        .next()
+                        .and_then(|h| (h as char).to_digit(16))
+                        .ok_or_else(invalid)
+                };
+                let high = hex()?;
+                let low = hex()?;
+                bytes.push((high * 16 + low) as u8);
+            }
+            b'+' if plus_as_space => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("example.com", Ok("example.com"))]
+    #[test_case("example.com:8080", Ok("example.com"))]
+    #[test_case("[2001:db8::1]:8080", Ok("2001:db8::1") ; "ipv6")]
+    #[test_case("https://user@f-one.example.com:8443/a?b=c", Ok("f-one.example.com") ; "url")]
+    #[test_case("/a/b", Err("No authority in '/a/b'") ; "path")]
+    fn finds_hosts(text: &str, expected: Result<&str, &str>) {
+        assert_eq!(
+            host(text).map_err(|e| e.to_string()),
+            expected.map_err(str::to_owned)
+        );
+    }
+
+    #[test_case("example.com:8080", Ok(8080))]
+    #[test_case("[2001:db8::1]:443", Ok(443) ; "ipv6")]
+    #[test_case("http://example.com:81/", Ok(81) ; "url")]
+    #[test_case("[2001:db8::1]", Err("No port in '[2001:db8::1]'") ; "ipv6 without port")]
+    #[test_case("example.com:http", Err("Invalid port 'http' in 'example.com:http'") ; "invalid")]
+    fn finds_ports(text: &str, expected: Result<u16, &str>) {
+        assert_eq!(
+            port(text).map_err(|e| e.to_string()),
+            expected.map_err(str::to_owned)
+        );
+    }
+
+    #[test_case("/", Ok(vec![]))]
+    #[test_case("/shop/caf%C3%A9/", Ok(vec!["shop", "café"]) ; "decoded")]
+    #[test_case("/a+b?c=d#e", Ok(vec!["a+b"]) ; "plus is not a space")]
+    #[test_case("https://example.com/a//b", Ok(vec!["a", "b"]) ; "url")]
+    #[test_case("https://example.com?q", Ok(vec![]) ; "url without path")]
+    #[test_case("/a%2", Err("Invalid percent-encoding in 'a%2'") ; "invalid")]
+    fn finds_path_segments(text: &str, expected: Result<Vec<&str>, &str>) {
+        assert_eq!(
+            segments(text).map_err(|e| e.to_string()),
+            expected
+                .map(|s| s.into_iter().map(str::to_owned).collect())
+                .map_err(str::to_owned)
+        );
+    }
+
+    #[test_case("/?features=a,b", "features", vec!["a,b"])]
+    #[test_case("/?f=a&g=x&f=b", "f", vec!["a", "b"] ; "repeated")]
+    #[test_case("/?f=one+two%21&f", "f", vec!["one two!", ""] ; "decoded")]
+    #[test_case("/?feat%75res=x", "features", vec!["x"] ; "encoded name")]
+    #[test_case("/?f=a#f=b", "f", vec!["a"] ; "fragment")]
+    #[test_case("/path", "f", vec![] ; "no query")]
+    fn finds_query_values(text: &str, name: &str, expected: Vec<&str>) {
+        assert_eq!(query_values(text, name).unwrap(), expected);
+    }
+}
@@ -0,0 +1,140 @@
+//! Version numbers for app and browser version targeting
+//!
+//! Versions are compared following the precedence rules of
+//! [Semantic Versioning](https://semver.org/#spec-item-11), but parsing is
+//! lenient to cope with the versions found in the wild:
+//!
+//! - a leading `v` is ignored, e.g. `v1.2.3`
+//! - missing components count as zero, so `4.9` equals `4.9.0`
+//! - more than three components are allowed, e.g. `91.0.4472.124`
+//! - build metadata after `+` is ignored
+//!
+//! Pre-releases, like `2.0.0-beta.2`, come before the release itself.
+
+use anyhow::{anyhow, Result};
+use std::{cmp::Ordering, str::FromStr};
+
+#[derive(Debug, Clone)]
+pub struct Version {
+    numbers: Vec<u64>,
+    pre_release: Vec<Identifier>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Identifier {
+    // numeric identifiers have lower precedence, so they come first
+    Numeric(u64),
+    Alphanumeric(String),
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let invalid = || anyhow!("'{}' is not a version number", text);
+
+        let version = text.trim();
+        let version = version.strip_prefix('v').unwrap_or(version);
+        let version = version.split('+').next().unwrap_or_default();
+        let (core, pre_release) = match version.find('-') {
+            Some(i) => (&version[..i], Some(&version[i + 1..])),
+            None => (version, None),
+        };
+
+        let numbers = core
+            .split('.')
+            .map(|n| n.parse().map_err(|_| invalid()))
+            .collect::<Result<Vec<u64>>>()?;
+
+        let pre_release = match pre_release {
+            Some(pre_release) => pre_release
+                .split('.')
+                .map(|id| {
+                    if id.is_empty() {
+                        Err(invalid())
+                    } else if id.bytes().all(|b| b.is_ascii_digit()) {
+                        id.parse().map(Identifier::Numeric).map_err(|_| invalid())
+                    } else {
+                        Ok(Identifier::Alphanumeric(id.to_owned()))
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => vec![],
+        };
+
+        Ok(Self {
+            numbers,
+            pre_release,
+        })
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = self.numbers.len().max(other.numbers.len());
+        let number = |v: &Self, i| v.numbers.get(i).copied().unwrap_or(0);
+
+        (0..len)
+            .map(|i| number(self, i).cmp(&number(other, i)))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or_else(
+                || match (self.pre_release.is_empty(), other.pre_release.is_empty()) {
+                    (true, true) => Ordering::Equal,
+                    (true, false) => Ordering::Greater,
+                    (false, true) => Ordering::Less,
+                    (false, false) => self.pre_release.cmp(&other.pre_release),
+                },
+            )
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    fn v(text: &str) -> Version {
+        text.parse().unwrap()
+    }
+
+    #[test_case("4.10.2", "4.9" ; "numeric not lexical")]
+    #[test_case("1.0.0", "1.0.0-rc.1" ; "release after pre-release")]
+    #[test_case("1.0.0-rc.1", "1.0.0-beta.11" ; "alphanumeric identifiers")]
+    #[test_case("1.0.0-beta.11", "1.0.0-beta.2" ; "numeric identifiers")]
+    #[test_case("1.0.0-beta", "1.0.0-2" ; "alphanumeric after numeric")]
+    #[test_case("1.0.0-alpha.1", "1.0.0-alpha" ; "more identifiers")]
+    #[test_case("91.0.4472.124", "91.0.4472" ; "four components")]
+    fn orders_versions(greater: &str, lesser: &str) {
+        assert!(v(greater) > v(lesser));
+        assert!(v(lesser) < v(greater));
+    }
+
+    #[test_case("4.9", "4.9.0" ; "partial")]
+    #[test_case("v2", "2.0.0" ; "prefix")]
+    #[test_case("1.2.3+build.5", "1.2.3" ; "build metadata")]
+    fn treats_versions_as_equal(left: &str, right: &str) {
+        assert_eq!(v(left), v(right));
+    }
+
+    #[test_case("" ; "empty")]
+    #[test_case("4.x" ; "wildcard")]
+    #[test_case("1.0.0-" ; "empty pre-release")]
+    #[test_case("1..2" ; "empty component")]
+    fn rejects_invalid_versions(text: &str) {
+        assert!(text.parse::<Version>().is_err());
+    }
+}
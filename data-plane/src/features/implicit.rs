@@ -1,31 +1,332 @@
-use crate::features::expression::Bool;
+use crate::features::{
+    expression::{
+        bucket,
+        compiled::{self, Environment},
+        get_cookie, syntax,
+        trace::{Memo, Trace},
+        url, Bool, Str,
+    },
+    lifecycle::Metadata,
+    parse_feature,
+};
+use crate::request::Request;
+use anyhow::{anyhow, bail, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
 
 /// A set of features and their matching rules
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Config(pub Vec<Feature>);
 
+impl Config {
+    /// Compile all the feature rules, failing on the first invalid one
+    pub fn compile(&self) -> Result<CompiledConfig> {
+        self.compile_with(&Environment::default())
+    }
+
+    /// Compile all the feature rules in a given environment, e.g. with a
+    /// different clock or geo databases
+    pub fn compile_with(&self, env: &Environment) -> Result<CompiledConfig> {
+        self.0
+            .iter()
+            .map(|feature| {
+                feature
+                    .compile(env)
+                    .map_err(|e| e.context(format!("Invalid rule for feature '{}'", feature.name)))
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(CompiledConfig)
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self(vec![])
     }
 }
 
+/// A set of features and their compiled matching rules, ready for evaluation
+#[derive(Debug, Default)]
+pub struct CompiledConfig(Vec<CompiledFeature>);
+
+#[derive(Debug)]
+struct CompiledFeature {
+    name: String,
+    rule: compiled::Bool,
+    variants: Option<CompiledVariants>,
+    on_error: OnError,
+}
+
+impl CompiledFeature {
+    /// The feature as it appears in the feature list, `name` or `name=variant`
+    fn enabled(&self, request: &Request) -> Result<Cow<'_, str>> {
+        match &self.variants {
+            None => Ok(Cow::Borrowed(&self.name)),
+            Some(variants) => variants
+                .choose(request)
+                .map(|variant| Cow::Owned(format!("{}={}", self.name, variant))),
+        }
+    }
+
+    /// Decides whether the feature is on given the result of its rule,
+    /// falling back to the error policy when the rule or the variant fail
+    fn decide(&self, request: &Request, matched: Result<bool>) -> Decision<'_> {
+        let enabled = matched.and_then(|matched| match matched {
+            true => self.enabled(request).map(Some),
+            false => Ok(None),
+        });
+
+        match enabled {
+            Ok(enabled) => Decision {
+                enabled,
+                error: None,
+            },
+            Err(error) => Decision {
+                enabled: self.fallback(request),
+                error: Some(error),
+            },
+        }
+    }
+
+    fn fallback(&self, request: &Request) -> Option<Cow<'_, str>> {
+        match &self.on_error {
+            OnError::Disabled => None,
+            OnError::Enabled => match &self.variants {
+                None => Some(Cow::Borrowed(&self.name)),
+                Some(variants) => variants
+                    .variants
+                    .first()
+                    .map(|(variant, _)| Cow::Owned(format!("{}={}", self.name, variant))),
+            },
+            OnError::Sticky { cookie } => get_cookie(request, cookie)
+                .and_then(url::decode)
+                .ok()?
+                .split_whitespace()
+                .find(|f| parse_feature(f).0 == self.name)
+                .map(|f| Cow::Owned(f.to_owned())),
+        }
+    }
+}
+
+struct Decision<'a> {
+    enabled: Option<Cow<'a, str>>,
+    /// The error the decision fell back on the error policy for
+    error: Option<anyhow::Error>,
+}
+
+#[derive(Debug)]
+struct CompiledVariants {
+    by: compiled::Str,
+    salt: String,
+    /// Variant names with the upper bound of their cumulative weight
+    variants: Vec<(String, u64)>,
+    total: u64,
+}
+
+impl CompiledVariants {
+    fn choose(&self, request: &Request) -> Result<&str> {
+        let identity = self.by.eval(request)?;
+        let point = u64::from(bucket::bucket(&self.salt, &identity)) * self.total
+            / u64::from(bucket::BUCKETS);
+
+        self.variants
+            .iter()
+            .find(|(_, upper)| point < *upper)
+            .map(|(name, _)| name.as_str())
+            .ok_or_else(|| anyhow!("No variant found for '{}'", identity))
+    }
+}
+
 /// Feature represents implicit targeting configuration for a single feature flag
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Feature {
-    name: String,
-    rule: Bool,
+    pub name: String,
+    /// The rule, either as an expression or as text in the rule syntax
+    #[serde(deserialize_with = "syntax::deserialize_bool")]
+    pub rule: Bool,
+    /// Variants of a multivariate feature, one of which is chosen for each
+    /// request the rule matches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variants: Option<Variants>,
+    /// Whether the feature is on when its rule or variant fail to evaluate
+    #[serde(default, skip_serializing_if = "OnError::is_default")]
+    pub on_error: OnError,
+    /// Owner, expiry etc., see the `lifecycle` module
+    #[serde(flatten)]
+    pub metadata: Metadata,
+}
+
+/// What to do with a feature whose rule fails to evaluate, e.g. because of a
+/// missing header or a malformed cookie
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    /// The feature is off
+    #[default]
+    Disabled,
+    /// The feature is on, in its first variant if it has variants
+    Enabled,
+    /// The feature is on if it was on in the feature list in the cookie, e.g.
+    /// one echoed in an earlier response, and off otherwise
+    ///
+    /// The feature list is percent-encoded, e.g. `beta%20checkout%3Dexpress`.
+    Sticky { cookie: String },
+}
+
+impl OnError {
+    fn is_default(&self) -> bool {
+        *self == OnError::Disabled
+    }
+}
+
+impl Feature {
+    fn compile(&self, env: &Environment) -> Result<CompiledFeature> {
+        let env = Environment {
+            salt: &self.name,
+            ..env.clone()
+        };
+        let rule = self.rule.compile_with(&env)?;
+        let variants = match &self.variants {
+            Some(variants) => Some(variants.compile(&self.name, &env)?),
+            None => None,
+        };
+
+        Ok(CompiledFeature {
+            name: self.name.clone(),
+            rule,
+            variants,
+            on_error: self.on_error.clone(),
+        })
+    }
+}
+
+/// Weighted variants of a feature, chosen stickily by an identity
+///
+/// The identity is bucketed like `Num::Rank`, salted with `<feature name>:variant`
+/// so the choice of variant is independent of a rank based rollout of the
+/// feature itself. Variants get a share of the buckets proportional to their
+/// weight, in the order they are listed, so adding a variant at the end only
+/// moves users from the existing variants to the new one.
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct Variants {
+    /// The identity, e.g. a user ID cookie, as an expression or as text
+    #[serde(deserialize_with = "syntax::deserialize_str")]
+    pub by: Str,
+    pub weights: Vec<Variant>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct Variant {
+    pub name: String,
+    pub weight: u32,
+}
+
+impl Variants {
+    fn compile(&self, feature: &str, env: &Environment) -> Result<CompiledVariants> {
+        let by = self.by.compile_with(env)?;
+
+        let mut total = 0;
+        let mut variants = Vec::with_capacity(self.weights.len());
+        for Variant { name, weight } in &self.weights {
+            total += u64::from(*weight);
+            variants.push((name.clone(), total));
+        }
+
+        if total == 0 {
+            bail!("Variants must have a positive total weight");
+        }
+
+        Ok(CompiledVariants {
+            by,
+            salt: format!("{}:variant", feature),
+            variants,
+            total,
+        })
+    }
+}
+
+/// Enabled features, as `name` or `name=variant` for multivariate features
+pub fn from_request<'a>(request: &Request, config: &'a CompiledConfig) -> Vec<Cow<'a, str>> {
+    evaluate(request, config).features
+}
+
+/// The outcome of implicit targeting
+#[derive(Debug)]
+pub struct Evaluation<'a> {
+    /// Enabled features, as `name` or `name=variant` for multivariate features
+    pub features: Vec<Cow<'a, str>>,
+    /// Features whose rule or variant failed to evaluate
+    pub errors: Vec<Failure>,
 }
 
-pub fn from_request<'a>(request: &HashMap<&str, &str>, config: &'a Config) -> Vec<&'a str> {
+/// A feature which failed to evaluate, decided by its error policy
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub feature: String,
+    pub error: String,
+    /// Whether the error policy turned the feature on
+    pub enabled: bool,
+}
+
+/// Enabled features, along with the features which failed to evaluate
+pub fn evaluate<'a>(request: &Request, config: &'a CompiledConfig) -> Evaluation<'a> {
+    let mut features = vec![];
+    let mut errors = vec![];
+    let mut memo = Memo::default();
+    for feature in &config.0 {
+        let matched = feature.rule.eval_traced(request, &mut memo);
+        let decision = feature.decide(request, matched);
+
+        if let Some(error) = decision.error {
+            errors.push(Failure {
+                feature: feature.name.clone(),
+                error: format!("{:#}", error),
+                enabled: decision.enabled.is_some(),
+            });
+        }
+        features.extend(decision.enabled);
+    }
+
+    Evaluation { features, errors }
+}
+
+/// How the targeting decision for a single feature was reached
+#[derive(Serialize, Debug)]
+pub struct FeatureTrace<'a> {
+    pub name: &'a str,
+    pub enabled: bool,
+    /// The chosen variant of a multivariate feature
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    /// The error the decision fell back on the error policy for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub trace: Trace,
+}
+
+pub fn explain<'a>(request: &Request, config: &'a CompiledConfig) -> Vec<FeatureTrace<'a>> {
     config
         .0
         .iter()
-        .filter_map(|Feature { name, rule }| match rule.eval(&request) {
-            Ok(true) => Some(name.as_ref()),
-            _ => None, // Ignore features whose rules fail to evaluate
+        .map(|feature| {
+            let trace = feature.rule.explain(request);
+            let matched = match &trace.error {
+                Some(error) => Err(anyhow!("{}", error)),
+                None => Ok(trace.value == Some(true.into())),
+            };
+            let decision = feature.decide(request, matched);
+
+            FeatureTrace {
+                name: &feature.name,
+                enabled: decision.enabled.is_some(),
+                variant: decision
+                    .enabled
+                    .as_deref()
+                    .and_then(|f| parse_feature(f).1)
+                    .map(str::to_owned),
+                error: decision.error.map(|e| format!("{:#}", e)),
+                trace,
+            }
         })
         .collect()
 }
@@ -36,6 +337,8 @@ mod test {
     use crate::features::expression::*;
     use pretty_assertions::assert_eq as assert_eq_diff;
     use serde_json::json;
+    use std::{collections::HashMap, sync::Arc};
+    use test_case::test_case;
 
     #[test]
     fn matches_a_complex_expression() {
@@ -51,6 +354,9 @@ mod test {
                     list: StrList::Constant(vec!["en".into(), "en-US".into(), "en-GB".into()]),
                     values: StrList::HttpQualityValue(Str::Attribute("accept-language".into())),
                 },
+                variants: None,
+                on_error: OnError::default(),
+                metadata: Metadata::default(),
             },
             Feature {
                 name: "other-english".into(),
@@ -68,6 +374,9 @@ mod test {
                         value: Str::Constant("en-GB".into()),
                     },
                 ]),
+                variants: None,
+                on_error: OnError::default(),
+                metadata: Metadata::default(),
             },
             Feature {
                 name: "british".into(),
@@ -75,6 +384,9 @@ mod test {
                     list: StrList::HttpQualityValue(Str::Attribute("accept-language".into())),
                     value: Str::Constant("en-GB".into()),
                 },
+                variants: None,
+                on_error: OnError::default(),
+                metadata: Metadata::default(),
             },
             Feature {
                 name: "german".into(),
@@ -82,15 +394,420 @@ mod test {
                     list: StrList::HttpQualityValue(Str::Attribute("accept-language".into())),
                     value: Str::Constant("de".into()),
                 },
+                variants: None,
+                on_error: OnError::default(),
+                metadata: Metadata::default(),
             },
         ]);
 
         assert_eq!(
-            from_request(&req, &config),
+            from_request(&req, &config.compile().unwrap()),
             vec!["english", "other-english", "british"]
         );
     }
 
+    #[test]
+    fn rejects_invalid_rules() {
+        let config = Config(vec![Feature {
+            name: "broken".into(),
+            rule: Bool::Matches("(".into(), Str::Attribute("host".into())),
+            variants: None,
+            on_error: OnError::default(),
+            metadata: Metadata::default(),
+        }]);
+
+        let error = config.compile().unwrap_err();
+        assert_eq!(error.to_string(), "Invalid rule for feature 'broken'");
+    }
+
+    #[test]
+    fn deserialises_rules_from_text() {
+        let config: Config = serde_json::from_value(json!([
+            {
+                "name": "british",
+                "rule": "\"en-GB\" in q_values(header(\"accept-language\"))"
+            },
+            {
+                "name": "english",
+                "rule": { "in": { "list": { "constant": ["en"] }, "value": { "constant": "en" } } }
+            }
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            config.0[0].rule,
+            Bool::In {
+                list: StrList::HttpQualityValue(Str::Attribute("accept-language".into())),
+                value: Str::Constant("en-GB".into()),
+            }
+        );
+        assert_eq!(
+            config.0[1].rule,
+            Bool::In {
+                list: StrList::Constant(vec!["en".into()]),
+                value: Str::Constant("en".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn reports_text_rule_errors_with_position() {
+        let error = serde_json::from_value::<Config>(json!([
+            { "name": "broken", "rule": "true and" }
+        ]))
+        .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "line 1, column 9: Unexpected end of input"
+        );
+    }
+
+    #[test]
+    fn explains_decisions() {
+        let req = [("accept-language", "en-GB,en;q=0.9")]
+            .iter()
+            .cloned()
+            .collect();
+        let config: Config = serde_json::from_value(json!([
+            {
+                "name": "british",
+                "rule": "\"en-GB\" in q_values(header(\"accept-language\"))"
+            },
+            {
+                "name": "beta",
+                "rule": "true and rank(cookie(\"uid\")) < 10"
+            }
+        ]))
+        .unwrap();
+        let config = config.compile().unwrap();
+
+        let expected = json!([
+            {
+                "name": "british",
+                "enabled": true,
+                "trace": {
+                    "expression": "in",
+                    "value": true,
+                    "operands": [
+                        {
+                            "expression": "http_quality_value",
+                            "value": ["en-GB", "en"],
+                            "operands": [
+                                {
+                                    "expression": "attribute",
+                                    "argument": "accept-language",
+                                    "value": "en-GB,en;q=0.9"
+                                }
+                            ]
+                        },
+                        { "expression": "constant", "value": "en-GB" }
+                    ]
+                }
+            },
+            {
+                "name": "beta",
+                "enabled": false,
+                "error": "No cookies found in request",
+                "trace": {
+                    "expression": "and",
+                    "error": "No cookies found in request",
+                    "decided_by": 1,
+                    "operands": [
+                        { "expression": "constant", "value": true },
+                        {
+                            "expression": "lt",
+                            "error": "No cookies found in request",
+                            "operands": [
+                                {
+                                    "expression": "rank",
+                                    "argument": "beta",
+                                    "error": "No cookies found in request",
+                                    "operands": [
+                                        {
+                                            "expression": "cookie",
+                                            "argument": "uid",
+                                            "error": "No cookies found in request"
+                                        }
+                                    ]
+                                }
+                            ]
+                        }
+                    ]
+                }
+            }
+        ]);
+
+        assert_eq_diff!(
+            serde_json::to_value(explain(&req, &config)).unwrap(),
+            expected
+        );
+    }
+
+    fn checkout() -> CompiledConfig {
+        let config: Config = serde_json::from_value(json!([
+            {
+                "name": "checkout",
+                "rule": "cookie(\"uid\") matches \".\"",
+                "variants": {
+                    "by": "cookie(\"uid\")",
+                    "weights": [
+                        { "name": "control", "weight": 34 },
+                        { "name": "one-page", "weight": 33 },
+                        { "name": "express", "weight": 33 }
+                    ]
+                }
+            },
+            { "name": "english", "rule": "true" }
+        ]))
+        .unwrap();
+
+        config.compile().unwrap()
+    }
+
+    #[test]
+    fn chooses_variants_stickily_by_weight() {
+        let config = checkout();
+        let mut counts = HashMap::new();
+
+        for i in 0..3000 {
+            let cookie = format!("uid={}", i);
+            let req = [("cookie", cookie.as_str())].iter().cloned().collect();
+
+            let features = from_request(&req, &config);
+            assert_eq!(features, from_request(&req, &config));
+            assert_eq!(features[1], "english");
+
+            let variant = features[0].strip_prefix("checkout=").unwrap().to_owned();
+            *counts.entry(variant).or_insert(0) += 1;
+        }
+
+        assert_eq!(counts.len(), 3);
+        for count in counts.values() {
+            assert!((900..1100).contains(count), "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn skips_variants_when_the_identity_is_missing() {
+        let req = [("cookie", "session=abc")].iter().cloned().collect();
+
+        assert_eq!(from_request(&req, &checkout()), vec!["english"]);
+    }
+
+    #[test]
+    fn explains_the_chosen_variant() {
+        let req = [("cookie", "uid=1")].iter().cloned().collect();
+        let chosen = from_request(&req, &checkout())[0].to_string();
+        let config = checkout();
+        let traces = explain(&req, &config);
+
+        assert!(traces[0].enabled);
+        assert_eq!(
+            Some(format!(
+                "checkout={}",
+                traces[0].variant.as_deref().unwrap()
+            )),
+            Some(chosen)
+        );
+        assert_eq!(traces[1].variant, None);
+    }
+
+    #[test_case(json!("disabled"), "", &[] ; "disabled")]
+    #[test_case(json!("enabled"), "", &["beta", "checkout=control"] ; "enabled")]
+    #[test_case(json!({ "sticky": { "cookie": "features" } }), "features=checkout%3Dexpress%20other", &["checkout=express"] ; "sticky")]
+    #[test_case(json!({ "sticky": { "cookie": "features" } }), "features=%ZZ", &[] ; "malformed sticky cookie")]
+    fn applies_error_policies(on_error: serde_json::Value, cookie: &str, expected: &[&str]) {
+        let config: Config = serde_json::from_value(json!([
+            { "name": "beta", "rule": "header(\"x-beta\") == \"yes\"", "on_error": on_error },
+            {
+                "name": "checkout",
+                "rule": "true",
+                "variants": { "by": "header(\"x-user-id\")", "weights": [{ "name": "control", "weight": 1 }] },
+                "on_error": on_error
+            }
+        ]))
+        .unwrap();
+        let config = config.compile().unwrap();
+        let req = Request::new().with_header("cookie", cookie);
+
+        let evaluation = evaluate(&req, &config);
+
+        assert_eq!(evaluation.features, expected.to_vec());
+        assert_eq!(
+            evaluation.errors,
+            vec![
+                Failure {
+                    feature: "beta".into(),
+                    error: "Attribute 'x-beta' not found.".into(),
+                    enabled: expected.contains(&"beta"),
+                },
+                Failure {
+                    feature: "checkout".into(),
+                    error: "Attribute 'x-user-id' not found.".into(),
+                    enabled: expected.iter().any(|f| f.starts_with("checkout")),
+                }
+            ]
+        );
+        assert_eq!(
+            explain(&req, &config)
+                .iter()
+                .map(|t| t.enabled)
+                .collect::<Vec<_>>(),
+            vec![evaluation.errors[0].enabled, evaluation.errors[1].enabled]
+        );
+    }
+
+    #[test]
+    fn rejects_variants_without_weight() {
+        let config = Config(vec![Feature {
+            name: "checkout".into(),
+            rule: Bool::Constant(true),
+            variants: Some(Variants {
+                by: Str::Browser,
+                weights: vec![Variant {
+                    name: "control".into(),
+                    weight: 0,
+                }],
+            }),
+            on_error: OnError::default(),
+            metadata: Metadata::default(),
+        }]);
+
+        let error = config.compile().unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "Invalid rule for feature 'checkout': Variants must have a positive total weight"
+        );
+    }
+
+    #[test]
+    fn deserialises_identity_from_json() {
+        let variants: Variants = serde_json::from_value(json!({
+            "by": "browser",
+            "weights": [{ "name": "a", "weight": 1 }]
+        }))
+        .unwrap();
+
+        assert_eq!(variants.by, Str::Browser);
+    }
+
+    fn jwt_fixture() -> (serde_json::Value, Environment<'static>) {
+        let fixture: serde_json::Value =
+            serde_json::from_str(include_str!("../../test-data/jwt.json")).unwrap();
+        let jwt = jwt::Config {
+            jwks: serde_json::from_value(fixture["jwks"].clone()).unwrap(),
+            issuer: Some("https://auth.example.com".into()),
+            audience: None,
+            leeway: 0,
+        };
+        let env = Environment {
+            clock: Arc::new(time::FixedClock(
+                time::parse_time("2026-06-01T00:00:00Z", None).unwrap(),
+            )),
+            jwt: Some(Arc::new(jwt.load().unwrap())),
+            ..Environment::default()
+        };
+
+        (fixture, env)
+    }
+
+    #[test]
+    fn targets_verified_token_claims() {
+        let (fixture, env) = jwt_fixture();
+        let config: Config = serde_json::from_value(json!([
+            { "name": "pro", "rule": "json(jwt(cookie(\"token\")), \"/plan\") == \"pro\"" }
+        ]))
+        .unwrap();
+        let compiled = config.compile_with(&env).unwrap();
+        let features = |token: &str| {
+            let cookie = format!("token={}", fixture["tokens"][token].as_str().unwrap());
+            let req = [("cookie", cookie.as_str())].iter().cloned().collect();
+
+            from_request(&req, &compiled)
+                .iter()
+                .map(|f| f.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(features("rs256"), vec!["pro"]);
+        // claim `"plan": "pro"`, but are not signed or come from another issuer
+        assert_eq!(features("unsigned"), Vec::<String>::new());
+        assert_eq!(features("wrong_issuer"), Vec::<String>::new());
+        assert!(config.compile().is_err());
+    }
+
+    #[test]
+    fn chooses_variants_by_verified_token_claims() {
+        let (fixture, env) = jwt_fixture();
+        let variants = |by: &str| {
+            json!([{
+                "name": "checkout",
+                "rule": "true",
+                "variants": {
+                    "by": by,
+                    "weights": [
+                        { "name": "a", "weight": 1 },
+                        { "name": "b", "weight": 1 },
+                        { "name": "c", "weight": 1 }
+                    ]
+                }
+            }])
+        };
+        let by_claim: Config =
+            serde_json::from_value(variants("json(jwt(cookie(\"token\")), \"/sub\")")).unwrap();
+        let by_cookie: Config = serde_json::from_value(variants("cookie(\"uid\")")).unwrap();
+        let cookie = format!(
+            "uid=alice; token={}",
+            fixture["tokens"]["rs256"].as_str().unwrap()
+        );
+        let req = [("cookie", cookie.as_str())].iter().cloned().collect();
+
+        // the token's subject is "alice"
+        assert_eq!(
+            from_request(&req, &by_claim.compile_with(&env).unwrap()),
+            from_request(&req, &by_cookie.compile().unwrap())
+        );
+        assert!(by_claim.compile().is_err());
+    }
+
+    #[test]
+    fn launches_features_on_schedule() {
+        let config: Config = serde_json::from_value(json!([
+            {
+                "name": "black-friday",
+                "rule": "now() >= time(\"2026-11-27T00:00\", \"Europe/London\")"
+            },
+            {
+                "name": "night-owl",
+                "rule": "window(\"22:00\", \"06:00\", \"Europe/London\")"
+            }
+        ]))
+        .unwrap();
+        let req = Request::new();
+        let at = |time: &str| {
+            let now = time::parse_time(time, None).unwrap();
+            let env = Environment {
+                clock: Arc::new(time::FixedClock(now)),
+                ..Environment::default()
+            };
+            config.compile_with(&env).unwrap()
+        };
+
+        assert_eq!(
+            from_request(&req, &at("2026-11-26T23:59:59Z")),
+            vec!["night-owl"]
+        );
+        assert_eq!(
+            from_request(&req, &at("2026-11-27T00:00:00Z")),
+            vec!["black-friday", "night-owl"]
+        );
+        assert_eq!(
+            from_request(&req, &at("2026-11-27T12:00:00Z")),
+            vec!["black-friday"]
+        );
+    }
+
     #[test]
     fn serialises_to_json() {
         let config = Config(vec![
@@ -100,6 +817,9 @@ mod test {
                     list: StrList::Constant(vec!["en".into(), "en-US".into(), "en-GB".into()]),
                     values: StrList::HttpQualityValue(Str::Attribute("accept-language".into())),
                 },
+                variants: None,
+                on_error: OnError::default(),
+                metadata: Metadata::default(),
             },
             Feature {
                 name: "other-english".into(),
@@ -117,6 +837,9 @@ mod test {
                         value: Str::Constant("en-GB".into()),
                     },
                 ]),
+                variants: None,
+                on_error: OnError::default(),
+                metadata: Metadata::default(),
             },
             Feature {
                 name: "british".into(),
@@ -124,6 +847,9 @@ mod test {
                     list: StrList::HttpQualityValue(Str::Attribute("accept-language".into())),
                     value: Str::Constant("en-GB".into()),
                 },
+                variants: None,
+                on_error: OnError::default(),
+                metadata: Metadata::default(),
             },
         ]);
 
@@ -0,0 +1,179 @@
+//! Metadata about features, and finding features ready for removal
+//!
+//! A feature flag is meant to be removed once the change it guards is fully
+//! released. Features can say who owns them, what they are for and when they
+//! expire, and `removal_candidates` lists the features which have expired or
+//! are on for everyone, so they are not forgotten.
+
+use crate::features::{
+    expression::{Bool, Num},
+    implicit,
+};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+/// Optional information about a feature, ignored by targeting
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct Metadata {
+    /// The team or person responsible for the feature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Link to the ticket tracking the change, e.g. an issue URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ticket: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<NaiveDate>,
+    /// The date from which the feature should be removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<NaiveDate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stage: Option<Stage>,
+}
+
+/// Where a feature is in its lifecycle, see "Change lifecycle" in the README
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Being built, enabled with explicit targeting by engineers
+    Development,
+    /// Being tested, enabled with explicit targeting by QA
+    Testing,
+    /// Being rolled out with implicit targeting
+    Rollout,
+    /// On for everyone, the flag is waiting to be removed
+    Released,
+}
+
+/// A feature which can likely be removed
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Candidate<'a> {
+    pub name: &'a str,
+    #[serde(flatten)]
+    pub metadata: &'a Metadata,
+    pub reasons: Vec<Reason>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// The feature's expiry date has been reached
+    Expired { expires_at: NaiveDate },
+    /// The rule matches every request, e.g. `true` or `rank(...) < 100`
+    RolledOut,
+}
+
+/// Features which have expired by `today`, or which are rolled out to everyone
+pub fn removal_candidates(config: &implicit::Config, today: NaiveDate) -> Vec<Candidate<'_>> {
+    config
+        .0
+        .iter()
+        .filter_map(|feature| {
+            let mut reasons = vec![];
+            if let Some(expires_at) = feature.metadata.expires_at {
+                if expires_at <= today {
+                    reasons.push(Reason::Expired { expires_at });
+                }
+            }
+            if rolled_out(&feature.rule) {
+                reasons.push(Reason::RolledOut);
+            }
+
+            if reasons.is_empty() {
+                None
+            } else {
+                Some(Candidate {
+                    name: &feature.name,
+                    metadata: &feature.metadata,
+                    reasons,
+                })
+            }
+        })
+        .collect()
+}
+
+/// Whether a rule matches every request, as far as can be told without one
+///
+/// Ranks are between 0 and 100, so a rollout to a rank of 100 or more is
+/// complete. Requests without the ranked value still don't match, but the
+/// rollout can't go any further.
+fn rolled_out(rule: &Bool) -> bool {
+    match rule {
+        Bool::Constant(c) => *c,
+        Bool::Lt(Num::Rank(_), Num::Constant(n))
+        | Bool::Lt(Num::SaltedRank { .. }, Num::Constant(n))
+        | Bool::Lte(Num::Rank(_), Num::Constant(n))
+        | Bool::Lte(Num::SaltedRank { .. }, Num::Constant(n))
+        | Bool::Gt(Num::Constant(n), Num::Rank(_))
+        | Bool::Gt(Num::Constant(n), Num::SaltedRank { .. })
+        | Bool::Gte(Num::Constant(n), Num::Rank(_))
+        | Bool::Gte(Num::Constant(n), Num::SaltedRank { .. }) => *n >= 100.0,
+        Bool::And(rules) => rules.iter().all(rolled_out),
+        Bool::Or(rules) => rules.iter().any(rolled_out),
+        Bool::Try { value, default } => *default && rolled_out(value),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use test_case::test_case;
+
+    fn date(date: &str) -> NaiveDate {
+        date.parse().unwrap()
+    }
+
+    #[test_case("true", true ; "constant")]
+    #[test_case("false", false ; "off")]
+    #[test_case(r#"rank(cookie("uid")) < 100"#, true ; "full rank")]
+    #[test_case(r#"rank(cookie("uid")) < 99.5"#, false ; "partial rank")]
+    #[test_case(r#"100 > rank(cookie("uid"))"#, true ; "reversed")]
+    #[test_case(r#"rank(cookie("uid")) < 100 and true"#, true ; "and")]
+    #[test_case(r#"rank(cookie("uid")) < 100 and exists(header("x-beta"))"#, false ; "and partial")]
+    #[test_case(r#"exists(header("x-beta")) or true"#, true ; "or")]
+    #[test_case(r#"exists(header("x-beta"))"#, false ; "attribute")]
+    fn finds_rolled_out_rules(rule: &str, expected: bool) {
+        assert_eq!(rolled_out(&rule.parse().unwrap()), expected);
+    }
+
+    #[test]
+    fn lists_removal_candidates() {
+        let config: implicit::Config = serde_json::from_value(json!([
+            {
+                "name": "new-checkout",
+                "rule": "rank(cookie(\"uid\")) < 100",
+                "owner": "payments",
+                "ticket": "https://example.com/PAY-123",
+                "created_at": "2026-01-10",
+                "expires_at": "2026-03-01",
+                "stage": "released"
+            },
+            { "name": "dark-mode", "rule": "true" },
+            { "name": "tips", "rule": "rank(cookie(\"uid\")) < 20", "expires_at": "2026-06-01" },
+            { "name": "banner", "rule": "rank(cookie(\"uid\")) < 20", "expires_at": "2026-12-01" }
+        ]))
+        .unwrap();
+
+        let candidates = removal_candidates(&config, date("2026-06-01"));
+
+        assert_eq!(
+            serde_json::to_value(candidates).unwrap(),
+            json!([
+                {
+                    "name": "new-checkout",
+                    "owner": "payments",
+                    "ticket": "https://example.com/PAY-123",
+                    "created_at": "2026-01-10",
+                    "expires_at": "2026-03-01",
+                    "stage": "released",
+                    "reasons": [{ "expired": { "expires_at": "2026-03-01" } }, "rolled_out"]
+                },
+                { "name": "dark-mode", "reasons": ["rolled_out"] },
+                { "name": "tips", "expires_at": "2026-06-01", "reasons": [{ "expired": { "expires_at": "2026-06-01" } }] }
+            ])
+        );
+    }
+}
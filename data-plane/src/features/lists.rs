@@ -0,0 +1,304 @@
+//! Named lists of values, like the user IDs of a beta cohort
+//!
+//! Lists with thousands of values are too large to inline in rules, so they
+//! are defined next to the features by where to load them from, and used in
+//! rules as `header("x-user-id") in list("beta-users")`. A list source has one
+//! value per line, blank lines and lines starting with `#` are skipped.
+//!
+//! Lists are loaded once, when the configuration is compiled, into a hashed
+//! set. Very large lists can instead be kept as a Bloom filter, which takes a
+//! few bits per value, at the cost of matching a small, configurable fraction
+//! of values that are not in the list. Either way membership is tested in
+//! constant time. A filter can't list its values, so lists can only be
+//! searched, with `in` or `any ... in`.
+
+use crate::features::data::{Loader, Source};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    f64::consts::LN_2,
+    fmt,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+/// Named lists to load
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct Config(pub BTreeMap<String, ListConfig>);
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct ListConfig {
+    /// Where to load the values from
+    pub source: Source,
+    /// Keep a Bloom filter rather than the values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<FilterConfig>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct FilterConfig {
+    /// Fraction of values not in the list which are found anyway, e.g. `0.001`
+    #[serde(default = "default_false_positive_rate")]
+    pub false_positive_rate: f64,
+}
+
+impl FilterConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.false_positive_rate > 0.0 && self.false_positive_rate < 1.0) {
+            bail!(
+                "False positive rate must be between 0 and 1, exclusive, not {}",
+                self.false_positive_rate
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn default_false_positive_rate() -> f64 {
+    0.001
+}
+
+/// Loaded lists, by name
+pub type Lists = HashMap<String, Arc<List>>;
+
+impl Config {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn load(&self, loader: &dyn Loader) -> Result<Lists> {
+        self.0
+            .iter()
+            .map(|(name, config)| {
+                let list = loader
+                    .load(&config.source)
+                    .and_then(|bytes| List::parse(name, &bytes, config.filter))
+                    .with_context(|| {
+                        format!("Failed to load list '{}' from {}", name, config.source)
+                    })?;
+
+                Ok((name.clone(), Arc::new(list)))
+            })
+            .collect()
+    }
+}
+
+/// A loaded list
+pub struct List {
+    pub name: String,
+    len: usize,
+    members: Members,
+}
+
+enum Members {
+    Set(HashSet<String>),
+    Filter(Bloom),
+}
+
+impl List {
+    /// Parses a list with one value per line
+    pub fn parse(name: &str, bytes: &[u8], filter: Option<FilterConfig>) -> Result<Self> {
+        let text = std::str::from_utf8(bytes).context("List is not valid UTF-8")?;
+        let values: HashSet<&str> = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+
+        let members = match filter {
+            Some(filter) => {
+                filter.validate()?;
+                let mut bloom = Bloom::new(values.len(), filter.false_positive_rate);
+                for value in &values {
+                    bloom.insert(value);
+                }
+                Members::Filter(bloom)
+            }
+            None => Members::Set(values.iter().map(|v| (*v).to_owned()).collect()),
+        };
+
+        Ok(Self {
+            name: name.to_owned(),
+            len: values.len(),
+            members,
+        })
+    }
+
+    /// Number of distinct values in the list
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains(&self, value: &str) -> bool {
+        match &self.members {
+            Members::Set(set) => set.contains(value),
+            Members::Filter(bloom) => bloom.contains(value),
+        }
+    }
+}
+
+/// Lists can be huge, so only their size is shown
+impl fmt::Debug for List {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.members {
+            Members::Set(_) => "set",
+            Members::Filter(_) => "filter",
+        };
+
+        write!(f, "List({:?}, {} values, {})", self.name, self.len, kind)
+    }
+}
+
+/// Bloom filter, with positions derived from a single 64 bit hash as
+/// `h1 + i * h2`, see Kirsch and Mitzenmacher, "Less Hashing, Same Performance"
+struct Bloom {
+    bits: Vec<u64>,
+    hashes: u64,
+}
+
+impl Bloom {
+    /// A filter sized for `count` values with the given false positive rate
+    fn new(count: usize, false_positive_rate: f64) -> Self {
+        let count = count.max(1) as f64;
+        let bits = (-count * false_positive_rate.ln() / (LN_2 * LN_2)).ceil() as usize;
+        let words = bits.max(64).div_ceil(64);
+        let hashes = ((words * 64) as f64 / count * LN_2)
+            .round()
+            .clamp(1.0, 32.0) as u64;
+
+        Self {
+            bits: vec![0; words],
+            hashes,
+        }
+    }
+
+    fn insert(&mut self, value: &str) {
+        for position in self.positions(value) {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    fn contains(&self, value: &str) -> bool {
+        self.positions(value)
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+
+    fn positions(&self, value: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let size = self.bits.len() as u64 * 64;
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        (0..self.hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % size) as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        features::{
+            data::DefaultLoader,
+            expression::{compiled::Environment, Bool},
+        },
+        request::Request,
+    };
+    use serde_json::json;
+    use test_case::test_case;
+
+    const USERS: &str = "# beta cohort\nalice\n\n  bob  \nalice\n";
+
+    #[test_case(None ; "set")]
+    #[test_case(Some(FilterConfig { false_positive_rate: 0.001 }) ; "filter")]
+    fn finds_values(filter: Option<FilterConfig>) {
+        let list = List::parse("beta", USERS.as_bytes(), filter).unwrap();
+
+        assert_eq!(list.len(), 2);
+        assert!(list.contains("alice"));
+        assert!(list.contains("bob"));
+        assert!(!list.contains("# beta cohort"));
+        assert!(!list.contains(""));
+    }
+
+    #[test]
+    fn filter_has_few_false_positives() {
+        let values: Vec<String> = (0..10_000).map(|i| format!("user-{}", i)).collect();
+        let filter = FilterConfig {
+            false_positive_rate: 0.01,
+        };
+        let list = List::parse("beta", values.join("\n").as_bytes(), Some(filter)).unwrap();
+
+        assert!(values.iter().all(|v| list.contains(v)));
+
+        let false_positives = (0..10_000)
+            .filter(|i| list.contains(&format!("visitor-{}", i)))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn loads_lists_from_sources() {
+        let config: Config = serde_json::from_value(json!({
+            "beta": { "source": { "base64": base64::encode("alice\nbob") } },
+            "staff": {
+                "source": { "base64": base64::encode("carol") },
+                "filter": {}
+            }
+        }))
+        .unwrap();
+
+        let lists = config.load(&DefaultLoader).unwrap();
+
+        assert!(lists["beta"].contains("bob"));
+        assert!(lists["staff"].contains("carol"));
+        assert_eq!(
+            format!("{:?}", lists["staff"]),
+            r#"List("staff", 1 values, filter)"#
+        );
+    }
+
+    #[test_case(r#"header("x-user-id") in list("beta")"#, Ok(true) ; "in")]
+    #[test_case(r#"any split(header("x-user-id"), ",") in list("beta")"#, Ok(true) ; "any in")]
+    #[test_case(r#"header("x-user-id") in list("gamma")"#, Err("Unknown list 'gamma'") ; "unknown")]
+    #[test_case(r#"first(list("beta")) == "alice""#, Err("Named list 'beta' can only be searched, with `in` or `any ... in`") ; "not searched")]
+    fn searches_named_lists(rule: &str, expected: Result<bool, &str>) {
+        let list = List::parse("beta", USERS.as_bytes(), None).unwrap();
+        let env = Environment {
+            lists: Some(Arc::new(
+                vec![("beta".to_owned(), Arc::new(list))]
+                    .into_iter()
+                    .collect(),
+            )),
+            ..Environment::default()
+        };
+        let request = Request::new().with_header("x-user-id", "bob");
+
+        let result = rule
+            .parse::<Bool>()
+            .unwrap()
+            .compile_with(&env)
+            .and_then(|rule| rule.eval(&request));
+
+        assert_eq!(
+            result.map_err(|e| e.to_string()),
+            expected.map_err(str::to_owned)
+        );
+    }
+
+    #[test_case(json!({ "source": { "path": "/does/not/exist" } }), "Failed to load list 'beta' from path \"/does/not/exist\"" ; "missing file")]
+    #[test_case(json!({ "source": { "base64": "" }, "filter": { "false_positive_rate": 1.5 } }), "Failed to load list 'beta' from inline base64 (0 characters)" ; "invalid filter")]
+    #[test_case(json!({ "source": { "base64": "Ym9iCmFsaWNlCg==" }, "filter": { "false_positive_rate": 1.5 } }), "Failed to load list 'beta' from inline base64 (16 characters)" ; "inline data")]
+    #[test_case(json!({ "source": "vm_configuration" }), "Failed to load list 'beta' from the VM configuration" ; "vm configuration")]
+    fn fails_to_load(list: serde_json::Value, error: &str) {
+        let config: Config = serde_json::from_value(json!({ "beta": list })).unwrap();
+
+        assert_eq!(config.load(&DefaultLoader).unwrap_err().to_string(), error);
+    }
+}
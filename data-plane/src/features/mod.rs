@@ -1,44 +1,221 @@
-use std::collections::HashMap;
+use crate::request::Request;
+use expression::{geo, jwt, trace::Trace};
+pub use feature_list::{is_enabled, parse_feature, variant, DEFAULT_HEADER};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 
+pub mod data;
+pub mod dependencies;
+pub mod echo;
 pub mod explicit;
 pub mod expression;
 pub mod implicit;
+pub mod lifecycle;
+pub mod lists;
+pub mod preview;
+pub mod segments;
+pub mod validation;
+pub mod visitor;
 
-pub fn target<'a>(
-    request: &HashMap<&str, &'a str>,
-    explicit_config: &explicit::Config,
-    implicit_config: &implicit::Config,
-) -> String {
-    union(
-        target_explicit(request, explicit_config)
-            .iter()
-            .map(|s| s.as_str())
-            .collect::<Vec<_>>()
-            .as_ref(),
-        target_implicit(request, implicit_config).as_ref(),
-    )
+/// Targeting configuration, covering both explicit and implicit targeting
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub explicit: explicit::Config,
+    #[serde(default)]
+    pub implicit: implicit::Config,
+    /// Named lists of values, see `StrList::Named`
+    #[serde(default, skip_serializing_if = "lists::Config::is_empty")]
+    pub lists: lists::Config,
+    /// Named rules shared between features, see `Bool::Segment`
+    #[serde(default, skip_serializing_if = "segments::Config::is_empty")]
+    pub segments: segments::Config,
+    /// Prerequisites and exclusion groups of features
+    #[serde(default)]
+    pub dependencies: dependencies::Config,
+    /// Whether enabling or disabling a feature wins when both are requested
+    /// explicitly
+    #[serde(default)]
+    pub precedence: Precedence,
+    /// Geo databases for `country`, `region` and `asn` lookups
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geo: Option<geo::Config>,
+    /// Keys and required claims for verifying `jwt` tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwt: Option<jwt::Config>,
+}
+
+/// Which explicit override wins when a feature is both enabled and disabled
+/// explicitly, e.g. with `x-feature-overrides: new-checkout -new-checkout`
+///
+/// Explicit overrides are `name` or `name=variant` to enable a feature, and
+/// `-name` to disable it. Either way they beat implicit targeting, so a feature
+/// disabled explicitly is off even if its rule matches. Dependencies between
+/// features are resolved after overrides, so disabling a feature also disables
+/// the features that require it.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Precedence {
+    /// Disabling a feature wins, the feature is off
+    #[default]
+    Disable,
+    /// Enabling a feature wins, the feature is on
+    Enable,
+}
+
+/// The outcome of targeting a request
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Targeted {
+    /// The enabled features, as a sorted, space separated list
+    pub features: String,
+    /// Explicit overrides dropped by the policy, for adapters to count
+    pub rejected: Vec<explicit::Rejected>,
+    /// Features which failed to evaluate, decided by their error policy, for
+    /// adapters to count and log
+    pub errors: Vec<implicit::Failure>,
+    /// Explicit targeting expressions which failed to evaluate
+    pub explicit_errors: Vec<explicit::SourceError>,
+}
+
+pub fn target(
+    request: &Request,
+    explicit_config: &explicit::CompiledConfig,
+    implicit_config: &implicit::CompiledConfig,
+    dependencies: &dependencies::CompiledConfig,
+    precedence: Precedence,
+) -> Targeted {
+    let explicit::Overrides {
+        features: explicit,
+        rejected,
+        errors: explicit_errors,
+    } = explicit::overrides(request, explicit_config);
+    let implicit::Evaluation {
+        features: implicit,
+        errors,
+    } = implicit::evaluate(request, implicit_config);
+
+    let explicit: Vec<&str> = explicit.iter().map(|s| s.as_str()).collect();
+    let (mut explicit, disabled) = overrides(&explicit, precedence);
+    let mut implicit: Vec<&str> = implicit
+        .iter()
+        .map(|s| s.as_ref())
+        .filter(|f| !disabled.contains(&parse_feature(f).0))
+        .collect();
+    dependencies.resolve(&mut explicit, &mut implicit);
+
+    Targeted {
+        features: union(&explicit, &implicit),
+        rejected,
+        errors,
+        explicit_errors,
+    }
+}
+
+/// Splits explicit overrides into the features enabled and the names of the
+/// features disabled, resolving conflicts between the two
+fn overrides<'f>(explicit: &[&'f str], precedence: Precedence) -> (Vec<&'f str>, Vec<&'f str>) {
+    let (disabled, enabled): (Vec<&str>, Vec<&str>) =
+        explicit.iter().partition(|f| f.starts_with('-'));
+    let mut disabled: Vec<&str> = disabled.iter().map(|f| &f[1..]).collect();
+
+    match precedence {
+        Precedence::Disable => {
+            let enabled = enabled
+                .into_iter()
+                .filter(|f| !disabled.contains(&parse_feature(f).0))
+                .collect();
+
+            (enabled, disabled)
+        }
+        Precedence::Enable => {
+            disabled.retain(|name| !enabled.iter().any(|f| parse_feature(f).0 == *name));
+
+            (enabled, disabled)
+        }
+    }
 }
 
+/// Joins feature lists into a sorted, space separated list
+///
+/// Each feature appears once. When the same feature is in both lists, possibly
+/// with different variants, the one in `existing` wins.
 pub fn union(existing: &[&str], new: &[&str]) -> String {
     let mut result: Vec<&str> = Vec::new();
-    result.extend_from_slice(existing);
-    result.extend_from_slice(new);
+    for &feature in existing.iter().chain(new) {
+        if !result
+            .iter()
+            .any(|f| parse_feature(f).0 == parse_feature(feature).0)
+        {
+            result.push(feature);
+        }
+    }
     result.sort();
-    result.dedup();
     result.join(" ")
 }
 
-pub fn target_explicit(request: &HashMap<&str, &str>, config: &explicit::Config) -> Vec<String> {
+pub fn target_explicit(request: &Request, config: &explicit::CompiledConfig) -> Vec<String> {
     explicit::from_request(request, config)
 }
 
 pub fn target_implicit<'a>(
-    request: &HashMap<&str, &str>,
-    config: &'a implicit::Config,
-) -> Vec<&'a str> {
+    request: &Request,
+    config: &'a implicit::CompiledConfig,
+) -> Vec<Cow<'a, str>> {
     implicit::from_request(request, config)
 }
 
+/// How the enabled features were decided for a request
+#[derive(Serialize, Debug)]
+pub struct Explanation<'a> {
+    /// The resulting feature list, as it would be set on the request
+    pub features: String,
+    /// Explicit overrides dropped by the policy
+    pub rejected: Vec<explicit::Rejected>,
+    /// Features which failed to evaluate
+    pub errors: Vec<implicit::Failure>,
+    /// Explicit targeting expressions which failed to evaluate
+    pub explicit_errors: Vec<explicit::SourceError>,
+    pub explicit: Vec<Trace>,
+    pub implicit: Vec<implicit::FeatureTrace<'a>>,
+}
+
+/// Targets the request like `target`, recording the evaluation of every expression
+pub fn explain<'a>(
+    request: &Request,
+    explicit_config: &explicit::CompiledConfig,
+    implicit_config: &'a implicit::CompiledConfig,
+    dependencies: &dependencies::CompiledConfig,
+    precedence: Precedence,
+) -> Explanation<'a> {
+    let Targeted {
+        features,
+        rejected,
+        errors,
+        explicit_errors,
+    } = target(
+        request,
+        explicit_config,
+        implicit_config,
+        dependencies,
+        precedence,
+    );
+
+    Explanation {
+        features,
+        rejected,
+        errors,
+        explicit_errors,
+        explicit: explicit::explain(request, explicit_config),
+        implicit: implicit::explain(request, implicit_config),
+    }
+}
+
+/// Checks the configuration for problems which would otherwise only show up
+/// as features silently failing to evaluate
+pub fn validate(config: &Config) -> Vec<validation::Problem> {
+    validation::validate(config)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -50,7 +227,46 @@ mod test {
     #[test_case(&["x", "y", "z"], &["z"], "x y z" ; "already exists")]
     #[test_case(&["x", "z", "y"], &[], "x y z" ; "sort")]
     #[test_case(&["x", "z", "z", "y"], &[], "x y z" ; "dedup")]
+    #[test_case(&["x=b"], &["x=a", "y"], "x=b y" ; "existing variant wins")]
+    #[test_case(&["x"], &["x=a"], "x" ; "existing without variant wins")]
     fn union_feature(existing: &[&str], new: &[&str], result: &str) {
         assert_eq!(union(existing, new), result);
     }
+
+    #[test_case("a checkout=express b", "checkout", true, Some("express"))]
+    #[test_case("a checkout b", "checkout", true, None)]
+    #[test_case("a checkout-v2=x", "checkout", false, None)]
+    #[test_case("", "checkout", false, None)]
+    fn reads_variants(features: &str, name: &str, enabled: bool, variant: Option<&str>) {
+        assert_eq!(is_enabled(features, name), enabled);
+        assert_eq!(super::variant(features, name), variant);
+    }
+
+    #[test_case("", Precedence::Disable, "british checkout" ; "implicit")]
+    #[test_case("-british", Precedence::Disable, "checkout" ; "disabled")]
+    #[test_case("-british -checkout beta", Precedence::Disable, "beta" ; "all disabled")]
+    #[test_case("british -british", Precedence::Disable, "checkout" ; "disable wins")]
+    #[test_case("british -british", Precedence::Enable, "british checkout" ; "enable wins")]
+    #[test_case("checkout=express -checkout", Precedence::Enable, "british checkout=express" ; "enable variant wins")]
+    #[test_case("-beta", Precedence::Enable, "british checkout" ; "disable absent")]
+    fn applies_overrides(overrides: &str, precedence: Precedence, expected: &str) {
+        let implicit: implicit::Config = serde_json::from_value(serde_json::json!([
+            { "name": "british", "rule": "true" },
+            { "name": "checkout", "rule": "true" }
+        ]))
+        .unwrap();
+        let request = Request::new().with_header("x-feature-overrides", overrides);
+
+        assert_eq!(
+            target(
+                &request,
+                &explicit::CompiledConfig::default(),
+                &implicit.compile().unwrap(),
+                &dependencies::CompiledConfig::default(),
+                precedence,
+            )
+            .features,
+            expected
+        );
+    }
 }
@@ -0,0 +1,419 @@
+//! Signed preview tokens, for sharing features in a link
+//!
+//! A token carries a list of explicit overrides and an expiry, signed with a
+//! key from the configuration, so features can be previewed without opening
+//! explicit targeting to everyone. Tokens are `<claims>.<signature>`, both
+//! base64url encoded without padding, where the claims are JSON, e.g.
+//! `{"features":["new-checkout"],"exp":1798761600}`. The signature is over
+//! the encoded claims, with HMAC SHA-256 (`hs256`) or Ed25519 (`ed25519`).
+//!
+//! Overrides from a valid token are not subject to the explicit targeting
+//! policy, since whoever signed the token has already allowed them.
+
+use crate::features::expression::{
+    compiled::{self, Environment},
+    time::SharedClock,
+    Str,
+};
+use crate::request::Request;
+use anyhow::{anyhow, bail, Result};
+use base64::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::Signer as _;
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use std::{convert::TryInto, fmt};
+
+/// Where to find preview tokens and the keys to verify them with
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct Config {
+    /// Expressions reading tokens from the request, e.g. a header, a cookie
+    /// or a query parameter
+    pub from: Vec<Str>,
+    /// Keys trusted to sign tokens
+    pub keys: Vec<Key>,
+}
+
+impl Config {
+    pub fn compile_with(&self, env: &Environment) -> Result<Tokens> {
+        let from = self
+            .from
+            .iter()
+            .map(|source| source.compile_with(env))
+            .collect::<Result<_>>()?;
+
+        Ok(Tokens {
+            from,
+            verifier: self.load()?,
+            clock: env.clock.clone(),
+        })
+    }
+
+    pub fn load(&self) -> Result<Verifier> {
+        let keys = self
+            .keys
+            .iter()
+            .map(VerifyingKey::from_key)
+            .collect::<Result<_>>()?;
+
+        Ok(Verifier { keys })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    Hs256,
+    Ed25519,
+}
+
+/// A key trusted to sign tokens
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct Key {
+    /// Chooses the key for tokens with the same `kid` claim
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    pub alg: Algorithm,
+    /// The shared secret, or the Ed25519 public key, base64url encoded
+    pub key: String,
+}
+
+/// Shared secrets are redacted, so configurations can be logged
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = match self.alg {
+            Algorithm::Hs256 => "<redacted>",
+            Algorithm::Ed25519 => &self.key,
+        };
+
+        f.debug_struct("Key")
+            .field("kid", &self.kid)
+            .field("alg", &self.alg)
+            .field("key", &key)
+            .finish()
+    }
+}
+
+impl Key {
+    /// Checks the key can be decoded
+    pub fn validate(&self) -> Result<()> {
+        VerifyingKey::from_key(self).map(|_| ())
+    }
+}
+
+/// The signed content of a token
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Claims {
+    /// Explicit overrides, like `name`, `name=variant` or `-name`
+    pub features: Vec<String>,
+    /// Expiry, in seconds since the Unix epoch
+    pub exp: i64,
+    /// The key the token is signed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+}
+
+enum Material {
+    Hmac(Vec<u8>),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+struct VerifyingKey {
+    kid: Option<String>,
+    material: Material,
+}
+
+impl VerifyingKey {
+    fn from_key(key: &Key) -> Result<Self> {
+        let bytes = decode(&key.key)?;
+        let material = match key.alg {
+            Algorithm::Hs256 if bytes.is_empty() => bail!("Shared secret is empty"),
+            Algorithm::Hs256 => Material::Hmac(bytes),
+            Algorithm::Ed25519 => {
+                let bytes: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| anyhow!("Ed25519 public key must be 32 bytes"))?;
+
+                ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+                    .map(Material::Ed25519)
+                    .map_err(|_| anyhow!("Invalid Ed25519 public key"))?
+            }
+        };
+
+        Ok(Self {
+            kid: key.kid.clone(),
+            material,
+        })
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match &self.material {
+            Material::Hmac(secret) => Hmac::<Sha256>::new_from_slice(secret)
+                .map(|mut mac| {
+                    mac.update(message);
+                    mac.verify_slice(signature).is_ok()
+                })
+                .unwrap_or(false),
+            Material::Ed25519(key) => ed25519_dalek::Signature::from_slice(signature)
+                .map(|s| key.verify_strict(message, &s).is_ok())
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Verifies tokens with a set of loaded keys
+pub struct Verifier {
+    keys: Vec<VerifyingKey>,
+}
+
+impl Verifier {
+    /// Verifies a token at the time `now`, returning its claims
+    pub fn verify(&self, token: &str, now: DateTime<Utc>) -> Result<Claims> {
+        let token = token.trim();
+        let (encoded, signature) = token
+            .split_once('.')
+            .ok_or_else(|| anyhow!("Malformed token, expected two segments"))?;
+
+        // the key is chosen by a claim, which is only trusted once verified
+        let claims: Claims = decode_json(encoded)?;
+        let signature = decode(signature)?;
+        let verified = self
+            .keys
+            .iter()
+            .filter(|key| claims.kid.is_none() || key.kid == claims.kid)
+            .any(|key| key.verify(encoded.as_bytes(), &signature));
+
+        if !verified {
+            bail!("Invalid token signature");
+        }
+        if now.timestamp() >= claims.exp {
+            bail!("Token has expired");
+        }
+
+        Ok(claims)
+    }
+}
+
+impl fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Verifier({} keys)", self.keys.len())
+    }
+}
+
+/// A key to sign tokens with
+pub enum SigningKey {
+    Hs256(Vec<u8>),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+impl SigningKey {
+    /// Decodes a base64url encoded shared secret, or Ed25519 private key
+    pub fn decode(alg: Algorithm, key: &str) -> Result<Self> {
+        let bytes = decode(key)?;
+
+        match alg {
+            Algorithm::Hs256 if bytes.is_empty() => bail!("Shared secret is empty"),
+            Algorithm::Hs256 => Ok(SigningKey::Hs256(bytes)),
+            Algorithm::Ed25519 => {
+                let bytes: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| anyhow!("Ed25519 private key must be 32 bytes"))?;
+
+                Ok(SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(
+                    &bytes,
+                )))
+            }
+        }
+    }
+
+    /// The key to put in the configuration to verify tokens signed with this
+    /// one, base64url encoded
+    pub fn verifying_key(&self) -> String {
+        match self {
+            SigningKey::Hs256(secret) => base64::encode_config(secret, URL_SAFE_NO_PAD),
+            SigningKey::Ed25519(key) => {
+                base64::encode_config(key.verifying_key().as_bytes(), URL_SAFE_NO_PAD)
+            }
+        }
+    }
+}
+
+/// Creates a signed token
+pub fn mint(claims: &Claims, key: &SigningKey) -> Result<String> {
+    let encoded = base64::encode_config(&serde_json::to_vec(claims)?, URL_SAFE_NO_PAD);
+
+    let signature = match key {
+        SigningKey::Hs256(secret) => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+                .map_err(|e| anyhow!("Invalid shared secret: {}", e))?;
+            mac.update(encoded.as_bytes());
+            mac.finalize().into_bytes().to_vec()
+        }
+        SigningKey::Ed25519(key) => key.sign(encoded.as_bytes()).to_bytes().to_vec(),
+    };
+
+    Ok(format!(
+        "{}.{}",
+        encoded,
+        base64::encode_config(&signature, URL_SAFE_NO_PAD)
+    ))
+}
+
+fn decode(text: &str) -> Result<Vec<u8>> {
+    base64::decode_config(text, URL_SAFE_NO_PAD).map_err(|e| anyhow!("Invalid base64: {}", e))
+}
+
+fn decode_json<T: DeserializeOwned>(text: &str) -> Result<T> {
+    serde_json::from_slice(&decode(text)?).map_err(|e| anyhow!("Invalid token claims: {}", e))
+}
+
+/// Compiled token configuration, ready for evaluation
+#[derive(Debug)]
+pub struct Tokens {
+    from: Vec<compiled::Str>,
+    verifier: Verifier,
+    clock: SharedClock,
+}
+
+impl Tokens {
+    /// Overrides from all the valid tokens in the request
+    ///
+    /// Tokens which are missing, invalid or expired are ignored.
+    pub fn features(&self, request: &Request) -> Vec<String> {
+        let now = match self.clock.now() {
+            Ok(now) => now,
+            Err(_) => return vec![],
+        };
+
+        self.from
+            .iter()
+            .filter_map(|source| source.eval(request).ok())
+            .filter_map(|token| self.verifier.verify(&token, now).ok())
+            .flat_map(|claims| claims.features)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_case::test_case;
+
+    const SECRET: &str = "c2hhcmVkLXNlY3JldC1mb3ItcHJldmlld3M";
+    const ED25519_PRIVATE: &str = "nWGxne_9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A";
+
+    fn claims(kid: Option<&str>) -> Claims {
+        Claims {
+            features: vec!["new-checkout".into(), "-old-nav".into()],
+            exp: time("2027-01-01T00:00:00Z").timestamp(),
+            kid: kid.map(str::to_owned),
+        }
+    }
+
+    fn time(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    fn now() -> DateTime<Utc> {
+        time("2026-10-17T12:00:00Z")
+    }
+
+    fn verifier() -> Verifier {
+        let public = SigningKey::decode(Algorithm::Ed25519, ED25519_PRIVATE)
+            .unwrap()
+            .verifying_key();
+
+        Config {
+            from: vec![],
+            keys: vec![
+                Key {
+                    kid: Some("shared".into()),
+                    alg: Algorithm::Hs256,
+                    key: SECRET.into(),
+                },
+                Key {
+                    kid: None,
+                    alg: Algorithm::Ed25519,
+                    key: public,
+                },
+            ],
+        }
+        .load()
+        .unwrap()
+    }
+
+    #[test]
+    fn redacts_shared_secrets() {
+        let config = Config {
+            from: vec![],
+            keys: vec![Key {
+                kid: Some("shared".into()),
+                alg: Algorithm::Hs256,
+                key: SECRET.into(),
+            }],
+        };
+
+        let debug = format!("{:?}", config);
+
+        assert!(!debug.contains(SECRET), "{}", debug);
+        assert!(debug.contains(r#"key: "<redacted>""#), "{}", debug);
+    }
+
+    #[test_case(Algorithm::Hs256, SECRET, Some("shared") ; "hs256")]
+    #[test_case(Algorithm::Hs256, SECRET, None ; "hs256 without kid")]
+    #[test_case(Algorithm::Ed25519, ED25519_PRIVATE, None ; "ed25519")]
+    fn verifies_minted_tokens(alg: Algorithm, key: &str, kid: Option<&str>) {
+        let key = SigningKey::decode(alg, key).unwrap();
+        let token = mint(&claims(kid), &key).unwrap();
+
+        assert_eq!(verifier().verify(&token, now()).unwrap(), claims(kid));
+    }
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        let verify = |token: &str, now| {
+            verifier()
+                .verify(token, now)
+                .map_err(|e| e.to_string())
+                .map(|_| ())
+        };
+        let hs256 = SigningKey::decode(Algorithm::Hs256, SECRET).unwrap();
+        let token = mint(&claims(None), &hs256).unwrap();
+
+        let other = SigningKey::decode(Algorithm::Hs256, "b3RoZXI").unwrap();
+        let forged = mint(&claims(None), &other).unwrap();
+
+        let mut tampered = claims(None);
+        tampered.features.push("admin".into());
+        let tampered = format!(
+            "{}.{}",
+            mint(&tampered, &other).unwrap().split('.').next().unwrap(),
+            token.split('.').nth(1).unwrap()
+        );
+
+        let wrong_kid = mint(&claims(Some("other")), &hs256).unwrap();
+
+        assert_eq!(verify(&token, now()), Ok(()));
+        assert_eq!(
+            verify(&token, time("2027-01-01T00:00:00Z")),
+            Err("Token has expired".into())
+        );
+        assert_eq!(
+            verify(&forged, now()),
+            Err("Invalid token signature".into())
+        );
+        assert_eq!(
+            verify(&tampered, now()),
+            Err("Invalid token signature".into())
+        );
+        assert_eq!(
+            verify(&wrong_kid, now()),
+            Err("Invalid token signature".into())
+        );
+        assert_eq!(
+            verify("nope", now()),
+            Err("Malformed token, expected two segments".into())
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Named segments, rules shared between features
+//!
+//! Segments like "staff" or "beta users" are defined once, next to the
+//! features, and used in rules as `in_segment("staff")`. Segments can refer to
+//! other segments. Unknown segments and segments referring to themselves,
+//! directly or not, are rejected when compiling.
+//!
+//! When targeting, each segment is evaluated at most once, however many rules
+//! refer to it. Explanations evaluate segments every time, to show how each
+//! rule was decided. A rank in a segment is salted with the segment name, rather
+//! than the feature name, so the segment is the same for every feature.
+
+use crate::features::{
+    dependencies,
+    expression::{
+        compiled::{self, Environment},
+        syntax, Bool,
+    },
+};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{collections::BTreeMap, sync::Arc};
+
+/// Segment rules by name, either as expressions or as text
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct Config(#[serde(deserialize_with = "deserialize_rules")] pub BTreeMap<String, Bool>);
+
+impl Config {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Compile all the segments, each after the segments it refers to
+    pub fn compile_with(&self, env: &Environment) -> Result<Arc<compiled::Segments>> {
+        let mut segments = compiled::Segments::new();
+        for name in self.sorted()? {
+            let env = Environment {
+                salt: name,
+                segments: Some(Arc::new(segments.clone())),
+                ..env.clone()
+            };
+            let rule = self.0[name]
+                .compile_with(&env)
+                .map_err(|e| e.context(format!("Invalid rule for segment '{}'", name)))?;
+
+            segments.insert(
+                name.to_owned(),
+                Arc::new(compiled::Segment {
+                    name: name.to_owned(),
+                    rule,
+                }),
+            );
+        }
+
+        Ok(Arc::new(segments))
+    }
+
+    /// Segment names, each after the segments it refers to
+    pub fn sorted(&self) -> Result<Vec<&str>> {
+        let references = self
+            .0
+            .iter()
+            .map(|(name, rule)| {
+                let mut references = vec![];
+                segment_references(rule, &mut references);
+
+                (name.clone(), references)
+            })
+            .collect();
+
+        dependencies::sort(&references)
+            .map(|sorted| sorted.into_iter().map(|name| self.name(name)).collect())
+            .map_err(|cycle| anyhow!("Segments form a cycle: {}", cycle.join(" -> ")))
+    }
+
+    /// The name as borrowed from the configuration
+    fn name(&self, name: &str) -> &str {
+        self.0
+            .get_key_value(name)
+            .map(|(key, _)| key.as_str())
+            .unwrap_or_default()
+    }
+}
+
+/// Names of the segments a rule refers to
+pub fn segment_references(rule: &Bool, references: &mut Vec<String>) {
+    match rule {
+        Bool::Segment(name) => references.push(name.clone()),
+        Bool::Not(value) | Bool::Try { value, .. } => segment_references(value, references),
+        Bool::And(values) | Bool::Or(values) => {
+            for value in values {
+                segment_references(value, references);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn deserialize_rules<'de, D>(deserializer: D) -> Result<BTreeMap<String, Bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Rule(#[serde(deserialize_with = "syntax::deserialize_bool")] Bool);
+
+    let rules: BTreeMap<String, Rule> = BTreeMap::deserialize(deserializer)?;
+
+    Ok(rules
+        .into_iter()
+        .map(|(name, Rule(rule))| (name, rule))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        features::{self, explicit, expression::trace::Memo, implicit},
+        request::Request,
+    };
+    use serde_json::json;
+    use test_case::test_case;
+
+    fn segments(config: serde_json::Value) -> Result<Arc<compiled::Segments>> {
+        let config: Config = serde_json::from_value(config).unwrap();
+
+        config.compile_with(&Environment::default())
+    }
+
+    #[test]
+    fn evaluates_segments_referring_to_segments() {
+        let segments = segments(json!({
+            "uk english": "\"en-GB\" in q_values(header(\"accept-language\"))",
+            "staff": "header(\"x-staff\") == \"yes\"",
+            "uk staff": "in_segment(\"staff\") and in_segment(\"uk english\")"
+        }))
+        .unwrap();
+        let env = Environment {
+            segments: Some(segments),
+            ..Environment::default()
+        };
+        let rule = Bool::Segment("uk staff".into()).compile_with(&env).unwrap();
+
+        let staff = Request::new()
+            .with_header("x-staff", "yes")
+            .with_header("accept-language", "en-GB");
+        let visitor = Request::new()
+            .with_header("x-staff", "no")
+            .with_header("accept-language", "en-GB");
+
+        assert!(rule.eval(&staff).unwrap());
+        assert!(!rule.eval(&visitor).unwrap());
+    }
+
+    #[test]
+    fn evaluates_each_segment_once_per_pass() {
+        let segments = segments(json!({ "staff": { "attribute": "x-staff" } })).unwrap();
+        let env = Environment {
+            segments: Some(segments),
+            ..Environment::default()
+        };
+        let staff = Bool::Segment("staff".into()).compile_with(&env).unwrap();
+        let mut memo = Memo::default();
+
+        assert!(staff
+            .eval_traced(&Request::new().with_header("x-staff", "1"), &mut memo)
+            .unwrap());
+        // remembered, rather than evaluated again
+        assert!(staff.eval_traced(&Request::new(), &mut memo).unwrap());
+        assert!(staff.eval(&Request::new()).is_err());
+    }
+
+    #[test]
+    fn explains_segments_after_targeting() {
+        let segments = segments(json!({ "staff": { "attribute": "x-staff" } })).unwrap();
+        let env = Environment {
+            segments: Some(segments),
+            ..Environment::default()
+        };
+        let config: implicit::Config = serde_json::from_value(json!([
+            { "name": "a", "rule": "in_segment(\"staff\")" },
+            { "name": "b", "rule": "in_segment(\"staff\")" }
+        ]))
+        .unwrap();
+        let implicit = config.compile_with(&env).unwrap();
+        let request = Request::new().with_header("x-staff", "1");
+
+        let explanation = features::explain(
+            &request,
+            &explicit::CompiledConfig::default(),
+            &implicit,
+            &dependencies::CompiledConfig::default(),
+            features::Precedence::default(),
+        );
+
+        assert_eq!(explanation.features, "a b");
+        for feature in &explanation.implicit {
+            let segment = &feature.trace;
+            assert_eq!(segment.argument.as_deref(), Some("staff"));
+            assert_eq!(segment.operands.len(), 1);
+            assert_eq!(segment.operands[0].expression, "attribute");
+        }
+    }
+
+    #[test_case(json!({ "a": "in_segment(\"b\")", "b": "in_segment(\"a\")" }), "Segments form a cycle: a -> b -> a" ; "cycle")]
+    #[test_case(json!({ "a": "not in_segment(\"a\")" }), "Segments form a cycle: a -> a" ; "self")]
+    #[test_case(json!({ "a": "in_segment(\"nope\")" }), "Invalid rule for segment 'a'" ; "unknown")]
+    fn rejects_invalid_segments(config: serde_json::Value, error: &str) {
+        assert_eq!(segments(config).unwrap_err().to_string(), error);
+    }
+}
@@ -0,0 +1,788 @@
+//! Static checks of targeting configuration
+//!
+//! Evaluation errors turn features off rather than failing requests, so a typo
+//! in a regular expression or a JSON pointer would otherwise go unnoticed.
+//! Validation walks the whole configuration and reports every problem found,
+//! along with its location in the JSON representation of the configuration.
+
+use crate::features::{
+    dependencies,
+    expression::{network::Network, time, version::Version, Bool, Num, Str, StrList},
+    implicit::{Feature, OnError, Variants},
+    Config,
+};
+use regex::Regex;
+use serde::Serialize;
+use std::{collections::HashSet, fmt};
+
+/// A problem found in the configuration
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Problem {
+    /// Location of the offending expression, e.g. `implicit[3].rule.and[1].matches`
+    pub path: String,
+    /// Description of the problem
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+pub fn validate(config: &Config) -> Vec<Problem> {
+    let mut validator = Validator {
+        has_geo: config.geo.is_some(),
+        has_jwt: config.jwt.is_some(),
+        segments: config.segments.0.keys().cloned().collect(),
+        lists: config.lists.0.keys().cloned().collect(),
+        ..Validator::default()
+    };
+
+    validator.field("lists", |v| {
+        for (name, list) in &config.lists.0 {
+            if let Some(filter) = &list.filter {
+                if let Err(e) = filter.validate() {
+                    v.key(name, |v| v.field("filter", |v| v.report(e.to_string())));
+                }
+            }
+        }
+    });
+
+    validator.field("segments", |v| {
+        if let Err(e) = config.segments.sorted() {
+            v.report(e.to_string());
+        }
+
+        for (name, rule) in &config.segments.0 {
+            v.key(name, |v| v.bool(rule));
+        }
+    });
+
+    if let Some(geo) = &config.geo {
+        validator.field("geo", |v| {
+            if geo.databases.is_empty() {
+                v.field("databases", |v| {
+                    v.report("At least one geo database is needed".to_owned())
+                });
+            }
+        });
+    }
+
+    if let Some(jwt) = &config.jwt {
+        validator.field("jwt", |v| {
+            v.field("jwks", |v| {
+                v.field("keys", |v| {
+                    for (i, key) in jwt.jwks.keys.iter().enumerate() {
+                        if let Err(e) = key.validate() {
+                            v.index(i, |v| v.report(e.to_string()));
+                        }
+                    }
+                })
+            })
+        });
+    }
+
+    validator.field("explicit", |v| {
+        let sources = |v: &mut Validator| {
+            for (i, list) in config.explicit.sources.iter().enumerate() {
+                v.index(i, |v| v.str_list(list));
+            }
+        };
+
+        if config.explicit.is_list() {
+            sources(v);
+        } else {
+            v.field("sources", sources);
+        }
+
+        if let Some(tokens) = &config.explicit.tokens {
+            v.field("tokens", |v| {
+                v.field("from", |v| {
+                    for (i, source) in tokens.from.iter().enumerate() {
+                        v.index(i, |v| v.str(source));
+                    }
+                });
+                v.field("keys", |v| {
+                    for (i, key) in tokens.keys.iter().enumerate() {
+                        if let Err(e) = key.validate() {
+                            v.index(i, |v| v.report(e.to_string()));
+                        }
+                    }
+                });
+            });
+        }
+    });
+    validator.field("implicit", |v| {
+        for (i, feature) in config.implicit.0.iter().enumerate() {
+            v.index(i, |v| v.feature(feature));
+        }
+    });
+    validator.field("dependencies", |v| v.dependencies(&config.dependencies));
+
+    validator.problems
+}
+
+enum Segment {
+    Field(&'static str),
+    Index(usize),
+    /// A key of a map, e.g. a segment name
+    Key(String),
+}
+
+#[derive(Default)]
+struct Validator {
+    path: Vec<Segment>,
+    problems: Vec<Problem>,
+    /// Whether the configuration has geo databases for geo lookups
+    has_geo: bool,
+    /// Whether the configuration has keys for verifying tokens
+    has_jwt: bool,
+    /// Names of the segments defined in the configuration
+    segments: HashSet<String>,
+    /// Names of the lists defined in the configuration
+    lists: HashSet<String>,
+}
+
+impl Validator {
+    fn field<F: FnOnce(&mut Self)>(&mut self, name: &'static str, f: F) {
+        self.path.push(Segment::Field(name));
+        f(self);
+        self.path.pop();
+    }
+
+    fn index<F: FnOnce(&mut Self)>(&mut self, index: usize, f: F) {
+        self.path.push(Segment::Index(index));
+        f(self);
+        self.path.pop();
+    }
+
+    fn key<F: FnOnce(&mut Self)>(&mut self, key: &str, f: F) {
+        self.path.push(Segment::Key(key.to_owned()));
+        f(self);
+        self.path.pop();
+    }
+
+    fn report(&mut self, message: String) {
+        let mut path = String::new();
+        for segment in &self.path {
+            match segment {
+                Segment::Field(name) if path.is_empty() => path.push_str(name),
+                Segment::Field(name) => {
+                    path.push('.');
+                    path.push_str(name);
+                }
+                Segment::Index(i) => path.push_str(&format!("[{}]", i)),
+                Segment::Key(key) => path.push_str(&format!("[{:?}]", key)),
+            }
+        }
+
+        self.problems.push(Problem { path, message });
+    }
+
+    fn feature(&mut self, feature: &Feature) {
+        if feature.name.is_empty() {
+            self.field("name", |v| v.report("Feature name is empty".to_owned()));
+        } else if feature.name.contains(char::is_whitespace) {
+            self.field("name", |v| {
+                v.report(format!(
+                    "Feature name '{}' contains whitespace",
+                    feature.name
+                ))
+            });
+        }
+
+        self.field("rule", |v| v.bool(&feature.rule));
+
+        if let Some(variants) = &feature.variants {
+            self.field("variants", |v| v.variants(variants));
+        }
+
+        if let OnError::Sticky { cookie } = &feature.on_error {
+            if cookie.is_empty() {
+                self.field("on_error", |v| {
+                    v.field("sticky", |v| v.report("Cookie name is empty".to_owned()))
+                });
+            }
+        }
+
+        if let (Some(created_at), Some(expires_at)) =
+            (feature.metadata.created_at, feature.metadata.expires_at)
+        {
+            if expires_at < created_at {
+                self.field("expires_at", |v| {
+                    v.report(format!(
+                        "Feature expires on {}, before it was created on {}",
+                        expires_at, created_at
+                    ))
+                });
+            }
+        }
+    }
+
+    fn dependencies(&mut self, dependencies: &dependencies::Config) {
+        if let Err(e) = dependencies.sorted() {
+            self.field("requires", |v| v.report(e.to_string()));
+        }
+
+        self.field("exclusive", |v| {
+            for (i, group) in dependencies.exclusive.iter().enumerate() {
+                let required = group.iter().find_map(|feature| {
+                    let prerequisites = dependencies.requires.get(feature)?;
+                    let excluded = prerequisites.iter().find(|p| group.contains(p))?;
+
+                    Some((feature, excluded))
+                });
+
+                if let Some((feature, excluded)) = required {
+                    v.index(i, |v| {
+                        v.report(format!(
+                            "Feature '{}' requires '{}', which it excludes",
+                            feature, excluded
+                        ))
+                    });
+                }
+            }
+        });
+    }
+
+    fn variants(&mut self, variants: &Variants) {
+        self.field("by", |v| v.str(&variants.by));
+        self.field("weights", |v| {
+            if variants.weights.iter().all(|w| w.weight == 0) {
+                v.report("Variants must have a positive total weight".to_owned());
+            }
+
+            for (i, variant) in variants.weights.iter().enumerate() {
+                let name = &variant.name;
+                if name.is_empty() || name.contains(|c: char| c == '=' || c.is_whitespace()) {
+                    v.index(i, |v| {
+                        v.report(format!(
+                            "Variant name '{}' must be non-empty, without '=' or whitespace",
+                            name
+                        ))
+                    });
+                } else if variants.weights[..i].iter().any(|w| &w.name == name) {
+                    v.index(i, |v| v.report(format!("Duplicate variant '{}'", name)));
+                }
+            }
+        });
+    }
+
+    fn bool(&mut self, expr: &Bool) {
+        use Bool::*;
+        match expr {
+            Constant(_) | Attribute(_) => {}
+            In { list, value } => self.field("in", |v| {
+                v.field("list", |v| v.haystack(list));
+                v.field("value", |v| v.str(value));
+            }),
+            AnyIn { list, values } => self.field("any_in", |v| {
+                v.field("list", |v| v.haystack(list));
+                v.field("values", |v| v.str_list(values));
+            }),
+            AllIn { list, values } => self.field("all_in", |v| {
+                v.field("list", |v| v.str_list(list));
+                v.field("values", |v| v.str_list(values));
+            }),
+            JsonPointer { pointer, value } => self.field("json_pointer", |v| {
+                v.pointer(pointer);
+                v.field("value", |v| v.str(value));
+            }),
+            Matches(regex, value) => self.field("matches", |v| {
+                v.regex(regex, false);
+                v.index(1, |v| v.str(value));
+            }),
+            StrEq(left, right) => self.field("str_eq", |v| {
+                v.index(0, |v| v.str(left));
+                v.index(1, |v| v.str(right));
+            }),
+            NumEq(left, right) => self.field("num_eq", |v| v.num_pair(left, right)),
+            Gt(left, right) => self.field("gt", |v| v.num_pair(left, right)),
+            Lt(left, right) => self.field("lt", |v| v.num_pair(left, right)),
+            Gte(left, right) => self.field("gte", |v| v.num_pair(left, right)),
+            Lte(left, right) => self.field("lte", |v| v.num_pair(left, right)),
+            InNetwork { networks, address } => self.field("in_network", |v| {
+                v.field("networks", |v| {
+                    for (i, n) in networks.iter().enumerate() {
+                        if let Err(e) = Network::parse(n) {
+                            v.index(i, |v| v.report(e.to_string()));
+                        }
+                    }
+                });
+                v.field("address", |v| v.str(address));
+            }),
+            VersionEq(left, right) => self.field("version_eq", |v| v.version_pair(left, right)),
+            VersionGt(left, right) => self.field("version_gt", |v| v.version_pair(left, right)),
+            VersionLt(left, right) => self.field("version_lt", |v| v.version_pair(left, right)),
+            VersionGte(left, right) => self.field("version_gte", |v| v.version_pair(left, right)),
+            VersionLte(left, right) => self.field("version_lte", |v| v.version_pair(left, right)),
+            Not(value) => self.field("not", |v| v.bool(value)),
+            And(values) => self.field("and", |v| v.bools(values)),
+            Or(values) => self.field("or", |v| v.bools(values)),
+            Try { value, .. } => self.field("try", |v| v.field("value", |v| v.bool(value))),
+            Window {
+                from,
+                until,
+                timezone,
+                days,
+            } => self.field("window", |v| {
+                if let Err(e) = time::Window::new(from, until, timezone, days) {
+                    v.report(e.to_string());
+                }
+            }),
+            Bool::Segment(name) => {
+                if !self.segments.contains(name) {
+                    self.field("segment", |v| {
+                        v.report(format!("Unknown segment '{}'", name))
+                    });
+                }
+            }
+        }
+    }
+
+    fn bools(&mut self, exprs: &[Bool]) {
+        for (i, expr) in exprs.iter().enumerate() {
+            self.index(i, |v| v.bool(expr));
+        }
+    }
+
+    /// A list searched with `in`, which may be a named list
+    fn haystack(&mut self, expr: &StrList) {
+        match expr {
+            StrList::Named(name) => {
+                if !self.lists.contains(name) {
+                    self.field("named", |v| v.report(format!("Unknown list '{}'", name)));
+                }
+            }
+            list => self.str_list(list),
+        }
+    }
+
+    fn str_list(&mut self, expr: &StrList) {
+        use StrList::*;
+        match expr {
+            Constant(_) => {}
+            Split { separator, value } => self.field("split", |v| {
+                if separator.is_empty() {
+                    v.report("Separator is empty".to_owned());
+                }
+                v.field("value", |v| v.str(value));
+            }),
+            Extract { regex, value } => self.field("extract", |v| {
+                v.regex(regex, true);
+                v.field("value", |v| v.str(value));
+            }),
+            HttpQualityValue(value) => self.field("http_quality_value", |v| v.str(value)),
+            PathSegments(value) => self.field("path_segments", |v| v.str(value)),
+            QueryParam { value, .. } => {
+                self.field("query_param", |v| v.field("value", |v| v.str(value)))
+            }
+            Named(name) => self.field("named", |v| {
+                v.report(format!(
+                    "Named list '{}' can only be searched, with `in` or `any ... in`",
+                    name
+                ))
+            }),
+        }
+    }
+
+    fn str(&mut self, expr: &Str) {
+        use Str::*;
+        match expr {
+            Constant(_) | Attribute(_) | Cookie(_) => {}
+            Browser | BrowserVersion | OperatingSystem | ClientIp { .. } => {}
+            Base64(value) => self.field("base64", |v| v.str(value)),
+            Extract { regex, value } => self.field("extract", |v| {
+                v.regex(regex, true);
+                v.field("value", |v| v.str(value));
+            }),
+            JsonPointer { pointer, value } => self.field("json_pointer", |v| {
+                v.pointer(pointer);
+                v.field("value", |v| v.str(value));
+            }),
+            First(list) => self.field("first", |v| v.str_list(list)),
+            Last(list) => self.field("last", |v| v.str_list(list)),
+            Country(address) => self.field("country", |v| v.geo(address)),
+            Region(address) => self.field("region", |v| v.geo(address)),
+            Asn(address) => self.field("asn", |v| v.geo(address)),
+            Jwt(token) => self.field("jwt", |v| {
+                if !v.has_jwt {
+                    v.report("Verifying tokens needs JWT keys in the configuration".to_owned());
+                }
+                v.str(token);
+            }),
+            Host(value) => self.field("host", |v| v.str(value)),
+            Port(value) => self.field("port", |v| v.str(value)),
+            Path(value) => self.field("path", |v| v.str(value)),
+            PathSegment { value, .. } => {
+                self.field("path_segment", |v| v.field("value", |v| v.str(value)))
+            }
+            QueryParam { value, .. } => {
+                self.field("query_param", |v| v.field("value", |v| v.str(value)))
+            }
+        }
+    }
+
+    fn geo(&mut self, address: &Str) {
+        if !self.has_geo {
+            self.report("Geo lookups need a geo database in the configuration".to_owned());
+        }
+        self.str(address);
+    }
+
+    fn num(&mut self, expr: &Num) {
+        use Num::*;
+        match expr {
+            Constant(n) if !n.is_finite() => self.field("constant", |v| {
+                v.report(format!(
+                    "Number {} is not finite, and can't be written in rules",
+                    n
+                ))
+            }),
+            Constant(_) | Attribute(_) => {}
+            Rank(value) => self.field("rank", |v| v.str(value)),
+            SaltedRank { value, .. } => {
+                self.field("salted_rank", |v| v.field("value", |v| v.str(value)))
+            }
+            JsonPointer { pointer, value } => self.field("json_pointer", |v| {
+                v.pointer(pointer);
+                v.field("value", |v| v.str(value));
+            }),
+            Now => {}
+            Time { value, timezone } => self.field("time", |v| {
+                let timezone = match timezone.as_deref().map(time::parse_timezone) {
+                    Some(Err(e)) => return v.report(e.to_string()),
+                    Some(Ok(tz)) => Some(tz),
+                    None => None,
+                };
+                if let Str::Constant(text) = value {
+                    if let Err(e) = time::parse_time(text, timezone.as_ref()) {
+                        v.report(e.to_string());
+                    }
+                }
+                v.field("value", |v| v.str(value));
+            }),
+            Duration(text) => self.field("duration", |v| {
+                if let Err(e) = time::parse_duration(text) {
+                    v.report(e.to_string());
+                }
+            }),
+            Since(value) => self.field("since", |v| v.num(value)),
+        }
+    }
+
+    fn num_pair(&mut self, left: &Num, right: &Num) {
+        self.index(0, |v| v.num(left));
+        self.index(1, |v| v.num(right));
+    }
+
+    fn version_pair(&mut self, left: &Str, right: &Str) {
+        for (i, value) in [left, right].iter().enumerate() {
+            self.index(i, |v| {
+                if let Str::Constant(text) = value {
+                    if let Err(e) = text.parse::<Version>() {
+                        v.report(e.to_string());
+                    }
+                }
+                v.str(value);
+            });
+        }
+    }
+
+    /// Checks the regular expression compiles and optionally has a capture group
+    fn regex(&mut self, regex: &str, needs_capture_group: bool) {
+        match Regex::new(regex) {
+            // the implicit group for the whole match is counted as well
+            Ok(r) if needs_capture_group && r.captures_len() < 2 => self.report(format!(
+                "Regular expression '{}' must have a capture group",
+                regex
+            )),
+            Ok(_) => {}
+            Err(e) => self.report(format!("Invalid regular expression: {}", e)),
+        }
+    }
+
+    /// Checks the pointer is valid according to RFC6901
+    fn pointer(&mut self, pointer: &str) {
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            self.report(format!(
+                "JSON pointer '{}' must be empty or start with '/'",
+                pointer
+            ));
+            return;
+        }
+
+        let mut chars = pointer.chars();
+        while let Some(c) = chars.next() {
+            if c == '~' && !matches!(chars.next(), Some('0') | Some('1')) {
+                self.report(format!(
+                    "JSON pointer '{}' has an invalid escape sequence, only '~0' and '~1' are allowed",
+                    pointer
+                ));
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq as assert_eq_diff;
+    use serde_json::json;
+
+    fn problems(config: serde_json::Value) -> Vec<String> {
+        let config: Config = serde_json::from_value(config).unwrap();
+
+        validate(&config).iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn valid_configuration_has_no_problems() {
+        let config = json!({
+            "explicit": [
+                { "split": { "separator": " ", "value": { "attribute": "x-feature-overrides" } } },
+                { "extract": { "regex": "f-([a-z]+)", "value": { "attribute": ":authority" } } }
+            ],
+            "implicit": [
+                {
+                    "name": "english",
+                    "rule": {
+                        "in": {
+                            "list": { "http_quality_value": { "attribute": "accept-language" } },
+                            "value": { "constant": "en" }
+                        }
+                    }
+                }
+            ]
+        });
+
+        assert_eq!(problems(config), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_all_problems_with_locations() {
+        let config = json!({
+            "explicit": [
+                { "split": { "separator": "", "value": { "attribute": "x-feature-overrides" } } },
+                { "extract": { "regex": "f-[a-z]+", "value": { "attribute": ":authority" } } }
+            ],
+            "implicit": [
+                { "name": "", "rule": { "constant": true } },
+                { "name": "two words", "rule": { "constant": true } },
+                {
+                    "name": "bad-regex",
+                    "rule": {
+                        "and": [
+                            { "constant": true },
+                            { "matches": ["(unclosed", { "attribute": "host" }] }
+                        ]
+                    }
+                },
+                {
+                    "name": "bad-pointer",
+                    "rule": {
+                        "gt": [
+                            {
+                                "json_pointer": {
+                                    "pointer": "user/age",
+                                    "value": { "cookie": "user" }
+                                }
+                            },
+                            { "constant": 18.0 }
+                        ]
+                    }
+                },
+                {
+                    "name": "bad-escape",
+                    "rule": {
+                        "not": {
+                            "str_eq": [
+                                { "constant": "a" },
+                                {
+                                    "json_pointer": {
+                                        "pointer": "/a~2b",
+                                        "value": { "attribute": "x-user" }
+                                    }
+                                }
+                            ]
+                        }
+                    }
+                },
+                {
+                    "name": "black-friday",
+                    "rule": {
+                        "and": [
+                            { "window": { "from": "9am", "until": "17:00", "timezone": "Europe/London" } },
+                            { "gte": ["now", { "time": { "value": { "constant": "2026-11-27T00:00" } } }] },
+                            { "lt": [{ "since": { "constant": 0.0 } }, { "duration": "1y" }] },
+                            { "version_gte": [{ "attribute": "x-app-version" }, { "constant": "4.x" }] },
+                            { "in_network": { "networks": ["10.0.0.0/8", "office"], "address": { "client_ip": {} } } }
+                        ]
+                    }
+                },
+                {
+                    "name": "checkout",
+                    "rule": { "constant": true },
+                    "variants": {
+                        "by": { "cookie": "uid" },
+                        "weights": [
+                            { "name": "control", "weight": 0 },
+                            { "name": "one page", "weight": 0 },
+                            { "name": "control", "weight": 0 }
+                        ]
+                    }
+                },
+                {
+                    "name": "tips",
+                    "rule": { "constant": true },
+                    "on_error": { "sticky": { "cookie": "" } },
+                    "created_at": "2026-03-01",
+                    "expires_at": "2026-01-01"
+                }
+            ]
+        });
+
+        assert_eq_diff!(
+            problems(config),
+            vec![
+                "explicit[0].split: Separator is empty",
+                "explicit[1].extract: Regular expression 'f-[a-z]+' must have a capture group",
+                "implicit[0].name: Feature name is empty",
+                "implicit[1].name: Feature name 'two words' contains whitespace",
+                "implicit[2].rule.and[1].matches: Invalid regular expression: regex parse error:\n    (unclosed\n    ^\nerror: unclosed group",
+                "implicit[3].rule.gt[0].json_pointer: JSON pointer 'user/age' must be empty or start with '/'",
+                "implicit[4].rule.not.str_eq[1].json_pointer: JSON pointer '/a~2b' has an invalid escape sequence, only '~0' and '~1' are allowed",
+                "implicit[5].rule.and[0].window: Invalid time of day '9am', expected e.g. '09:30'",
+                "implicit[5].rule.and[1].gte[1].time: '2026-11-27T00:00' is not an RFC 3339 timestamp, local times need a time zone",
+                "implicit[5].rule.and[2].lt[1].duration: Invalid duration '1y', expected e.g. '1h30m'",
+                "implicit[5].rule.and[3].version_gte[1]: '4.x' is not a version number",
+                "implicit[5].rule.and[4].in_network.networks[1]: Invalid network 'office', expected e.g. '10.0.0.0/8'",
+                "implicit[6].variants.weights: Variants must have a positive total weight",
+                "implicit[6].variants.weights[1]: Variant name 'one page' must be non-empty, without '=' or whitespace",
+                "implicit[6].variants.weights[2]: Duplicate variant 'control'",
+                "implicit[7].on_error.sticky: Cookie name is empty",
+                "implicit[7].expires_at: Feature expires on 2026-01-01, before it was created on 2026-03-01",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_geo_lookups_without_databases() {
+        let rule = json!({
+            "in": {
+                "list": { "constant": ["GB", "IE"] },
+                "value": { "country": { "client_ip": { "trusted_proxies": 1 } } }
+            }
+        });
+
+        assert_eq!(
+            problems(json!({ "implicit": [{ "name": "uk", "rule": rule }] })),
+            vec!["implicit[0].rule.in.value.country: Geo lookups need a geo database in the configuration"]
+        );
+        assert_eq!(
+            problems(json!({
+                "implicit": [{ "name": "uk", "rule": rule }],
+                "geo": { "databases": [] }
+            })),
+            vec!["geo.databases: At least one geo database is needed"]
+        );
+    }
+
+    #[test]
+    fn reports_tokens_without_keys() {
+        let rule = json!({
+            "str_eq": [
+                { "json_pointer": { "pointer": "/plan", "value": { "jwt": { "cookie": "token" } } } },
+                { "constant": "pro" }
+            ]
+        });
+
+        assert_eq!(
+            problems(json!({ "implicit": [{ "name": "pro", "rule": rule }] })),
+            vec!["implicit[0].rule.str_eq[0].json_pointer.value.jwt: Verifying tokens needs JWT keys in the configuration"]
+        );
+        assert_eq!(
+            problems(json!({
+                "implicit": [{ "name": "pro", "rule": rule }],
+                "jwt": { "jwks": { "keys": [{ "kty": "oct", "k": "c2VjcmV0" }, { "kty": "RSA", "n": "AQAB" }] } }
+            })),
+            vec!["jwt.jwks.keys[1]: RSA key is missing 'e'"]
+        );
+    }
+
+    #[test]
+    fn reports_contradictory_dependencies() {
+        assert_eq!(
+            problems(json!({
+                "dependencies": {
+                    "requires": { "tips": ["checkout"], "checkout": ["tips"] },
+                    "exclusive": [["dark", "light"], ["tips", "one-page", "checkout"]]
+                }
+            })),
+            vec![
+                "dependencies.requires: Prerequisites form a cycle: checkout -> tips -> checkout",
+                "dependencies.exclusive[1]: Feature 'tips' requires 'checkout', which it excludes",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_segment_problems() {
+        assert_eq!(
+            problems(json!({
+                "segments": {
+                    "beta users": "in_segment(\"staff\") or in_segment(\"beta users\")",
+                    "staff": "header(\"x-staff\") matches \"(\""
+                },
+                "implicit": [{ "name": "beta", "rule": "in_segment(\"beta\")" }]
+            })),
+            vec![
+                "segments: Segments form a cycle: beta users -> beta users",
+                "segments[\"staff\"].matches: Invalid regular expression: regex parse error:\n    (\n    ^\nerror: unclosed group",
+                "implicit[0].rule.segment: Unknown segment 'beta'",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_list_problems() {
+        assert_eq!(
+            problems(json!({
+                "lists": {
+                    "beta": { "source": { "path": "beta.txt" }, "filter": { "false_positive_rate": 0 } }
+                },
+                "implicit": [
+                    { "name": "a", "rule": "header(\"x-user-id\") in list(\"alpha\")" },
+                    { "name": "b", "rule": "all split(header(\"x-ids\"), \",\") in list(\"beta\")" }
+                ]
+            })),
+            vec![
+                "lists[\"beta\"].filter: False positive rate must be between 0 and 1, exclusive, not 0",
+                "implicit[0].rule.in.list.named: Unknown list 'alpha'",
+                "implicit[1].rule.all_in.list.named: Named list 'beta' can only be searched, with `in` or `any ... in`",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_non_finite_numbers() {
+        let mut config: Config = serde_json::from_value(json!({
+            "implicit": [{ "name": "a", "rule": { "constant": true } }]
+        }))
+        .unwrap();
+        config.implicit.0[0].rule = Bool::Lt(
+            Num::Rank(Str::Cookie("uid".into())),
+            Num::Constant(f64::NAN),
+        );
+
+        assert_eq!(
+            validate(&config)
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>(),
+            vec!["implicit[0].rule.lt[1].constant: Number NaN is not finite, and can't be written in rules"]
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Visitor IDs, for sticky rollouts to anonymous visitors
+//!
+//! Rank based rollouts and variants need a stable identifier, which anonymous
+//! visitors don't have. Adapters can give them one: when the request has no
+//! visitor cookie, the adapter mints a random ID, adds the cookie to the
+//! request before targeting, so rules like `rank(cookie("visitor_id")) < 10`
+//! see it straight away, and sets the cookie on the response. The visitor then
+//! gets the same features on later visits.
+
+use crate::{features::expression::get_cookie, request::Request};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// The visitor ID cookie
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Name of the cookie
+    #[serde(default = "default_cookie")]
+    pub cookie: String,
+    /// Domain the cookie is set for, e.g. `example.com` to share it between
+    /// subdomains, by default only the host of the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// Lifetime of the cookie in seconds, a year by default
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+    #[serde(default)]
+    pub same_site: SameSite,
+    /// Only send the cookie over HTTPS, always the case with `SameSite=None`
+    #[serde(default)]
+    pub secure: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            cookie: default_cookie(),
+            domain: None,
+            max_age: default_max_age(),
+            same_site: SameSite::default(),
+            secure: false,
+        }
+    }
+}
+
+fn default_cookie() -> String {
+    "visitor_id".to_owned()
+}
+
+fn default_max_age() -> u64 {
+    365 * 24 * 60 * 60
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SameSite {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        if !is_token(&self.cookie) {
+            bail!("Invalid visitor cookie name '{}'", self.cookie);
+        }
+        if let Some(domain) = &self.domain {
+            if !is_token(domain) {
+                bail!("Invalid visitor cookie domain '{}'", domain);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The visitor ID from the request's cookie, if it has one
+    pub fn id<'r>(&self, request: &'r Request) -> Option<&'r str> {
+        get_cookie(request, &self.cookie)
+            .ok()
+            .filter(|id| !id.is_empty())
+    }
+
+    /// Adds the cookie with a newly minted ID to the request
+    pub fn assign(&self, request: &mut Request, id: &str) {
+        request.add_header("cookie", self.cookie_pair(id));
+    }
+
+    /// The `name=id` pair, as sent in a `Cookie` header
+    pub fn cookie_pair(&self, id: &str) -> String {
+        format!("{}={}", self.cookie, id)
+    }
+
+    /// The value of the `Set-Cookie` response header for a newly minted ID
+    pub fn set_cookie(&self, id: &str) -> String {
+        set_cookie(
+            &self.cookie_pair(id),
+            self.domain.as_deref(),
+            Some(self.max_age),
+            self.same_site,
+            self.secure,
+        )
+    }
+}
+
+/// Whether the text can be used as a cookie name or attribute, without
+/// separators or white space
+pub(crate) fn is_token(text: &str) -> bool {
+    !text.is_empty()
+        && !text.contains(|c: char| c.is_whitespace() || c.is_control() || ";,=".contains(c))
+}
+
+/// A `Set-Cookie` header value for the whole site, `Secure` with `SameSite=None`
+pub(crate) fn set_cookie(
+    pair: &str,
+    domain: Option<&str>,
+    max_age: Option<u64>,
+    same_site: SameSite,
+    secure: bool,
+) -> String {
+    let mut cookie = format!("{}; Path=/", pair);
+    if let Some(max_age) = max_age {
+        cookie.push_str(&format!("; Max-Age={}", max_age));
+    }
+    if let Some(domain) = domain {
+        cookie.push_str(&format!("; Domain={}", domain));
+    }
+    cookie.push_str(match same_site {
+        SameSite::Strict => "; SameSite=Strict",
+        SameSite::Lax => "; SameSite=Lax",
+        SameSite::None => "; SameSite=None",
+    });
+    if secure || same_site == SameSite::None {
+        cookie.push_str("; Secure");
+    }
+
+    cookie
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::features::expression::Num;
+    use serde_json::json;
+    use test_case::test_case;
+
+    #[test_case(json!({}), "visitor_id=abc; Path=/; Max-Age=31536000; SameSite=Lax" ; "defaults")]
+    #[test_case(json!({ "cookie": "vid", "domain": "example.com", "max_age": 60, "same_site": "strict", "secure": true }), "vid=abc; Path=/; Max-Age=60; Domain=example.com; SameSite=Strict; Secure" ; "all set")]
+    #[test_case(json!({ "same_site": "none" }), "visitor_id=abc; Path=/; Max-Age=31536000; SameSite=None; Secure" ; "same site none")]
+    fn formats_set_cookie(config: serde_json::Value, expected: &str) {
+        let config: Config = serde_json::from_value(config).unwrap();
+
+        assert_eq!(config.set_cookie("abc"), expected);
+    }
+
+    #[test]
+    fn ranks_by_an_assigned_id() {
+        let config = Config::default();
+        let rank = r#"rank(cookie("visitor_id"))"#.parse::<Num>().unwrap();
+        let mut request = Request::new().with_header("cookie", "session=1");
+        assert_eq!(config.id(&request), None);
+
+        config.assign(&mut request, "abc");
+
+        assert_eq!(config.id(&request), Some("abc"));
+        assert_eq!(
+            rank.eval(&request).unwrap(),
+            rank.eval(&Request::new().with_header("cookie", "visitor_id=abc"))
+                .unwrap()
+        );
+    }
+
+    #[test_case(json!({ "cookie": "" }), "Invalid visitor cookie name ''" ; "empty name")]
+    #[test_case(json!({ "cookie": "a b" }), "Invalid visitor cookie name 'a b'" ; "space in name")]
+    #[test_case(json!({ "domain": "example.com; Secure" }), "Invalid visitor cookie domain 'example.com; Secure'" ; "domain injection")]
+    fn rejects_invalid_cookies(config: serde_json::Value, error: &str) {
+        let config: Config = serde_json::from_value(config).unwrap();
+
+        assert_eq!(config.validate().unwrap_err().to_string(), error);
+    }
+}
@@ -1 +1,2 @@
 pub mod features;
+pub mod request;
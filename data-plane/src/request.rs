@@ -0,0 +1,367 @@
+//! The request being targeted
+//!
+//! Header names are case-insensitive and a header can be repeated, so all of
+//! its values are kept, in order. HTTP/2 pseudo-headers, like `:path` and
+//! `:authority`, are headers too. Connection attributes, like the address of
+//! the peer, are kept separately, named as in Envoy, e.g. `source.address`.
+//! Adapters and expressions can also attach typed extensions.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    any::{Any, TypeId},
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    iter::FromIterator,
+};
+
+/// Connection attribute with the address of the peer, e.g. `10.0.0.2:51234`
+pub const SOURCE_ADDRESS: &str = "source.address";
+
+/// Which value of a repeated header to use
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Select {
+    #[default]
+    First,
+    Last,
+    /// All values, see `Request::joined_header`
+    Joined,
+}
+
+/// Headers carrying credentials, left out of the debug output of a request
+const CREDENTIALS: &[&str] = &["authorization", "proxy-authorization", "cookie"];
+
+#[derive(Default)]
+pub struct Request<'a> {
+    /// Headers with lowercase names, in the order they were received
+    headers: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+    attributes: HashMap<Cow<'a, str>, Cow<'a, str>>,
+    extensions: Extensions,
+}
+
+impl<'a> Request<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a header, keeping any previous values
+    pub fn add_header<N, V>(&mut self, name: N, value: V)
+    where
+        N: Into<Cow<'a, str>>,
+        V: Into<Cow<'a, str>>,
+    {
+        let name = name.into();
+        let name = if name.bytes().any(|b| b.is_ascii_uppercase()) {
+            Cow::Owned(name.to_ascii_lowercase())
+        } else {
+            name
+        };
+
+        self.headers.push((name, value.into()));
+    }
+
+    /// Builder style `add_header`
+    pub fn with_header<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: Into<Cow<'a, str>>,
+        V: Into<Cow<'a, str>>,
+    {
+        self.add_header(name, value);
+        self
+    }
+
+    /// The first value of a header
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_ref())
+    }
+
+    /// The last value of a header
+    pub fn last_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .rev()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_ref())
+    }
+
+    /// All values of a header, in order
+    pub fn header_values<'r>(&'r self, name: &'r str) -> impl Iterator<Item = &'r str> + 'r {
+        self.headers
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_ref())
+    }
+
+    /// All values of a header joined into one, as if they were sent as a
+    /// single comma separated list (or `; ` separated, for cookies)
+    pub fn joined_header(&self, name: &str) -> Option<String> {
+        let separator = if name.eq_ignore_ascii_case("cookie") {
+            "; "
+        } else {
+            ", "
+        };
+        let values: Vec<&str> = self.header_values(name).collect();
+
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(separator))
+        }
+    }
+
+    /// All headers in order, with lowercase names
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(n, v)| (n.as_ref(), v.as_ref()))
+    }
+
+    pub fn method(&self) -> Option<&str> {
+        self.header(":method")
+    }
+
+    pub fn scheme(&self) -> Option<&str> {
+        self.header(":scheme")
+    }
+
+    pub fn authority(&self) -> Option<&str> {
+        self.header(":authority")
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.header(":path")
+    }
+
+    pub fn set_attribute<N, V>(&mut self, name: N, value: V)
+    where
+        N: Into<Cow<'a, str>>,
+        V: Into<Cow<'a, str>>,
+    {
+        self.attributes.insert(name.into(), value.into());
+    }
+
+    /// Builder style `set_attribute`
+    pub fn with_attribute<N, V>(mut self, name: N, value: V) -> Self
+    where
+        N: Into<Cow<'a, str>>,
+        V: Into<Cow<'a, str>>,
+    {
+        self.set_attribute(name, value);
+        self
+    }
+
+    /// A connection attribute, e.g. `source.address`
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(|v| v.as_ref())
+    }
+
+    /// The first value of a header or, when there is none, a connection
+    /// attribute of the same name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.header(name).or_else(|| self.attribute(name))
+    }
+
+    /// Like `get`, selecting among the values of a repeated header
+    pub fn select(&self, name: &str, select: Select) -> Option<Cow<'_, str>> {
+        let header = match select {
+            Select::First => self.header(name).map(Cow::Borrowed),
+            Select::Last => self.last_header(name).map(Cow::Borrowed),
+            Select::Joined => self.joined_header(name).map(Cow::Owned),
+        };
+
+        header.or_else(|| self.attribute(name).map(Cow::Borrowed))
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+}
+
+/// Redacts credentials, so requests can be logged
+impl fmt::Debug for Request<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let headers: Vec<_> = self.headers.iter().map(|(n, v)| redact(n, v)).collect();
+        let attributes: HashMap<_, _> = self.attributes.iter().map(|(n, v)| redact(n, v)).collect();
+
+        f.debug_struct("Request")
+            .field("headers", &headers)
+            .field("attributes", &attributes)
+            .field("extensions", &self.extensions)
+            .finish()
+    }
+}
+
+fn redact<'r>(name: &'r str, value: &'r str) -> (&'r str, &'r str) {
+    if CREDENTIALS.contains(&name) {
+        (name, "<redacted>")
+    } else {
+        (name, value)
+    }
+}
+
+/// Compatibility with the map of headers used before, which has a single
+/// value per header
+impl<'a> From<&HashMap<&'a str, &'a str>> for Request<'a> {
+    fn from(map: &HashMap<&'a str, &'a str>) -> Self {
+        map.iter().map(|(n, v)| (*n, *v)).collect()
+    }
+}
+
+impl<'a> From<HashMap<&'a str, &'a str>> for Request<'a> {
+    fn from(map: HashMap<&'a str, &'a str>) -> Self {
+        map.into_iter().collect()
+    }
+}
+
+impl<'a, N, V> FromIterator<(N, V)> for Request<'a>
+where
+    N: Into<Cow<'a, str>>,
+    V: Into<Cow<'a, str>>,
+{
+    fn from_iter<I: IntoIterator<Item = (N, V)>>(headers: I) -> Self {
+        let mut request = Self::new();
+        for (name, value) in headers {
+            request.add_header(name, value);
+        }
+
+        request
+    }
+}
+
+/// Values attached to a request, one per type
+#[derive(Default)]
+pub struct Extensions(HashMap<TypeId, Box<dyn Any + Send + Sync>>);
+
+impl Extensions {
+    /// Attaches a value, returning the previous value of the same type
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.0
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok().map(|b| *b))
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.0
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.0
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok().map(|b| *b))
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Extensions({})", self.0.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request() -> Request<'static> {
+        Request::new()
+            .with_header(":path", "/cart")
+            .with_header("Accept", "text/html")
+            .with_header("x-forwarded-for", "203.0.113.7")
+            .with_header("X-Forwarded-For", "10.0.0.2")
+            .with_header("cookie", "a=1")
+            .with_header("cookie", "b=2")
+            .with_attribute(SOURCE_ADDRESS, "10.0.0.3:51234")
+    }
+
+    #[test]
+    fn finds_headers_regardless_of_case() {
+        let request = request();
+
+        assert_eq!(request.header("accept"), Some("text/html"));
+        assert_eq!(request.header("ACCEPT"), Some("text/html"));
+        assert_eq!(request.path(), Some("/cart"));
+        assert_eq!(request.header("x-nope"), None);
+    }
+
+    #[test]
+    fn keeps_repeated_headers() {
+        let request = request();
+
+        assert_eq!(
+            request.header_values("x-forwarded-for").collect::<Vec<_>>(),
+            vec!["203.0.113.7", "10.0.0.2"]
+        );
+        assert_eq!(request.header("x-forwarded-for"), Some("203.0.113.7"));
+        assert_eq!(request.last_header("x-forwarded-for"), Some("10.0.0.2"));
+        assert_eq!(
+            request.joined_header("x-forwarded-for"),
+            Some("203.0.113.7, 10.0.0.2".into())
+        );
+        assert_eq!(request.joined_header("cookie"), Some("a=1; b=2".into()));
+    }
+
+    #[test]
+    fn selects_header_values() {
+        let request = request();
+        let select = |name, select| request.select(name, select).map(|v| v.into_owned());
+
+        assert_eq!(select("cookie", Select::First), Some("a=1".into()));
+        assert_eq!(select("cookie", Select::Last), Some("b=2".into()));
+        assert_eq!(select("cookie", Select::Joined), Some("a=1; b=2".into()));
+        assert_eq!(
+            select(SOURCE_ADDRESS, Select::Joined),
+            Some("10.0.0.3:51234".into())
+        );
+        assert_eq!(select("x-nope", Select::Last), None);
+    }
+
+    #[test]
+    fn falls_back_to_connection_attributes() {
+        let request = request();
+
+        assert_eq!(request.get(SOURCE_ADDRESS), Some("10.0.0.3:51234"));
+        assert_eq!(request.header(SOURCE_ADDRESS), None);
+    }
+
+    #[test]
+    fn converts_from_maps() {
+        let map: HashMap<&str, &str> = [("Host", "example.com")].iter().cloned().collect();
+
+        assert_eq!(Request::from(&map).header("host"), Some("example.com"));
+    }
+
+    #[test]
+    fn stores_extensions_by_type() {
+        #[derive(Debug, PartialEq)]
+        struct UserId(u32);
+
+        let mut request = request();
+        assert_eq!(request.extensions_mut().insert(UserId(1)), None);
+        assert_eq!(request.extensions_mut().insert(UserId(7)), Some(UserId(1)));
+
+        assert_eq!(request.extensions().get::<UserId>(), Some(&UserId(7)));
+        assert_eq!(request.extensions().get::<String>(), None);
+    }
+
+    #[test]
+    fn redacts_credentials_in_debug_output() {
+        let request = request()
+            .with_header("Authorization", "Bearer secret")
+            .with_attribute("cookie", "c=3");
+
+        let debug = format!("{:?}", request);
+
+        assert!(debug.contains("text/html"), "{}", debug);
+        for secret in &["a=1", "b=2", "c=3", "secret"] {
+            assert!(!debug.contains(secret), "{}", debug);
+        }
+        assert!(debug.contains(r#"("cookie", "<redacted>")"#), "{}", debug);
+    }
+}
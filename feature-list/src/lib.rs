@@ -0,0 +1,74 @@
+//! The format of the feature list the proxy sets on requests
+//!
+//! Features are listed as `name` or, for multivariate features,
+//! `name=variant`, separated by spaces. The proxy adapters write the list and
+//! services read it back with the SDK, so both share this crate.
+
+/// The header the feature list is set in, unless configured otherwise
+pub const DEFAULT_HEADER: &str = "x-features";
+
+/// Splits an entry of a feature list into the feature name and its variant
+///
+/// Features are listed as `name` or, for multivariate features, `name=variant`.
+pub fn parse_feature(feature: &str) -> (&str, Option<&str>) {
+    let mut parts = feature.splitn(2, '=');
+    let name = parts.next().unwrap_or_default();
+
+    (name, parts.next())
+}
+
+/// Whether the feature is enabled in a feature list, in any variant
+pub fn is_enabled(features: &str, name: &str) -> bool {
+    features
+        .split_whitespace()
+        .any(|f| parse_feature(f).0 == name)
+}
+
+/// The variant of a multivariate feature in a feature list, if it is enabled
+pub fn variant<'a>(features: &'a str, name: &str) -> Option<&'a str> {
+    features
+        .split_whitespace()
+        .map(parse_feature)
+        .find(|(n, _)| *n == name)
+        .and_then(|(_, variant)| variant)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_features() {
+        assert_eq!(parse_feature("beta"), ("beta", None));
+        assert_eq!(
+            parse_feature("checkout=express"),
+            ("checkout", Some("express"))
+        );
+        assert_eq!(parse_feature("checkout=a=b"), ("checkout", Some("a=b")));
+    }
+
+    #[test]
+    fn reads_variants() {
+        let features = "a checkout=express b";
+
+        assert!(is_enabled(features, "checkout"));
+        assert_eq!(variant(features, "checkout"), Some("express"));
+        assert!(is_enabled("a checkout b", "checkout"));
+        assert_eq!(variant("a checkout b", "checkout"), None);
+        assert!(!is_enabled("a checkout-v2=x", "checkout"));
+        assert!(!is_enabled("", "checkout"));
+    }
+
+    #[test]
+    fn first_variant_wins() {
+        assert_eq!(
+            variant("checkout=express checkout=classic", "checkout"),
+            Some("express")
+        );
+    }
+
+    #[test]
+    fn sets_the_documented_header() {
+        assert_eq!(DEFAULT_HEADER, "x-features");
+    }
+}
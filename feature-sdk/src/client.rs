@@ -0,0 +1,218 @@
+//! Middleware forwarding the current feature set to upstream services
+//!
+//! Outgoing requests made while handling a request get the `x-features`
+//! header of the [current](FeatureSet::current) feature set, unless they set
+//! the header themselves.
+//!
+//! For hyper, and other clients built on tower services, wrap the client in a
+//! [`PropagateLayer`]:
+//!
+//! ```ignore
+//! let client = ServiceBuilder::new()
+//!     .layer(PropagateLayer::new())
+//!     .service(Client::builder(TokioExecutor::new()).build_http());
+//! ```
+//!
+//! For reqwest, with the `reqwest` feature, add [`PropagateMiddleware`] to a
+//! `reqwest_middleware` client:
+//!
+//! ```ignore
+//! let client = ClientBuilder::new(reqwest::Client::new())
+//!     .with(PropagateMiddleware::new())
+//!     .build();
+//! ```
+//!
+//! For surf, with the `surf` feature, use `Client::new().with(PropagateMiddleware::new())`.
+
+use crate::{FeatureSet, HEADER};
+use http::HeaderName;
+
+#[cfg(feature = "tower")]
+pub use self::layer::{Propagate, PropagateLayer};
+
+/// Adds the current feature set to requests of a reqwest or surf client
+#[cfg(any(feature = "reqwest", feature = "surf"))]
+#[derive(Debug, Clone)]
+pub struct PropagateMiddleware {
+    header: HeaderName,
+}
+
+#[cfg(any(feature = "reqwest", feature = "surf"))]
+impl PropagateMiddleware {
+    /// Forwards the features in the `x-features` header
+    pub fn new() -> Self {
+        Self::header(HeaderName::from_static(HEADER))
+    }
+
+    /// Forwards the features in the named header
+    pub fn header(header: HeaderName) -> Self {
+        Self { header }
+    }
+}
+
+#[cfg(any(feature = "reqwest", feature = "surf"))]
+impl Default for PropagateMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "tower")]
+mod layer {
+    use super::*;
+    use http::Request;
+    use std::task::{Context, Poll};
+    use tower_layer::Layer;
+    use tower_service::Service;
+
+    /// Applies [`Propagate`] to a client
+    #[derive(Debug, Clone)]
+    pub struct PropagateLayer {
+        header: HeaderName,
+    }
+
+    impl PropagateLayer {
+        /// Forwards the features in the `x-features` header
+        pub fn new() -> Self {
+            Self::header(HeaderName::from_static(HEADER))
+        }
+
+        /// Forwards the features in the named header
+        pub fn header(header: HeaderName) -> Self {
+            Self { header }
+        }
+    }
+
+    impl Default for PropagateLayer {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<S> Layer<S> for PropagateLayer {
+        type Service = Propagate<S>;
+
+        fn layer(&self, inner: S) -> Self::Service {
+            Propagate {
+                inner,
+                header: self.header.clone(),
+            }
+        }
+    }
+
+    /// Adds the current feature set to the requests of a client
+    #[derive(Debug, Clone)]
+    pub struct Propagate<S> {
+        inner: S,
+        header: HeaderName,
+    }
+
+    impl<S, B> Service<Request<B>> for Propagate<S>
+    where
+        S: Service<Request<B>>,
+    {
+        type Response = S::Response;
+        type Error = S::Error;
+        type Future = S::Future;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, mut request: Request<B>) -> Self::Future {
+            if let Some(features) = FeatureSet::current() {
+                features.insert_into(request.headers_mut(), &self.header);
+            }
+
+            self.inner.call(request)
+        }
+    }
+}
+
+#[cfg(feature = "reqwest")]
+#[async_trait::async_trait]
+impl reqwest_middleware::Middleware for PropagateMiddleware {
+    async fn handle(
+        &self,
+        mut request: reqwest_middleware::reqwest::Request,
+        extensions: &mut http::Extensions,
+        next: reqwest_middleware::Next<'_>,
+    ) -> reqwest_middleware::Result<reqwest_middleware::reqwest::Response> {
+        if let Some(features) = FeatureSet::current() {
+            features.insert_into(request.headers_mut(), &self.header);
+        }
+
+        next.run(request, extensions).await
+    }
+}
+
+/// surf has its own header types, so the header is set by name
+#[cfg(feature = "surf")]
+#[surf::utils::async_trait]
+impl surf::middleware::Middleware for PropagateMiddleware {
+    async fn handle(
+        &self,
+        mut request: surf::Request,
+        client: surf::Client,
+        next: surf::middleware::Next<'_>,
+    ) -> surf::Result<surf::Response> {
+        let name = self.header.as_str();
+        if let Some(features) = FeatureSet::current() {
+            if !features.is_empty() && request.header(name).is_none() {
+                request.insert_header(name, features.to_string());
+            }
+        }
+
+        next.run(request, client).await
+    }
+}
+
+#[cfg(all(test, feature = "tower"))]
+mod test {
+    use super::*;
+    use http::Request;
+    use pretty_assertions::assert_eq;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+    use tower_layer::Layer;
+
+    async fn upstream(request: Request<()>) -> Result<Option<String>, Infallible> {
+        Ok(request
+            .headers()
+            .get(HEADER)
+            .map(|value| value.to_str().unwrap().to_owned()))
+    }
+
+    async fn call(request: Request<()>) -> Option<String> {
+        let client = PropagateLayer::new().layer(service_fn(upstream));
+
+        client.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn forwards_the_current_feature_set() {
+        let features = FeatureSet::parse("beta checkout=express");
+
+        let forwarded = features.scope(call(Request::new(()))).await;
+
+        assert_eq!(forwarded.as_deref(), Some("beta checkout=express"));
+    }
+
+    #[tokio::test]
+    async fn keeps_headers_set_by_the_caller() {
+        let features = FeatureSet::parse("beta");
+        let request = Request::builder()
+            .header(HEADER, "dark-mode")
+            .body(())
+            .unwrap();
+
+        let forwarded = features.scope(call(request)).await;
+
+        assert_eq!(forwarded.as_deref(), Some("dark-mode"));
+    }
+
+    #[tokio::test]
+    async fn forwards_nothing_outside_a_request() {
+        assert_eq!(call(Request::new(())).await, None);
+    }
+}
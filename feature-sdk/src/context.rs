@@ -0,0 +1,50 @@
+//! The feature set of the request being handled, as a task-local
+//!
+//! Task-locals are not inherited by spawned tasks, so work spawned while
+//! handling a request needs to be wrapped in [`FeatureSet::scope`] to forward
+//! the features.
+
+use crate::FeatureSet;
+use std::future::Future;
+use tokio::task::futures::TaskLocalFuture;
+
+tokio::task_local! {
+    static CURRENT: FeatureSet;
+}
+
+impl FeatureSet {
+    /// The feature set of the request being handled, if there is one
+    pub fn current() -> Option<FeatureSet> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    /// Runs the future with this as the current feature set
+    pub fn scope<F: Future>(self, future: F) -> TaskLocalFuture<FeatureSet, F> {
+        CURRENT.scope(self, future)
+    }
+
+    /// Runs the closure with this as the current feature set
+    pub fn sync_scope<R>(self, f: impl FnOnce() -> R) -> R {
+        CURRENT.sync_scope(self, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn sets_the_current_feature_set() {
+        let features = FeatureSet::parse("beta");
+
+        assert_eq!(FeatureSet::current(), None);
+        let current = features.clone().scope(async { FeatureSet::current() });
+        assert_eq!(current.await, Some(features.clone()));
+        assert_eq!(
+            features.clone().sync_scope(FeatureSet::current),
+            Some(features)
+        );
+        assert_eq!(FeatureSet::current(), None);
+    }
+}
@@ -0,0 +1,187 @@
+//! Reading the targeted features in services, and forwarding them upstream
+//!
+//! The proxy decides which features are enabled for a request and lists them
+//! in the `x-features` header, as `name` or, for multivariate features,
+//! `name=variant`, separated by spaces. Every service in the request path needs
+//! to read the header, and forward it to the services it calls, so the feature
+//! set is the same throughout.
+//!
+//! [`FeatureSet`] parses the header. The [`server`] middleware does so for each
+//! request, stores the set in the request extensions and makes it the
+//! [current](FeatureSet::current) feature set while the request is handled.
+//! The [`client`] middleware adds the current feature set to outgoing
+//! requests, so the header is forwarded without passing it around.
+
+mod context;
+
+#[cfg(any(feature = "tower", feature = "reqwest", feature = "surf"))]
+pub mod client;
+#[cfg(feature = "tower")]
+pub mod server;
+
+use http::{header::AsHeaderName, HeaderMap, HeaderName, HeaderValue};
+use std::fmt;
+
+/// The header the proxy lists the enabled features in
+pub const HEADER: &str = feature_list::DEFAULT_HEADER;
+
+/// The features enabled for a request
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FeatureSet {
+    /// The feature list, without repeated features or extra whitespace
+    features: String,
+}
+
+impl FeatureSet {
+    /// Parses a feature list, e.g. `beta checkout=express`
+    ///
+    /// Features listed more than once keep their first variant.
+    pub fn parse(features: &str) -> Self {
+        let mut set = Self::default();
+        for feature in features.split_whitespace() {
+            let (name, _) = feature_list::parse_feature(feature);
+            if !set.is_enabled(name) {
+                if !set.features.is_empty() {
+                    set.features.push(' ');
+                }
+                set.features.push_str(feature);
+            }
+        }
+
+        set
+    }
+
+    /// The features listed in the `x-features` headers
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        Self::from_header(headers, HEADER)
+    }
+
+    /// The features listed in the named headers, when the proxy is configured
+    /// with a different header name
+    ///
+    /// Repeated headers are combined, values which aren't valid text are
+    /// ignored.
+    pub fn from_header<K: AsHeaderName>(headers: &HeaderMap, name: K) -> Self {
+        let features: Vec<&str> = headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+
+        Self::parse(&features.join(" "))
+    }
+
+    /// Whether the feature is enabled, in any variant
+    pub fn is_enabled(&self, name: &str) -> bool {
+        feature_list::is_enabled(&self.features, name)
+    }
+
+    /// The variant of a multivariate feature, if it is enabled
+    pub fn variant(&self, name: &str) -> Option<&str> {
+        feature_list::variant(&self.features, name)
+    }
+
+    /// Names and variants of the enabled features
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.features
+            .split_whitespace()
+            .map(feature_list::parse_feature)
+    }
+
+    pub fn len(&self) -> usize {
+        self.features.split_whitespace().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.features.is_empty()
+    }
+
+    /// Sets the feature list as the named header, unless the headers have it
+    /// already
+    ///
+    /// Nothing is set for an empty feature set, or one with names which can't
+    /// be sent in a header.
+    pub fn insert_into(&self, headers: &mut HeaderMap, name: &HeaderName) {
+        if self.is_empty() || headers.contains_key(name) {
+            return;
+        }
+        if let Ok(value) = HeaderValue::from_str(&self.to_string()) {
+            headers.insert(name.clone(), value);
+        }
+    }
+}
+
+/// Formats the feature list as sent in the header
+impl fmt::Display for FeatureSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.features)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    #[test_case("beta checkout=express", "beta", true ; "plain")]
+    #[test_case("beta checkout=express", "checkout", true ; "variant")]
+    #[test_case("beta checkout=express", "check", false ; "prefix")]
+    #[test_case("beta checkout=express", "express", false ; "variant name")]
+    #[test_case("", "beta", false ; "empty")]
+    fn finds_enabled_features(features: &str, name: &str, expected: bool) {
+        assert_eq!(FeatureSet::parse(features).is_enabled(name), expected);
+    }
+
+    #[test_case("beta checkout=express", "checkout", Some("express") ; "variant")]
+    #[test_case("beta checkout=express", "beta", None ; "no variant")]
+    #[test_case("checkout=express checkout=classic", "checkout", Some("express") ; "first wins")]
+    #[test_case("checkout=a=b", "checkout", Some("a=b") ; "equals in variant")]
+    fn finds_variants(features: &str, name: &str, expected: Option<&str>) {
+        assert_eq!(FeatureSet::parse(features).variant(name), expected);
+    }
+
+    #[test]
+    fn reads_repeated_headers() {
+        let mut headers = HeaderMap::new();
+        headers.append(HEADER, HeaderValue::from_static("beta  checkout=express"));
+        headers.append(HEADER, HeaderValue::from_static("dark-mode beta"));
+        headers.append("x-other", HeaderValue::from_static("ignored"));
+
+        let features = FeatureSet::from_headers(&headers);
+
+        assert_eq!(
+            features.iter().collect::<Vec<_>>(),
+            vec![
+                ("beta", None),
+                ("checkout", Some("express")),
+                ("dark-mode", None)
+            ]
+        );
+        assert_eq!(features.to_string(), "beta checkout=express dark-mode");
+    }
+
+    #[test]
+    fn reads_the_header_the_proxy_sets() {
+        assert_eq!(HEADER, "x-features");
+    }
+
+    #[test]
+    fn inserts_into_headers() {
+        let name = HeaderName::from_static(HEADER);
+        let features = FeatureSet::parse("beta checkout=express");
+
+        let mut empty = HeaderMap::new();
+        features.insert_into(&mut empty, &name);
+        assert_eq!(empty[HEADER], "beta checkout=express");
+
+        let mut existing = HeaderMap::new();
+        existing.insert(HEADER, HeaderValue::from_static("dark-mode"));
+        features.insert_into(&mut existing, &name);
+        assert_eq!(existing[HEADER], "dark-mode");
+
+        let mut none = HeaderMap::new();
+        FeatureSet::default().insert_into(&mut none, &name);
+        assert!(none.is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+//! Tower middleware reading the feature set of incoming requests
+//!
+//! Works with any server built on tower services, e.g. hyper, axum or tonic:
+//!
+//! ```ignore
+//! let service = ServiceBuilder::new()
+//!     .layer(FeaturesLayer::new())
+//!     .service(handler);
+//! ```
+//!
+//! Handlers can then find the [`FeatureSet`] in the request extensions, or call
+//! [`FeatureSet::current`].
+
+use crate::{FeatureSet, HEADER};
+use http::{HeaderName, Request};
+use std::task::{Context, Poll};
+use tokio::task::futures::TaskLocalFuture;
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Applies [`Features`] to a service
+#[derive(Debug, Clone)]
+pub struct FeaturesLayer {
+    header: HeaderName,
+}
+
+impl FeaturesLayer {
+    /// Reads the features from the `x-features` header
+    pub fn new() -> Self {
+        Self::header(HeaderName::from_static(HEADER))
+    }
+
+    /// Reads the features from the named header
+    pub fn header(header: HeaderName) -> Self {
+        Self { header }
+    }
+}
+
+impl Default for FeaturesLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Layer<S> for FeaturesLayer {
+    type Service = Features<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Features {
+            inner,
+            header: self.header.clone(),
+        }
+    }
+}
+
+/// Parses the feature set of each request, stores it in the request extensions
+/// and makes it the current feature set while the inner service handles the
+/// request
+#[derive(Debug, Clone)]
+pub struct Features<S> {
+    inner: S,
+    header: HeaderName,
+}
+
+impl<S, B> Service<Request<B>> for Features<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = TaskLocalFuture<FeatureSet, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        let features = FeatureSet::from_header(request.headers(), &self.header);
+        request.extensions_mut().insert(features.clone());
+
+        let inner = &mut self.inner;
+        let future = features.clone().sync_scope(|| inner.call(request));
+
+        features.scope(future)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    async fn handler(request: Request<()>) -> Result<String, Infallible> {
+        let stored = request.extensions().get::<FeatureSet>().cloned();
+        let current = FeatureSet::current();
+        assert_eq!(stored, current);
+
+        Ok(current.unwrap_or_default().to_string())
+    }
+
+    #[tokio::test]
+    async fn reads_the_feature_set() {
+        let service = FeaturesLayer::new().layer(service_fn(handler));
+        let request = Request::builder()
+            .header("x-features", "beta checkout=express")
+            .body(())
+            .unwrap();
+
+        let response = service.oneshot(request).await.unwrap();
+
+        assert_eq!(response, "beta checkout=express");
+    }
+
+    #[tokio::test]
+    async fn reads_a_configured_header() {
+        let service =
+            FeaturesLayer::header(HeaderName::from_static("x-flags")).layer(service_fn(handler));
+        let request = Request::builder()
+            .header("x-features", "ignored")
+            .header("x-flags", "beta")
+            .body(())
+            .unwrap();
+
+        let response = service.oneshot(request).await.unwrap();
+
+        assert_eq!(response, "beta");
+    }
+}
@@ -4,10 +4,11 @@ extern crate serde_derive;
 extern crate serde_json;
 
 use anyhow::anyhow;
+use data_plane::features;
 use log::{error, info};
 use roperator::prelude::*;
 use serde_json::value::Value;
-use std::{collections::BTreeMap, time::Duration};
+use std::{collections::BTreeMap, fmt, time::Duration};
 
 /// Name of our operator, which is automatically added as a label value in all of the child resources we create
 const OPERATOR_NAME: &str = "feature-targeting";
@@ -51,6 +52,14 @@ pub struct FeatureTargetSpec {
     pub configuration: String,
 }
 
+/// The part of the filter configuration passed through to the proxies, which
+/// the operator checks before creating the filter
+#[derive(Deserialize, Debug)]
+pub struct FilterConfiguration {
+    #[serde(flatten)]
+    pub features: features::Config,
+}
+
 /// Represents the status of a parent FeatureTargetConfig instance
 #[derive(Deserialize, Clone, Debug, PartialEq)]
 pub struct FeatureTargetStatus {
@@ -123,8 +132,39 @@ fn get_current_status_message(request: &SyncRequest) -> String {
         .unwrap_or_else(|| "Waiting for Filter to be initialized".to_owned())
 }
 
+/// Refuses configurations the filter would not be able to load, so that the
+/// problems show up in the status of the parent instead of the proxy logs
+fn validate_configuration(configuration: &str) -> Result<(), Error> {
+    let config: FilterConfiguration = serde_json::from_str(configuration)?;
+    let problems = features::validate(&config.features);
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    Err(Box::new(InvalidConfiguration(problems)))
+}
+
+/// Problems found validating the configuration of a parent
+#[derive(Debug)]
+struct InvalidConfiguration(Vec<features::validation::Problem>);
+
+impl fmt::Display for InvalidConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Invalid configuration")?;
+        for (i, problem) in self.0.iter().enumerate() {
+            write!(f, "{} {}", if i == 0 { ":" } else { ";" }, problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvalidConfiguration {}
+
 fn get_desired_children(request: &SyncRequest) -> Result<Vec<Value>, Error> {
     let custom_resource: FeatureTargetConfig = request.deserialize_parent()?;
+    validate_configuration(&custom_resource.spec.configuration)?;
 
     let filter = json!({
       "apiVersion": ENVOY_FILTER_TYPE.api_version,
@@ -0,0 +1,176 @@
+//! Mints links which enable features with a signed preview token
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, Utc};
+use data_plane::features::{
+    expression::time,
+    preview::{self, Algorithm, Claims, Key, SigningKey},
+};
+use serde_json::json;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(about = "Mints links which enable features with a signed preview token")]
+struct Options {
+    /// The page to link to
+    url: Option<String>,
+    /// Features to enable, as `name` or `name=variant`, or to disable, as
+    /// `-name` (after `--`)
+    features: Vec<String>,
+    /// Signing algorithm, `hs256` or `ed25519`
+    #[structopt(long, default_value = "hs256", parse(try_from_str = parse_algorithm))]
+    alg: Algorithm,
+    /// The shared secret, or the Ed25519 private key, base64url encoded
+    #[structopt(long, env = "PREVIEW_KEY", hide_env_values = true)]
+    key: String,
+    /// Identifies the key in the filter configuration
+    #[structopt(long)]
+    kid: Option<String>,
+    /// How long the link works for, e.g. `2h` or `7d`
+    #[structopt(long, default_value = "1d")]
+    expires_in: String,
+    /// The query parameter carrying the token
+    #[structopt(long, default_value = "preview")]
+    param: String,
+    /// Print the key to add to the filter configuration instead of a link
+    #[structopt(long)]
+    print_key: bool,
+}
+
+fn parse_algorithm(text: &str) -> Result<Algorithm> {
+    serde_json::from_value(json!(text)).map_err(|_| anyhow!("Unknown algorithm '{}'", text))
+}
+
+fn main() -> Result<()> {
+    let options = Options::from_args();
+    let key = SigningKey::decode(options.alg, &options.key)?;
+
+    if options.print_key {
+        let config = Key {
+            kid: options.kid,
+            alg: options.alg,
+            key: key.verifying_key(),
+        };
+        println!("{}", serde_json::to_string_pretty(&config)?);
+
+        return Ok(());
+    }
+
+    let url = match options.url {
+        Some(url) if !options.features.is_empty() => url,
+        _ => bail!("A URL and at least one feature are needed"),
+    };
+    let claims = claims(
+        options.features,
+        &options.expires_in,
+        options.kid,
+        Utc::now(),
+    )?;
+    let token = preview::mint(&claims, &key)?;
+
+    println!("{}", link(&url, &options.param, &token));
+
+    Ok(())
+}
+
+/// Claims of a token expiring `expires_in` after `now`
+fn claims(
+    features: Vec<String>,
+    expires_in: &str,
+    kid: Option<String>,
+    now: DateTime<Utc>,
+) -> Result<Claims> {
+    let expires_in = time::parse_duration(expires_in)?;
+
+    Ok(Claims {
+        features,
+        exp: (now + Duration::seconds(expires_in as i64)).timestamp(),
+        kid,
+    })
+}
+
+/// Adds the token to the query of the URL
+fn link(url: &str, param: &str, token: &str) -> String {
+    let (url, fragment) = match url.find('#') {
+        Some(i) => url.split_at(i),
+        None => (url, ""),
+    };
+    let separator = if url.contains('?') { '&' } else { '?' };
+
+    // tokens are base64url, which needs no escaping in a query
+    format!("{}{}{}={}{}", url, separator, param, token, fragment)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn adds_the_token_to_the_query() {
+        let cases = [
+            (
+                "https://shop.example/",
+                "https://shop.example/?preview=t0k3n",
+            ),
+            (
+                "https://shop.example/cart?currency=gbp",
+                "https://shop.example/cart?currency=gbp&preview=t0k3n",
+            ),
+            (
+                "https://shop.example/cart#summary",
+                "https://shop.example/cart?preview=t0k3n#summary",
+            ),
+            (
+                "https://shop.example/cart?currency=gbp#summary?x",
+                "https://shop.example/cart?currency=gbp&preview=t0k3n#summary?x",
+            ),
+            (
+                "https://shop.example/#/cart?tab=1",
+                "https://shop.example/?preview=t0k3n#/cart?tab=1",
+            ),
+        ];
+
+        for (url, expected) in &cases {
+            assert_eq!(link(url, "preview", "t0k3n"), *expected, "{}", url);
+        }
+    }
+
+    #[test]
+    fn tokens_expire_after_the_given_duration() {
+        let now = Utc.with_ymd_and_hms(2026, 10, 17, 9, 0, 0).unwrap();
+        let key = SigningKey::decode(Algorithm::Hs256, "c2VjcmV0LXNpZ25pbmcta2V5").unwrap();
+        let verifier = preview::Config {
+            from: vec![],
+            keys: vec![Key {
+                kid: None,
+                alg: Algorithm::Hs256,
+                key: key.verifying_key(),
+            }],
+        }
+        .load()
+        .unwrap();
+
+        let claims = claims(vec!["beta".into()], "2h", None, now).unwrap();
+        let token = preview::mint(&claims, &key).unwrap();
+
+        assert_eq!(claims.exp, (now + Duration::hours(2)).timestamp());
+        assert!(verifier
+            .verify(&token, now + Duration::hours(2) - Duration::seconds(1))
+            .is_ok());
+        assert_eq!(
+            verifier
+                .verify(&token, now + Duration::hours(2))
+                .unwrap_err()
+                .to_string(),
+            "Token has expired"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_expiry() {
+        let now = Utc.with_ymd_and_hms(2026, 10, 17, 9, 0, 0).unwrap();
+
+        assert!(claims(vec!["beta".into()], "tomorrow", None, now).is_err());
+    }
+}